@@ -39,11 +39,38 @@ pub enum Error {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum PdfError {
     FontFaceError,
+    /// The character can't be encoded in the encoding of a builtin font (or isn't
+    /// in a Type1 font, or the 256 codes of the Type1 font are used up)
+    UnencodableCharacter(char),
+    /// The glyph ID doesn't fit into the two bytes of a CID (Identity-H / Identity-V)
+    GlyphIdOutOfRange(u32),
+    /// The font collection doesn't contain the requested face
+    FaceNotInCollection,
+    /// The font doesn't have the variation axis (or isn't a variable font)
+    VariationAxisNotFound([u8; 4]),
+    /// The variable font can't be instanced (only fonts with TrueType outlines can)
+    VariationsNotSupported,
+    /// More than 256 different glyphs were written with a color font (a Type3 font
+    /// with single-byte codes), the remaining glyphs were replaced with `.notdef`
+    TooManyColorGlyphs,
+    /// The license of the font doesn't allow embedding it (`OS/2` `fsType` is
+    /// "restricted license embedding" or "bitmap embedding only")
+    FontEmbeddingRestricted,
+    /// The hyphenation pattern file contains no patterns, or a pattern without values
+    InvalidHyphenationPatterns,
+    /// Text had to be written with another font of a `FontFamily`, but no font size
+    /// was set with `set_font` on the layer, so the font couldn't be set
+    UnknownFontSize,
 }
 
 impl fmt::Display for PdfError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.description())
+        match *self {
+            PdfError::UnencodableCharacter(c) => write!(f, "{}: {:?} (U+{:04X})", self.description(), c, c as u32),
+            PdfError::GlyphIdOutOfRange(glyph_id) => write!(f, "{}: {}", self.description(), glyph_id),
+            PdfError::VariationAxisNotFound(axis) => write!(f, "{}: {}", self.description(), String::from_utf8_lossy(&axis)),
+            _ => write!(f, "{}", self.description()),
+        }
     }
 }
 
@@ -52,6 +79,15 @@ impl IError for PdfError {
         use self::PdfError::*;
         match *self {
             FontFaceError => "Invalid or corrupt font face",
+            UnencodableCharacter(_) => "Character can't be encoded in the font",
+            GlyphIdOutOfRange(_) => "Glyph ID is too large for a two-byte CID",
+            FaceNotInCollection => "Face not found in the font collection",
+            VariationAxisNotFound(_) => "Variation axis not found in the font",
+            VariationsNotSupported => "Variable fonts with CFF2 outlines can't be instanced",
+            TooManyColorGlyphs => "Too many different glyphs for a color font (at most 256)",
+            FontEmbeddingRestricted => "The license of the font doesn't allow embedding it",
+            InvalidHyphenationPatterns => "Invalid or empty hyphenation pattern file",
+            UnknownFontSize => "No font size was set for switching to a fallback font",
         }
     }
 }
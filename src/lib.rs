@@ -337,6 +337,14 @@ extern crate lopdf;
 extern crate rusttype;
 extern crate usvg;
 extern crate time;
+extern crate subsetter;
+extern crate ttf_parser;
+extern crate rustybuzz;
+extern crate unicode_bidi;
+extern crate unicode_bidi_mirroring;
+extern crate flate2;
+extern crate brotli_decompressor;
+extern crate unicode_linebreak;
 #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
 extern crate js_sys;
 
@@ -371,8 +379,14 @@ pub use self::types::plugins::misc::document_info::DocumentInfo;
 /// Stub module for 3D content in a PDF
 pub use self::types::plugins::graphics::three_dimensional;
 pub use self::types::plugins::graphics::two_dimensional::font::{
-    Font, BuiltinFont, ExternalFont, TextRenderingMode, IndirectFontRef, DirectFontRef, FontList
+    Font, BuiltinFont, ExternalFont, TextRenderingMode, IndirectFontRef, DirectFontRef, FontList,
+    TextMetrics, FontFamily, CollectionFace, FontSynthesis, EmbeddingPermission, Type1Font
 };
+pub use self::types::plugins::graphics::two_dimensional::shaping::{FontFeatures, ShapingOptions, TextDirection};
+pub use self::types::plugins::graphics::two_dimensional::paragraph::{Paragraph, ParagraphLayout, TextAlign};
+pub use self::types::plugins::graphics::two_dimensional::hyphenation::Hyphenator;
+pub use self::types::plugins::graphics::two_dimensional::rich_text::{RichText, TextSpan};
+pub use self::types::plugins::graphics::two_dimensional::flow::{Frame, TextFlow};
 pub use self::types::plugins::graphics::two_dimensional::image::Image;
 pub use self::types::plugins::graphics::two_dimensional::line::Line;
 pub use self::types::plugins::graphics::two_dimensional::point::Point;
@@ -11,8 +11,8 @@ use lopdf::{self, dictionary, Dictionary, Object, ObjectId};
 
 use indices::*;
 use {
-    BuiltinFont, DirectFontRef, Error, ExternalFont, Font, FontList, IccProfileList,
-    IndirectFontRef, Mm, PdfConformance, PdfMetadata, PdfPage, PdfPageReference,
+    BuiltinFont, CollectionFace, DirectFontRef, Error, ExternalFont, Font, FontList, FontSynthesis, IccProfileList,
+    IndirectFontRef, Mm, PdfConformance, PdfMetadata, PdfPage, PdfPageReference, Pt, TextDirection, TextMetrics, Type1Font,
 };
 
 /// PDF document
@@ -102,28 +102,61 @@ impl PdfDocument {
         let doc_ref = Rc::new(RefCell::new(doc));
         PdfDocumentReference { document: doc_ref }
     }
+
+    /// Measures the text with the font, see `PdfDocumentReference::measure_text`.
+    /// Fonts that were not added to this document are skipped, without any font
+    /// the metrics are zero.
+    pub(crate) fn measure_text<F>(&mut self, font: &F, text: &str, font_size: f64, base_direction: Option<TextDirection>) -> TextMetrics
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        let fonts = font.as_ref();
+        let font_runs = self.fonts.font_runs(text, fonts);
+
+        let mut measure = |font: &IndirectFontRef, text: &str| {
+            self.fonts.font_data_mut(font).map(|font| font.measure_text(text, font_size, base_direction))
+        };
+
+        let mut metrics = fonts.iter().filter_map(|font| measure(font, "")).next().unwrap_or(TextMetrics {
+            width: Pt(0.0),
+            ascent: Pt(0.0),
+            descent: Pt(0.0),
+            glyph_advances: Vec::new(),
+        });
+
+        // with a font family, the runs of the fallback fonts are measured on their own
+        for (range, run_font) in font_runs {
+            let run_metrics = match measure(&run_font, &text[range]) {
+                Some(run_metrics) => run_metrics,
+                None => continue,
+            };
+            metrics.width = Pt(metrics.width.0 + run_metrics.width.0);
+            metrics.ascent = Pt(metrics.ascent.0.max(run_metrics.ascent.0));
+            metrics.descent = Pt(metrics.descent.0.min(run_metrics.descent.0));
+            metrics.glyph_advances.extend(run_metrics.glyph_advances);
+        }
+
+        metrics
+    }
 }
 
 macro_rules! implement_adding_fonts {
     ($self:expr, $font_name:expr, $font:expr) => {{
         let font_ref;
 
-        let possible_ref = {
+        let font_exists = {
             let doc = $self.document.borrow();
             font_ref = IndirectFontRef::new($font_name);
-            match doc.fonts.get_font(&font_ref) {
-                Some(f) => Some(f.clone()),
-                None => None,
-            }
+            doc.fonts.font_data(&font_ref).is_some()
         };
 
-        if possible_ref.is_some() {
+        if font_exists {
             Ok(font_ref)
         } else {
             let mut doc = $self.document.borrow_mut();
             let direct_ref = DirectFontRef {
                 inner_obj: doc.inner_doc.new_object_id(),
                 data: $font,
+                used_glyphs: Default::default(),
             };
 
             doc.fonts.add_font(font_ref.clone(), direct_ref);
@@ -238,6 +271,17 @@ impl PdfDocumentReference {
     }
 
     /// Add a font from a font stream
+    ///
+    /// Fonts with color glyphs (`COLR` / `CPAL` layers or PNG bitmaps in `sbix` / `CBDT`,
+    /// such as emoji fonts) are embedded as Type3 fonts that draw the colored glyphs.
+    /// They are written like any other font, but only 256 different glyphs can be
+    /// used per color font (see `check_for_errors`).
+    ///
+    /// The embedding permission of the font (`OS/2` `fsType`) is honored by all
+    /// `add_external_font*` functions: fonts with restricted license embedding fail
+    /// with `PdfError::FontEmbeddingRestricted`, fonts that don't allow subsetting are
+    /// embedded as a whole and "preview & print" fonts are logged as a warning.
+    /// See `ExternalFont::embedding_permission`.
     pub fn add_external_font<R>(
         &self,
         font_stream: R,
@@ -245,16 +289,135 @@ impl PdfDocumentReference {
     where
         R: ::std::io::Read,
     {
+        self.add_loaded_external_font(ExternalFont::new(font_stream, 0)?)
+    }
+
+    /// Add a font that was created (and configured) with `ExternalFont`, so that the
+    /// options of the `add_external_font_*` functions can be combined:
+    ///
+    /// ```rust,ignore
+    /// let font = ExternalFont::new(File::open("Font.otf")?, 0)?
+    ///     .with_variations(&[("wght", 700.0)])?
+    ///     .with_vertical_writing();
+    /// let font = doc.add_loaded_external_font(font)?;
+    /// ```
+    ///
+    /// The font gets the next free font name of the document, the `font_index` that
+    /// it was created with doesn't matter.
+    pub fn add_loaded_external_font(
+        &self,
+        mut external_font: ExternalFont,
+    ) -> ::std::result::Result<IndirectFontRef, Error> {
         let last_font_index = {
             let doc = self.document.borrow();
             doc.fonts.len()
         };
-        let external_font = ExternalFont::new(font_stream, last_font_index)?;
+        external_font.face_name = format!("F{}", last_font_index);
         let external_font_name = external_font.face_name.clone();
-        let font = Font::ExternalFont(external_font);
+        let font = Font::ExternalFont(Box::new(external_font));
         implement_adding_fonts!(&self, external_font_name, font)
     }
 
+    /// Add one face of a font collection (`.ttc` / `.otc`) from a font stream. The face
+    /// is selected by its index in the collection or by its PostScript name:
+    ///
+    /// ```rust,ignore
+    /// let bold = doc.add_external_font_from_collection(File::open("Family.ttc")?, 1)?;
+    /// let italic = doc.add_external_font_from_collection(File::open("Family.ttc")?, "Family-Italic")?;
+    /// ```
+    ///
+    /// Only the tables of the selected face are embedded.
+    pub fn add_external_font_from_collection<R, C>(
+        &self,
+        font_stream: R,
+        face: C,
+    ) -> ::std::result::Result<IndirectFontRef, Error>
+    where
+        R: ::std::io::Read,
+        C: Into<CollectionFace>,
+    {
+        self.add_loaded_external_font(ExternalFont::from_collection(font_stream, &face.into(), 0)?)
+    }
+
+    /// Add an instance of a variable font from a font stream, selected by its axis
+    /// coordinates (for example `&[("wght", 700.0), ("wdth", 75.0)]`). The same font
+    /// file can be added several times with different coordinates, each instance is a
+    /// separate font in the document. See `ExternalFont::with_variations`.
+    pub fn add_external_font_with_variations<R>(
+        &self,
+        font_stream: R,
+        variations: &[(&str, f32)],
+    ) -> ::std::result::Result<IndirectFontRef, Error>
+    where
+        R: ::std::io::Read,
+    {
+        self.add_loaded_external_font(ExternalFont::new(font_stream, 0)?.with_variations(variations)?)
+    }
+
+    /// Add a font from a font stream that is written with a synthetic bold and / or oblique
+    /// style, for fonts that only come with a regular face:
+    ///
+    /// ```rust,ignore
+    /// let bold = doc.add_external_font_with_synthesis(File::open("Font.ttf")?, FontSynthesis::bold())?;
+    /// let oblique = doc.add_external_font_with_synthesis(File::open("Font.ttf")?, FontSynthesis::oblique(12.0))?;
+    /// ```
+    ///
+    /// The style is applied by `set_font` (see there). The glyph widths of a synthetic
+    /// bold font include the stroke, so `measure_text` returns the emboldened width.
+    pub fn add_external_font_with_synthesis<R>(
+        &self,
+        font_stream: R,
+        synthesis: FontSynthesis,
+    ) -> ::std::result::Result<IndirectFontRef, Error>
+    where
+        R: ::std::io::Read,
+    {
+        self.add_loaded_external_font(ExternalFont::new(font_stream, 0)?.with_synthesis(synthesis))
+    }
+
+    /// Add a font from a font stream for vertical writing (for CJK text), see
+    /// `ExternalFont::with_vertical_writing`.
+    pub fn add_external_font_vertical<R>(
+        &self,
+        font_stream: R,
+    ) -> ::std::result::Result<IndirectFontRef, Error>
+    where
+        R: ::std::io::Read,
+    {
+        self.add_loaded_external_font(ExternalFont::new(font_stream, 0)?.with_vertical_writing())
+    }
+
+    /// Add a PostScript Type1 font from the font program (a PFB or PFA stream) and
+    /// the AFM stream with its metrics:
+    ///
+    /// ```rust,ignore
+    /// let font = doc.add_type1_font(File::open("Font.pfb")?, File::open("Font.afm")?)?;
+    /// ```
+    ///
+    /// The font is written like a builtin font, with single-byte codes: text can use
+    /// any of the glyphs in the AFM file, but only 256 different glyphs per font (see
+    /// `Type1Font`). Characters that can't be encoded are replaced with `?` and
+    /// reported by `check_for_errors` (and `save` fails). The font program is
+    /// embedded as a whole.
+    pub fn add_type1_font<R, A>(
+        &self,
+        font_stream: R,
+        afm_stream: A,
+    ) -> ::std::result::Result<IndirectFontRef, Error>
+    where
+        R: ::std::io::Read,
+        A: ::std::io::Read,
+    {
+        let last_font_index = {
+            let doc = self.document.borrow();
+            doc.fonts.len()
+        };
+        let type1_font = Type1Font::new(font_stream, afm_stream, last_font_index)?;
+        let type1_font_name = type1_font.face_name.clone();
+        let font = Font::Type1Font(Box::new(type1_font));
+        implement_adding_fonts!(&self, type1_font_name, font)
+    }
+
     /// Add a built-in font to the document
     pub fn add_builtin_font(
         &self,
@@ -285,6 +448,22 @@ impl PdfDocumentReference {
         doc.fonts.get_font(font)
     }
 
+    /// Measures how wide the text is when it is written with the font at the given
+    /// font size (with `write_text` or `use_text`). Works for external and builtin fonts.
+    /// For fonts added with `add_external_font_vertical`, the width is the height of the text.
+    ///
+    /// The `font` can also be a `FontFamily`: the ascent and descent are then the
+    /// largest ones of the fonts that are used for the text.
+    ///
+    /// Fonts that were not added to this document are skipped (like when the text is
+    /// written), so the text has a width of zero if none of the fonts is in the document.
+    pub fn measure_text<F>(&self, font: &F, text: &str, font_size: f64) -> TextMetrics
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        let mut doc = self.document.borrow_mut();
+        doc.measure_text(font, text, font_size, None)
+    }
+
     /// Drops the PDFDocument, returning the inner `lopdf::Document`.
     /// Document may be only half-written, use only in extreme cases
     #[inline]
@@ -296,11 +475,29 @@ impl PdfDocumentReference {
     // --- MISC FUNCTIONS
 
     /// Checks for invalid settings in the document
+    ///
+    /// Currently, this only reports problems with the written text. The text is still
+    /// written when they occur, but not as intended, so the error is recorded and the
+    /// first one is returned here (and by `save`). Call this right after writing the
+    /// text to find out which call caused the problem:
+    ///
+    /// - `PdfError::UnencodableCharacter`: any of the `write_*text*` functions of
+    ///   `PdfLayerReference` wrote a character that a builtin font or a `Type1Font`
+    ///   can't encode, it was replaced with `?`
+    /// - `PdfError::TooManyColorGlyphs`: any of the `write_*text*` functions wrote
+    ///   more than 256 different glyphs with a color font, the other glyphs were written
+    ///   as `.notdef`
+    /// - `PdfError::GlyphIdOutOfRange`: `write_shaped_text` or `write_text_with_features`
+    ///   got a glyph from shaping that can't be written as a two-byte CID, the text
+    ///   was written without shaping
+    /// - `PdfError::UnknownFontSize`: any of the `write_*text*` functions needed a fallback
+    ///   font of a `FontFamily` before a font size was set with `set_font` on the layer,
+    ///   the text of the fallback font was left out
     pub fn check_for_errors(&self) -> ::std::result::Result<(), Error> {
-        // TODO
-        #[cfg(feature = "logging")]
-        {
-            warn!("Checking PDFs for errors is currently not supported!");
+        let doc = self.document.borrow();
+
+        if let Some(&error) = doc.fonts.errors().first() {
+            return Err(error.into());
         }
 
         Ok(())
@@ -345,12 +542,18 @@ impl PdfDocumentReference {
     }
 
     /// Save PDF Document, writing the contents to the target
+    ///
+    /// Fails without writing anything if an error was recorded while writing text,
+    /// because the affected text would silently be wrong in the document. See
+    /// `check_for_errors` for these errors and the calls that record them.
     pub fn save<W: Write>(self, target: &mut BufWriter<W>) -> ::std::result::Result<(), Error> {
         use lopdf::Object::*;
         use lopdf::StringFormat::Literal;
         use lopdf::{Dictionary as LoDictionary, Object as LoObject};
         use std::iter::FromIterator;
 
+        self.check_for_errors()?;
+
         // todo: remove unwrap, handle error
         let mut doc = Rc::try_unwrap(self.document).unwrap().into_inner();
         let pages_id = doc.inner_doc.new_object_id();
@@ -601,3 +804,45 @@ impl PdfDocumentReference {
         doc.compress();
     }
 }
+
+#[test]
+fn test_save_with_unencodable_characters() {
+    use PdfError;
+
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+    let layer = doc.get_page(page).get_layer(layer);
+    layer.use_text("\u{3B1}", 10.0, Mm(10.0), Mm(10.0), &font);
+
+    assert!(doc.check_for_errors().is_err());
+    let mut target = BufWriter::new(Vec::new());
+    match doc.save(&mut target) {
+        Err(Error::Pdf(PdfError::UnencodableCharacter(c))) => assert_eq!(c, '\u{3B1}'),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(target.get_ref().is_empty());
+}
+
+#[test]
+fn test_fonts_of_other_documents() {
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let (other_doc, _, _) = PdfDocument::new("other", Mm(210.0), Mm(297.0), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+    let other_font = other_doc.add_builtin_font(BuiltinFont::Courier).unwrap();
+    let no_fonts: &[IndirectFontRef] = &[];
+
+    assert!(doc.measure_text(&font, "abc", 10.0).width.0 > 0.0);
+    assert_eq!(doc.measure_text(&other_font, "abc", 10.0).width.0, 0.0);
+    assert_eq!(doc.measure_text(no_fonts, "abc", 10.0).width.0, 0.0);
+
+    // text of fonts that aren't in the document is skipped instead of panicking
+    let layer = doc.get_page(page).get_layer(layer);
+    layer.begin_text_section();
+    layer.set_font(no_fonts, 10.0);
+    layer.write_text("abc", no_fonts);
+    layer.write_text("abc", &other_font);
+    layer.end_text_section();
+    let family = ::FontFamily::new(&other_font).with_fallback(&font);
+    let metrics = doc.document.borrow_mut().measure_text(&family, "abc", 10.0, None);
+    assert_eq!(metrics, doc.measure_text(&font, "abc", 10.0));
+}
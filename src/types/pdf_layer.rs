@@ -8,12 +8,14 @@ use lopdf::content::Operation;
 use std::cell::RefCell;
 use std::rc::Weak;
 
-use crate::utils::text_bytes_for_font;
+use crate::utils::{kerning_for_glyphs, vertical_glyph_ids};
+use std::iter::FromIterator;
+use types::plugins::graphics::two_dimensional::shaping::{bidi_runs, encode_text_string, visual_text, ShapedText};
 
 use {
-    BlendMode, Color, CurTransMat, ExtendedGraphicsStateBuilder, Font, ImageXObject,
-    IndirectFontRef, Line, LineCapStyle, LineDashPattern, LineJoinStyle, Mm, PdfColor, PdfDocument,
-    Pt, TextMatrix, TextRenderingMode, XObject, XObjectRef,
+    BlendMode, Color, CurTransMat, Greyscale, ExtendedGraphicsStateBuilder, Font, FontFeatures, FontList, ImageXObject,
+    IndirectFontRef, Line, LineCapStyle, LineDashPattern, LineJoinStyle, Mm, PdfColor, PdfDocument, PdfError,
+    Pt, ShapingOptions, TextDirection, TextMatrix, TextRenderingMode, XObject, XObjectRef,
 };
 
 /// One layer of PDF data
@@ -23,6 +25,69 @@ pub struct PdfLayer {
     pub(crate) name: String,
     /// Stream objects in this layer. Usually, one layer == one stream
     pub(super) operations: Vec<Operation>,
+    /// The font that was last set with `set_font`, used to record which
+    /// glyphs are written by `write_codepoints`
+    pub(crate) current_font: Option<IndirectFontRef>,
+    /// The font size that was last set with `set_font`, `None` if no font was set yet
+    pub(crate) current_font_size: Option<f64>,
+    /// The text rise that was last set with `set_line_offset`
+    pub(crate) current_text_rise: f64,
+    /// The line height that was last set with `set_line_height`
+    pub(crate) current_line_height: f64,
+    /// Start of the current line in text space (in points), moved by `set_text_cursor`,
+    /// `add_line_break` and `set_text_matrix`
+    pub(crate) current_line_start: (f64, f64),
+    /// Stroke width (in points) of the synthetic bold font that was last set with `set_font`
+    pub(crate) current_text_stroke: Option<f64>,
+    /// Line width and colors that were set with `set_outline_thickness`, `set_outline_color`
+    /// and `set_fill_color`, to restore them after the stroke of a synthetic bold font
+    pub(crate) current_stroke: StrokeState,
+    /// Whether the line width and outline color in the content stream are the ones of the
+    /// synthetic bold font, they are set again before text is written if they were changed
+    pub(crate) text_stroke_applied: bool,
+    /// The states that were saved with `save_graphics_state`
+    pub(crate) saved_states: Vec<GraphicsState>,
+    /// Whether the layer is between `begin_text_section` and `end_text_section`
+    pub(crate) in_text_section: bool,
+    /// Oblique angle of the font that was last set with `set_font` (0 if it isn't synthetic oblique)
+    pub(crate) current_font_oblique: f64,
+    /// Oblique angle of the text matrix that is currently set
+    pub(crate) current_matrix_oblique: f64,
+    /// Base direction for the Unicode Bidirectional Algorithm that was last set with
+    /// `set_base_direction`
+    pub(crate) current_base_direction: Option<TextDirection>,
+}
+
+/// The parts of the graphics state that the stroke of a synthetic bold font changes
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StrokeState {
+    pub(crate) line_width: f64,
+    pub(crate) outline_color: Color,
+    pub(crate) fill_color: Color,
+}
+
+/// The parts of the graphics state that are tracked by the layer, saved by
+/// `save_graphics_state` and restored by `restore_graphics_state`
+#[derive(Debug, Clone)]
+pub(crate) struct GraphicsState {
+    font: Option<IndirectFontRef>,
+    font_size: Option<f64>,
+    text_rise: f64,
+    line_height: f64,
+    text_stroke: Option<f64>,
+    stroke: StrokeState,
+    text_stroke_applied: bool,
+    font_oblique: f64,
+}
+
+impl Default for StrokeState {
+    fn default() -> Self {
+        Self {
+            line_width: 1.0,
+            outline_color: Color::Greyscale(Greyscale::new(0.0, None)),
+            fill_color: Color::Greyscale(Greyscale::new(0.0, None)),
+        }
+    }
 }
 
 /// A "reference" to the current layer, allows for inner mutability
@@ -47,8 +112,108 @@ impl PdfLayer {
         Self {
             name: name.into(),
             operations: Vec::new(),
+            current_font: None,
+            current_font_size: None,
+            current_text_rise: 0.0,
+            current_line_height: 0.0,
+            current_line_start: (0.0, 0.0),
+            current_text_stroke: None,
+            current_stroke: StrokeState::default(),
+            text_stroke_applied: false,
+            saved_states: Vec::new(),
+            in_text_section: false,
+            current_font_oblique: 0.0,
+            current_matrix_oblique: 0.0,
+            current_base_direction: None,
+        }
+    }
+
+    /// Moves the start of the line by `x` / `y` points. While the text is slanted for a
+    /// synthetic oblique font, the relative `operation` (`Td` or `T*`) would move along
+    /// the slanted axes, so the text matrix is set with `Tm` instead.
+    fn move_line_start(&mut self, x: f64, y: f64, operation: Operation) -> Operation {
+        self.current_line_start = (self.current_line_start.0 + x, self.current_line_start.1 + y);
+        if self.current_font_oblique != 0.0 || self.current_matrix_oblique != 0.0 {
+            self.line_matrix()
+        } else {
+            operation
+        }
+    }
+
+    /// Returns the operations that set the line width and outline color for the stroke
+    /// of a synthetic bold font (the outline has the fill color), or that restore them
+    /// for other fonts and for paths
+    fn stroke_operations(&self, text_stroke: Option<f64>) -> Vec<Operation> {
+        match text_stroke {
+            Some(width) => vec![
+                Operation::new(OP_PATH_STATE_SET_LINE_WIDTH, vec![lopdf::Object::Real(width)]),
+                PdfColor::OutlineColor(self.current_stroke.fill_color.clone()).into(),
+            ],
+            None => vec![
+                Operation::new(OP_PATH_STATE_SET_LINE_WIDTH, vec![lopdf::Object::Real(self.current_stroke.line_width)]),
+                PdfColor::OutlineColor(self.current_stroke.outline_color.clone()).into(),
+            ],
+        }
+    }
+
+    /// Whether a synthetic bold font is set in the text section
+    fn is_text_stroke_active(&self) -> bool {
+        self.in_text_section && self.current_text_stroke.is_some()
+    }
+
+    /// Sets the line width and outline color for the stroke of a synthetic bold font
+    /// (`Some`), or restores them if they were set for one (`None`)
+    fn apply_text_stroke(&mut self, text_stroke: Option<f64>) {
+        if text_stroke.is_none() && !self.text_stroke_applied {
+            return;
+        }
+        let operations = self.stroke_operations(text_stroke);
+        self.operations.extend(operations);
+        self.text_stroke_applied = text_stroke.is_some();
+    }
+
+    /// Sets the stroke of the synthetic bold font again before text is written,
+    /// if the line width or outline color were changed since it was set
+    fn prepare_text_stroke(&mut self) {
+        if self.is_text_stroke_active() && !self.text_stroke_applied {
+            let text_stroke = self.current_text_stroke;
+            self.apply_text_stroke(text_stroke);
+        }
+    }
+
+    /// Returns the tracked parts of the graphics state, for `save_graphics_state`
+    fn graphics_state(&self) -> GraphicsState {
+        GraphicsState {
+            font: self.current_font.clone(),
+            font_size: self.current_font_size,
+            text_rise: self.current_text_rise,
+            line_height: self.current_line_height,
+            text_stroke: self.current_text_stroke,
+            stroke: self.current_stroke.clone(),
+            text_stroke_applied: self.text_stroke_applied,
+            font_oblique: self.current_font_oblique,
         }
     }
+
+    /// Restores the tracked parts of the graphics state, for `restore_graphics_state`
+    fn set_graphics_state(&mut self, state: GraphicsState) {
+        self.current_font = state.font;
+        self.current_font_size = state.font_size;
+        self.current_text_rise = state.text_rise;
+        self.current_line_height = state.line_height;
+        self.current_text_stroke = state.text_stroke;
+        self.current_stroke = state.stroke;
+        self.text_stroke_applied = state.text_stroke_applied;
+        self.current_font_oblique = state.font_oblique;
+    }
+
+    /// Returns a `Tm` operation that starts the current line with the oblique
+    /// angle of the current font
+    fn line_matrix(&mut self) -> Operation {
+        self.current_matrix_oblique = self.current_font_oblique;
+        let (x, y) = self.current_line_start;
+        TextMatrix::Skew(Pt(x).into(), Pt(y).into(), self.current_font_oblique).into()
+    }
 }
 
 impl Into<lopdf::Stream> for PdfLayer {
@@ -144,36 +309,131 @@ impl PdfLayerReference {
     /// You have to make sure to call `end_text_section` afterwards
     #[inline]
     pub fn begin_text_section(&self) -> () {
-        self.internal_add_operation(Operation::new("BT", vec![]));
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+
+        // BT resets the text matrix, the font (and its oblique angle) stays
+        layer.operations.push(Operation::new("BT", vec![]));
+        layer.in_text_section = true;
+        layer.current_line_start = (0.0, 0.0);
+        layer.current_matrix_oblique = 0.0;
+        if layer.current_font_oblique != 0.0 {
+            let operation = layer.line_matrix();
+            layer.operations.push(operation);
+        }
+        let text_stroke = layer.current_text_stroke;
+        if text_stroke.is_some() {
+            layer.apply_text_stroke(text_stroke);
+        }
     }
 
     /// Ends a new text section
     /// Only valid if `begin_text_section` has been called
+    ///
+    /// The line width and outline color of a synthetic bold font are reset, so that
+    /// they don't apply to the paths that are drawn after the text.
     #[inline]
     pub fn end_text_section(&self) -> () {
-        self.internal_add_operation(Operation::new("ET", vec![]));
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+
+        layer.operations.push(Operation::new("ET", vec![]));
+        layer.apply_text_stroke(None);
+        layer.in_text_section = false;
     }
 
     /// Set the current fill color for the layer
+    ///
+    /// While a synthetic bold font is set, the outline color is set as well, since
+    /// the stroke of the glyphs has to have the color of the glyphs.
     #[inline]
     pub fn set_fill_color(&self, fill_color: Color) -> () {
-        self.internal_add_operation(PdfColor::FillColor(fill_color));
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+
+        layer.current_stroke.fill_color = fill_color.clone();
+        if layer.is_text_stroke_active() && layer.text_stroke_applied {
+            layer.operations.push(PdfColor::OutlineColor(fill_color.clone()).into());
+        }
+        layer.operations.push(PdfColor::FillColor(fill_color).into());
     }
 
     /// Set the current font, only valid in a `begin_text_section` to
-    /// `end_text_section` block
-    #[inline]
-    pub fn set_font(&self, font: &IndirectFontRef, font_size: f64) -> () {
-        self.internal_add_operation(Operation::new(
+    /// `end_text_section` block. For a `FontFamily`, the primary font is set.
+    ///
+    /// For fonts added with `add_external_font_with_synthesis`, the synthetic style
+    /// is set up as well: synthetic bold sets the text rendering mode to `FillStroke`,
+    /// the outline thickness to the stroke width and the outline color to the fill
+    /// color, synthetic oblique slants the text matrix at the start of the current line.
+    /// Setting a regular font afterwards switches back to the `Fill` rendering mode, the
+    /// previous outline thickness and color and an upright text matrix.
+    ///
+    /// Nothing is set for an empty list of fonts.
+    pub fn set_font<F>(&self, font: &F, font_size: f64)
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        use lopdf::Object::*;
+
+        let font = match font.as_ref().first() {
+            Some(font) => font,
+            None => return,
+        };
+
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let (bold_stroke, oblique_angle) = match doc.fonts.font_data(font) {
+            Some(Font::ExternalFont(external_font)) => (external_font.synthetic_bold_stroke(), external_font.synthesis.oblique_angle),
+            _ => (None, 0.0),
+        };
+
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+        layer.operations.push(Operation::new(
             "Tf",
             vec![font.name.clone().into(), font_size.into()],
         ));
+        layer.current_font = Some(font.clone());
+        layer.current_font_size = Some(font_size);
+
+        // the line width and outline color are only changed inside of the text section
+        let text_stroke = bold_stroke.map(|stroke| stroke * font_size);
+        match text_stroke {
+            Some(_) if layer.current_text_stroke.is_none() => {
+                layer.operations.push(Operation::new("Tr", vec![Integer(TextRenderingMode::FillStroke.into())]));
+            },
+            None if layer.current_text_stroke.is_some() => {
+                layer.operations.push(Operation::new("Tr", vec![Integer(TextRenderingMode::Fill.into())]));
+            },
+            _ => { },
+        }
+        if layer.in_text_section && text_stroke != layer.current_text_stroke {
+            layer.apply_text_stroke(text_stroke);
+        }
+        layer.current_text_stroke = text_stroke;
+
+        layer.current_font_oblique = oblique_angle;
+        if layer.current_matrix_oblique != oblique_angle {
+            let operation = layer.line_matrix();
+            layer.operations.push(operation);
+        }
     }
 
     /// Set the current line / outline color for the layer
+    ///
+    /// While a synthetic bold font is set in a text section, the outline of its
+    /// glyphs keeps the fill color: the stroke of the font is set again before
+    /// the next text is written.
     #[inline]
     pub fn set_outline_color(&self, color: Color) {
-        self.internal_add_operation(PdfColor::OutlineColor(color));
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+
+        layer.current_stroke.outline_color = color.clone();
+        layer.text_stroke_applied = false;
+        layer.operations.push(PdfColor::OutlineColor(color).into());
     }
     /// Instantiate layers, forms and postscript items on the page
     /// __WARNING__: Object must be added to the same page, since the XObjectRef is just a
@@ -323,10 +583,21 @@ impl PdfLayerReference {
     ///
     /// __NOTE__: 0.0 is a special value, it does not make the line disappear, but rather
     /// makes it appear 1px wide across all devices
+    ///
+    /// While a synthetic bold font is set in a text section, its glyphs keep the
+    /// stroke width of the font: the stroke of the font is set again before the
+    /// next text is written.
     #[inline]
     pub fn set_outline_thickness(&self, outline_thickness: f64) {
         use lopdf::Object::*;
-        self.internal_add_operation(Operation::new(
+
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+
+        layer.current_stroke.line_width = outline_thickness;
+        layer.text_stroke_applied = false;
+        layer.operations.push(Operation::new(
             OP_PATH_STATE_SET_LINE_WIDTH,
             vec![Real(outline_thickness)],
         ));
@@ -363,26 +634,62 @@ impl PdfLayerReference {
     /// instead of concatenated to the current matrix. However,
     /// you should only call this function with in a block scoped by
     /// `begin_text_section()` and `end_text_section()`
-    #[inline]
+    ///
+    /// If the current font is synthetic oblique, a `TextMatrix::Translate` is
+    /// slanted by the oblique angle of the font (a rotated matrix is not)
     pub fn set_text_matrix(&self, tm: TextMatrix) {
-        self.internal_add_operation(tm);
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+
+        let operation = match tm {
+            TextMatrix::Translate(x, y) => {
+                layer.current_line_start = (Pt::from(x).0, Pt::from(y).0);
+                if layer.current_font_oblique != 0.0 {
+                    layer.line_matrix()
+                } else {
+                    layer.current_matrix_oblique = 0.0;
+                    tm.into()
+                }
+            },
+            TextMatrix::Skew(x, y, angle) => {
+                layer.current_line_start = (Pt::from(x).0, Pt::from(y).0);
+                layer.current_matrix_oblique = angle;
+                tm.into()
+            },
+            TextMatrix::Rotate(_) => {
+                layer.current_line_start = (0.0, 0.0);
+                layer.current_matrix_oblique = 0.0;
+                tm.into()
+            },
+        };
+
+        layer.operations.push(operation);
     }
 
     /// Sets the position where the text should appear
-    #[inline]
     pub fn set_text_cursor(&self, x: Mm, y: Mm) {
         let x_in_pt: Pt = x.into();
         let y_in_pt: Pt = y.into();
-        self.internal_add_operation(Operation::new("Td", vec![x_in_pt.into(), y_in_pt.into()]));
+
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+        let operation = layer.move_line_start(x_in_pt.0, y_in_pt.0, Operation::new("Td", vec![x_in_pt.into(), y_in_pt.into()]));
+        layer.operations.push(operation);
     }
 
     /// If called inside a text block scoped by `begin_text_section` and
     /// `end_text_section`, moves the cursor to a new line. PDF does not have
     /// any concept of "alignment" except left-aligned text
     /// __Note:__ Use `set_line_height` earlier to set the line height first
-    #[inline]
     pub fn add_line_break(&self) {
-        self.internal_add_operation(Operation::new("T*", Vec::new()));
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+        let line_height = layer.current_line_height;
+        let operation = layer.move_line_start(0.0, -line_height, Operation::new("T*", Vec::new()));
+        layer.operations.push(operation);
     }
 
     /// Sets the text line height inside a text block
@@ -390,6 +697,10 @@ impl PdfLayerReference {
     #[inline]
     pub fn set_line_height(&self, height: i64) {
         self.internal_add_operation(Operation::new("TL", vec![lopdf::Object::Integer(height)]));
+
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        doc.pages[self.page.0].layers[self.layer.0].current_line_height = height as f64;
     }
 
     /// Sets the character spacing inside a text block
@@ -407,7 +718,8 @@ impl PdfLayerReference {
     /// PDF does not recognize unicode fonts. It only
     /// recognizes builtin fonts done with PDFDoc encoding.
     /// However, the function itself is valid and _will work_
-    /// with builtin fonts.
+    /// with builtin fonts and Type1 fonts. For justified text,
+    /// use `write_justified_text`, which works with all fonts.
     #[inline]
     pub fn set_word_spacing(&self, spacing: f64) {
         self.internal_add_operation(Operation::new("Tw", vec![lopdf::Object::Real(spacing)]));
@@ -430,6 +742,23 @@ impl PdfLayerReference {
     #[inline]
     pub fn set_line_offset(&self, offset: i64) {
         self.internal_add_operation(Operation::new("Ts", vec![lopdf::Object::Integer(offset)]));
+
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        doc.pages[self.page.0].layers[self.layer.0].current_text_rise = offset as f64;
+    }
+
+    /// Sets the base direction of the paragraph for the Unicode Bidirectional Algorithm,
+    /// which is used to reorder text with right-to-left characters by `write_text`,
+    /// `write_justified_text` and `write_shaped_text` (if `ShapingOptions::base_direction`
+    /// isn't set). If it is `None` (the default), the direction is taken from the first
+    /// character with a strong direction, so a line of Hebrew or Arabic text that starts
+    /// with a Latin word would be written as left-to-right text.
+    #[inline]
+    pub fn set_base_direction(&self, direction: Option<TextDirection>) {
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        doc.pages[self.page.0].layers[self.layer.0].current_base_direction = direction;
     }
 
     #[inline]
@@ -449,19 +778,14 @@ impl PdfLayerReference {
         use lopdf::Object::*;
         use lopdf::StringFormat::Hexadecimal;
 
-        let bytes = codepoints
-            .into_iter()
-            .flat_map(|x| {
-                let [b0, b1] = x.to_be_bytes();
-                std::iter::once(b0).chain(std::iter::once(b1))
-            })
-            .collect::<Vec<u8>>();
+        let codepoints = codepoints.into_iter().collect::<Vec<u16>>();
 
         let doc = self.document.upgrade().unwrap();
         let mut doc = doc.borrow_mut();
-        doc.pages[self.page.0].layers[self.layer.0]
-            .operations
-            .push(Operation::new("Tj", vec![String(bytes, Hexadecimal)]));
+        let (bytes, _) = self.internal_record_codepoints(&mut doc, codepoints);
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+        layer.prepare_text_stroke();
+        layer.operations.push(Operation::new("Tj", vec![String(bytes, Hexadecimal)]));
     }
 
     /// Add text to the file at the current position by specifying
@@ -473,129 +797,364 @@ impl PdfLayerReference {
         use lopdf::Object::*;
         use lopdf::StringFormat::Hexadecimal;
 
-        let mut list = Vec::new();
+        let (positions, used_codepoints): (Vec<i64>, Vec<u16>) = codepoints.into_iter().unzip();
+
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let (bytes, bytes_per_glyph) = self.internal_record_codepoints(&mut doc, used_codepoints);
 
-        for (pos, codepoint) in codepoints {
+        let mut list = Vec::new();
+        for (pos, code) in positions.into_iter().zip(bytes.chunks(bytes_per_glyph)) {
             if pos != 0 {
                 list.push(Integer(pos));
             }
-            let bytes = codepoint.to_be_bytes().to_vec();
-            list.push(String(bytes, Hexadecimal));
+            list.push(String(code.to_vec(), Hexadecimal));
         }
 
-        let doc = self.document.upgrade().unwrap();
-        let mut doc = doc.borrow_mut();
-        doc.pages[self.page.0].layers[self.layer.0]
-            .operations
-            .push(Operation::new("TJ", vec![Array(list)]));
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+        layer.prepare_text_stroke();
+        layer.operations.push(Operation::new("TJ", vec![Array(list)]));
     }
 
-    /// Add text to the file at the current position
+    /// Add text to the file at the current position. Pair kerning from the
+    /// font is applied, use `write_text_with_kerning` to turn it off.
+    ///
+    /// Text that contains right-to-left characters (Hebrew, Arabic) is reordered
+    /// with the Unicode Bidirectional Algorithm and marked with `/ActualText`,
+    /// so that copying it out of the PDF returns the text in logical order. The
+    /// base direction can be set with `set_base_direction`.
+    ///
+    /// The `font` can be a single font or a `FontFamily`, see `write_text_with_kerning`.
     #[inline]
-    pub fn write_text(&self, text: &str, font: &IndirectFontRef) {
+    pub fn write_text<F>(&self, text: &str, font: &F)
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        self.write_text_with_kerning(text, font, true);
+    }
+
+    /// Add text to the file at the current position. If `kerning` is true,
+    /// the pair kerning of the font (from the GPOS `kern` feature or the
+    /// legacy `kern` table) is applied by writing the text with `TJ` offsets.
+    ///
+    /// If the `font` is a `FontFamily`, characters that are missing in the primary
+    /// font are written with the first fallback font that has them. The font is
+    /// switched with `Tf` (at the font size of the last `set_font`) and switched
+    /// back afterwards. If no font was set yet, the text of the fallback fonts is left
+    /// out (see `PdfDocumentReference::check_for_errors`).
+    ///
+    /// Characters that can't be encoded in a builtin or Type1 font are replaced with
+    /// `?`, the document can't be saved then (see `PdfDocumentReference::check_for_errors`).
+    ///
+    /// Fonts added with `add_external_font_vertical` are written with the vertical
+    /// glyph forms of the font and without kerning.
+    pub fn write_text_with_kerning<F>(&self, text: &str, font: &F, kerning: bool)
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
         // NOTE: The unwrap() calls in this function are safe, since
         // we've already checked the font for validity when it was added to the document
 
-        use lopdf::Object::*;
-        use lopdf::StringFormat::Hexadecimal;
-
         // we need to transform the characters into glyph ids and then add them to the layer
         let doc = self.document.upgrade().unwrap();
         let mut doc = doc.borrow_mut();
 
-        // glyph IDs that make up this string
+        // right-to-left runs have to be written in visual order
+        let visual = visual_text(text, doc.pages[self.page.0].layers[self.layer.0].current_base_direction);
+        let actual_text = visual.as_ref().map(|_| text);
+        let text = visual.as_deref().unwrap_or(text);
 
-        // kerning for each glyph id. If no kerning is present, will be 0
-        // must be the same length as list_gid
-        // let mut kerning_data = Vec::<freetype::Vector>::new();
+        let mut font_switch = FontSwitch::new(&doc.pages[self.page.0].layers[self.layer.0], font.as_ref());
+        let mut operations = Vec::new();
 
-        let bytes: Vec<u8> = {
-            use rusttype::FontCollection;
+        for (range, run_font) in doc.fonts.font_runs(text, font.as_ref()) {
+            if let Err(error) = font_switch.switch_to(&run_font, &mut operations) {
+                doc.fonts.record_error(error);
+                continue;
+            }
+            let (bytes, bytes_per_glyph, kerning_data) = encode_text(&mut doc.fonts, &text[range], &run_font, kerning);
+            operations.push(kerned_text_operation(&bytes, bytes_per_glyph, &kerning_data));
+        }
 
-            if let Font::ExternalFont(face_direct_ref) = doc.fonts.get_font(font).unwrap().data {
-                let collection = FontCollection::from_bytes(&*face_direct_ref.font_bytes).unwrap();
-                let font = collection
-                    .clone()
-                    .into_font()
-                    .unwrap_or(collection.font_at(0).unwrap());
+        font_switch.switch_back(&mut operations);
+        push_text_operations(&mut doc.pages[self.page.0].layers[self.layer.0], operations, actual_text);
+    }
 
-                text_bytes_for_font(&text, &font)
-            } else {
-                text.as_bytes().to_vec()
-            }
+    /// Add text to the file at the current position that is stretched to the `width`,
+    /// for justified text. The extra space is inserted with `TJ` offsets after the spaces
+    /// (U+0020) of the text, so unlike `set_word_spacing` this works the same for external
+    /// fonts (which are written with two-byte codes) and builtin fonts.
+    ///
+    /// Up to `max_letter_spacing` points (0 for none) of the extra space are put between
+    /// all glyphs: each gap between two glyphs gets the same share of the extra space,
+    /// but at most `max_letter_spacing`, and the rest is distributed over the spaces.
+    /// Text that is already wider than the `width` is written like with `write_text`.
+    ///
+    /// The text is kerned and can be written with a `FontFamily` (see `write_text_with_kerning`).
+    pub fn write_justified_text<F>(&self, text: &str, font: &F, width: Mm, max_letter_spacing: f64)
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+
+        // the logical text is measured (it's reordered while measuring), but right-to-left
+        // runs have to be written in visual order
+        let base_direction = doc.pages[self.page.0].layers[self.layer.0].current_base_direction;
+        let font_size = doc.pages[self.page.0].layers[self.layer.0].current_font_size.unwrap_or(0.0);
+        let extra = Pt::from(width).0 - doc.measure_text(font, text, font_size, base_direction).width.0;
+
+        let visual = visual_text(text, base_direction);
+        let actual_text = visual.as_ref().map(|_| text);
+        let text = visual.as_deref().unwrap_or(text);
+
+        // the gaps between two glyphs, and the ones of them that follow a space
+        let chars: Vec<char> = text.chars().collect();
+        let gaps = chars.len().saturating_sub(1);
+        let spaces = chars.windows(2).filter(|pair| pair[0] == ' ').count();
+
+        let (letter_spacing, space_extra) = if extra > 0.0 && gaps > 0 && font_size > 0.0 {
+            let letter_spacing = max_letter_spacing.max(0.0).min(extra / gaps as f64);
+            let space_extra = if spaces > 0 { (extra - letter_spacing * gaps as f64) / spaces as f64 } else { 0.0 };
+            // TJ offsets are in thousandths of a unit of text space
+            (letter_spacing * 1000.0 / font_size, space_extra * 1000.0 / font_size)
+        } else {
+            (0.0, 0.0)
         };
 
-        doc.pages[self.page.0].layers[self.layer.0]
-            .operations
-            .push(Operation::new("Tj", vec![String(bytes, Hexadecimal)]));
+        let mut font_switch = FontSwitch::new(&doc.pages[self.page.0].layers[self.layer.0], font.as_ref());
+        let mut operations = Vec::new();
+
+        for (range, run_font) in doc.fonts.font_runs(text, font.as_ref()) {
+            if let Err(error) = font_switch.switch_to(&run_font, &mut operations) {
+                doc.fonts.record_error(error);
+                continue;
+            }
+            let (bytes, bytes_per_glyph, kerning_data) = encode_text(&mut doc.fonts, &text[range.clone()], &run_font, true);
+
+            // the offset before each glyph, which includes the gap to the previous run
+            let mut previous = text[..range.start].chars().next_back();
+            let offsets: Vec<f64> = text[range].chars().zip(kerning_data).map(|(c, kerning)| {
+                let offset = match previous {
+                    Some(' ') => kerning as f64 + letter_spacing + space_extra,
+                    Some(_) => kerning as f64 + letter_spacing,
+                    None => kerning as f64,
+                };
+                previous = Some(c);
+                offset
+            }).collect();
+
+            operations.push(kerned_text_operation(&bytes, bytes_per_glyph, &offsets));
+        }
+
+        font_switch.switch_back(&mut operations);
+        push_text_operations(&mut doc.pages[self.page.0].layers[self.layer.0], operations, actual_text);
     }
 
-    pub fn write_gapped_text<'a>(
-        &self,
-        content: impl Iterator<Item = GappedTextElement<'a>>,
-        font: &IndirectFontRef,
-    ) {
-        use lopdf::Object::*;
-        use lopdf::StringFormat::Hexadecimal;
+    /// Add text to the file at the current position with the OpenType `features`, for
+    /// example small caps or tabular figures. The text is shaped like with
+    /// `write_shaped_text`, so the default features of the font (such as standard
+    /// ligatures) are applied as well, unless they are turned off in `features`.
+    ///
+    /// The text is written with its original characters in the ToUnicode map (or as
+    /// `/ActualText`), so that searching and copying still work for substituted glyphs.
+    #[inline]
+    pub fn write_text_with_features<F>(&self, text: &str, font: &F, features: &FontFeatures)
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        self.write_shaped_text(text, font, ShapingOptions { features: features.clone(), ..ShapingOptions::new() });
+    }
 
-        // we need to transform the characters into glyph ids and then add them to the layer
+    /// Add text to the file at the current position, shaped with the OpenType
+    /// tables of the font: ligatures, contextual forms, reordering and mark
+    /// positioning are applied, which is required for complex scripts such as
+    /// Arabic or Devanagari. Builtin fonts can't be shaped, the text is written
+    /// like with `write_text` instead.
+    ///
+    /// Text with mixed directions is split into runs with the Unicode Bidirectional
+    /// Algorithm (see `ShapingOptions::base_direction`), the runs are written in
+    /// visual order. If the glyphs can't be mapped back to the text one by one (for
+    /// example because of reordering), the text is marked with `/ActualText`, so
+    /// that copying it out of the PDF still returns the original string.
+    ///
+    /// If the `font` is a `FontFamily`, each run is further split by the fonts
+    /// that have its characters (see `write_text_with_kerning`).
+    ///
+    /// Fonts added with `add_external_font_vertical` are shaped from top to bottom.
+    ///
+    /// If shaping fails, the text is written without shaping and the document can't be
+    /// saved (see `PdfDocumentReference::check_for_errors`).
+    pub fn write_shaped_text<F>(&self, text: &str, font: &F, options: ShapingOptions)
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
         let doc = self.document.upgrade().unwrap();
         let mut doc = doc.borrow_mut();
 
-        let font = if let Font::ExternalFont(face_direct_ref) = doc.fonts.get_font(font).unwrap().data {
-            use rusttype::FontCollection;
+        let layer = &doc.pages[self.page.0].layers[self.layer.0];
+        let (font_size, text_rise) = (layer.current_font_size.unwrap_or(0.0), layer.current_text_rise);
+        let mut font_switch = FontSwitch::new(layer, font.as_ref());
+
+        let mut operations = Vec::new();
+        // the text that a PDF reader extracts from the written glyphs
+        let mut extracted_text = String::new();
+
+        let base_direction = options.base_direction.or(layer.current_base_direction);
+        for (range, direction) in bidi_runs(text, base_direction) {
 
-            let collection = FontCollection::from_bytes(face_direct_ref.font_bytes).unwrap();
-            let font = collection
-                .clone()
-                .into_font()
-                .unwrap_or(collection.font_at(0).unwrap());
+            let run_text = &text[range];
+            let mut font_runs = doc.fonts.font_runs(run_text, font.as_ref());
+            // the runs of the fonts are in logical order
+            if direction == TextDirection::RightToLeft {
+                font_runs.reverse();
+            }
+
+            let run_options = ShapingOptions { base_direction: Some(direction), ..options.clone() };
+
+            for (font_range, run_font) in font_runs {
+                let font_run_text = &run_text[font_range];
+                if let Err(error) = font_switch.switch_to(&run_font, &mut operations) {
+                    doc.fonts.record_error(error);
+                    continue;
+                }
+
+                // builtin fonts and Type1 fonts can't be shaped. If shaping fails, the error is reported
+                // by `check_for_errors` and the text is written without shaping
+                let shaped = match *doc.fonts.font_data(&run_font).unwrap() {
+                    Font::ExternalFont(ref external_font) => {
+                        match ShapedText::shape(external_font.face(), font_run_text, &run_options, external_font.vertical_writing) {
+                            Ok(mut shaped) => {
+                                shaped.extra_advance = external_font.synthetic_bold_advance();
+                                Some(shaped)
+                            },
+                            Err(error) => {
+                                doc.fonts.record_error(error);
+                                None
+                            },
+                        }
+                    },
+                    Font::BuiltinFont(_) | Font::Type1Font(_) => None,
+                };
+
+                match shaped {
+                    Some(shaped) => {
+                        doc.fonts.record_used_glyphs(&run_font, shaped.glyph_texts(font_run_text));
+                        let (codes, bytes_per_glyph) = doc.fonts.encode_glyph_ids(&run_font, &shaped.glyph_ids());
+                        operations.extend(shaped.to_operations(&codes, bytes_per_glyph, font_size, text_rise));
+                        extracted_text.push_str(&doc.fonts.extracted_text(&run_font, &shaped.glyph_ids()).unwrap_or_default());
+                    },
+                    None => {
+                        let visual = visual_text(font_run_text, Some(direction));
+                        let font_run_text = visual.as_deref().unwrap_or(font_run_text);
+                        let (bytes, bytes_per_glyph, kerning_data) = encode_text(&mut doc.fonts, font_run_text, &run_font, options.kerning);
+                        operations.push(kerned_text_operation(&bytes, bytes_per_glyph, &kerning_data));
+                        extracted_text.push_str(font_run_text);
+                    },
+                }
+            }
+        }
 
-            Some(font)
+        font_switch.switch_back(&mut operations);
 
-            // text_bytes_for_font(&text, font)
+        let actual_text = if extracted_text != text {
+            Some(text)
         } else {
-            // text.as_bytes().to_vec()
             None
         };
 
-        let bytes = |text: &str| {
-            if let Some(font) = &font {
-                text_bytes_for_font(text, font)
-            } else {
-                text.as_bytes().to_vec()
+        push_text_operations(&mut doc.pages[self.page.0].layers[self.layer.0], operations, actual_text);
+    }
+
+    /// Add text with gaps (in thousandths of a unit of text space) between the
+    /// parts. The `font` can be a single font or a `FontFamily`, see `write_text_with_kerning`.
+    pub fn write_gapped_text<'a, F>(
+        &self,
+        content: impl Iterator<Item = GappedTextElement<'a>>,
+        font: &F,
+    ) where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        use lopdf::Object::*;
+        use lopdf::StringFormat::Hexadecimal;
+
+        // we need to transform the characters into glyph ids and then add them to the layer
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+
+        let mut font_switch = FontSwitch::new(&doc.pages[self.page.0].layers[self.layer.0], font.as_ref());
+        let mut operations = Vec::new();
+        let mut operand = Vec::new();
+
+        for element in content {
+            match element {
+                GappedTextElement::Text(text) => {
+                    for (range, run_font) in doc.fonts.font_runs(text, font.as_ref()) {
+                        // a TJ array can only contain text of one font
+                        if !font_switch.is_active(&run_font) && !operand.is_empty() {
+                            operations.push(Operation::new("TJ", vec![Array(::std::mem::take(&mut operand))]));
+                        }
+                        if let Err(error) = font_switch.switch_to(&run_font, &mut operations) {
+                            doc.fonts.record_error(error);
+                            continue;
+                        }
+                        let (bytes, _, _) = encode_text(&mut doc.fonts, &text[range], &run_font, false);
+                        operand.push(String(bytes, Hexadecimal));
+                    }
+                },
+                GappedTextElement::Gap(gap) => operand.push(Real(-gap)),
             }
-        };
+        }
 
-        let operand = content.map(|c| match c {
-            GappedTextElement::Text(text) => String(bytes(text), Hexadecimal),
-            GappedTextElement::Gap(gap) => Real(-gap),
-        }).collect();
+        operations.push(Operation::new("TJ", vec![Array(operand)]));
+        font_switch.switch_back(&mut operations);
 
-        doc.pages[self.page.0].layers[self.layer.0]
-            .operations
-            .push(Operation::new("TJ", vec![Array(operand)]));
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+        layer.prepare_text_stroke();
+        layer.operations.extend(operations);
     }
 
     /// Saves the current graphic state
+    ///
+    /// The font, text state and stroke that the layer tracks are saved as well and
+    /// set back by `restore_graphics_state`, like the PDF reader does for `Q`.
     #[inline]
     pub fn save_graphics_state(&self) {
-        self.internal_add_operation(Operation::new("q", Vec::new()));
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+
+        let state = layer.graphics_state();
+        layer.saved_states.push(state);
+        layer.operations.push(Operation::new("q", Vec::new()));
     }
 
     /// Restores the previous graphic state
     #[inline]
     pub fn restore_graphics_state(&self) {
-        self.internal_add_operation(Operation::new("Q", Vec::new()));
+        let doc = self.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        let layer = &mut doc.pages[self.page.0].layers[self.layer.0];
+
+        if let Some(state) = layer.saved_states.pop() {
+            layer.set_graphics_state(state);
+        }
+        layer.operations.push(Operation::new("Q", Vec::new()));
+    }
+
+    /// Add text to the file, x and y are measure in millimeter from the bottom left corner.
+    /// The `font` can be a single font or a `FontFamily`.
+    #[inline]
+    pub fn use_text<F>(&self, text: &str, font_size: f64, x: Mm, y: Mm, font: &F)
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        self.use_text_with_kerning(text, font_size, x, y, font, true);
     }
 
-    /// Add text to the file, x and y are measure in millimeter from the bottom left corner
+    /// Same as `use_text`, but the kerning of the font can be turned off
+    /// (see `write_text_with_kerning`)
     #[inline]
-    pub fn use_text(&self, text: &str, font_size: f64, x: Mm, y: Mm, font: &IndirectFontRef) -> () {
+    pub fn use_text_with_kerning<F>(&self, text: &str, font_size: f64, x: Mm, y: Mm, font: &F, kerning: bool)
+    where F: AsRef<[IndirectFontRef]> + ?Sized
+    {
         self.begin_text_section();
         self.set_font(font, font_size);
         self.set_text_cursor(x, y);
-        self.write_text(text, font);
+        self.write_text_with_kerning(text, font, kerning);
         self.end_text_section();
     }
 
@@ -622,6 +1181,21 @@ impl PdfLayerReference {
         }
     */
 
+    // internal function to record the glyphs written by `write_codepoints`
+    // for the font that was last set on this layer
+    // records the glyphs as used by the current font and returns their character codes
+    // and the number of bytes per code (color fonts use single-byte codes)
+    fn internal_record_codepoints(&self, doc: &mut PdfDocument, codepoints: Vec<u16>) -> (Vec<u8>, usize) {
+        let current_font = doc.pages[self.page.0].layers[self.layer.0].current_font.clone();
+        match current_font {
+            Some(font) => {
+                doc.fonts.record_used_glyphs(&font, codepoints.iter().map(|&gid| (gid, None)));
+                doc.fonts.encode_glyph_ids(&font, &codepoints)
+            },
+            None => (codepoints.iter().flat_map(|gid| gid.to_be_bytes()).collect(), 2),
+        }
+    }
+
     // internal function to invoke an xobject
     fn internal_invoke_xobject(&self, name: String) {
         let doc = self.document.upgrade().unwrap();
@@ -648,9 +1222,371 @@ impl PdfLayerReference {
     }
 }
 
+/// Encodes the text for the font (as glyph IDs for external fonts, or single-byte
+/// codes for builtin fonts, Type1 fonts and color fonts) and records the glyphs / unencodable characters in the
+/// font list. Returns the encoded bytes, the number of bytes per glyph and the
+/// kerning before each glyph (all zero if `kerning` is false).
+fn encode_text(fonts: &mut FontList, text: &str, font: &IndirectFontRef, kerning: bool) -> (Vec<u8>, usize, Vec<i64>) {
+    match *fonts.font_data_mut(font).unwrap() {
+        Font::ExternalFont(ref mut external_font) => {
+            let mut glyph_ids = external_font.glyph_ids_for_text(text);
+            let gids = glyph_ids.iter().map(|&(gid, _)| gid).collect::<Vec<u16>>();
+
+            // kerning for each glyph id. If no kerning is present, will be 0
+            let kerning_data = if external_font.vertical_writing {
+                let vertical_gids = vertical_glyph_ids(external_font.face(), &gids);
+                for (glyph, vertical_gid) in glyph_ids.iter_mut().zip(vertical_gids) {
+                    glyph.0 = vertical_gid;
+                }
+                vec![0; glyph_ids.len()]
+            } else if kerning {
+                kerning_for_glyphs(external_font.face(), &gids)
+            } else {
+                vec![0; glyph_ids.len()]
+            };
+
+            let gids = glyph_ids.iter().map(|&(gid, _)| gid).collect::<Vec<u16>>();
+            fonts.record_used_glyphs(font, glyph_ids.iter().map(|&(gid, ch)| (gid, Some(ch.to_string()))));
+            let (bytes, bytes_per_glyph) = fonts.encode_glyph_ids(font, &gids);
+
+            (bytes, bytes_per_glyph, kerning_data)
+        },
+        Font::BuiltinFont(builtin_font) => {
+            let (codes, unencodable) = builtin_font.encode_text_lossy(text);
+            fonts.record_unencodable_chars(unencodable);
+
+            let kerning_data = if kerning {
+                builtin_font.kerning_for_codes(&codes)
+            } else {
+                vec![0; codes.len()]
+            };
+
+            (codes, 1, kerning_data)
+        },
+        Font::Type1Font(ref mut type1_font) => {
+            let (codes, unencodable) = type1_font.encode_text_lossy(text);
+
+            let kerning_data = if kerning {
+                type1_font.kerning_for_codes(&codes)
+            } else {
+                vec![0; codes.len()]
+            };
+
+            fonts.record_unencodable_chars(unencodable);
+            (codes, 1, kerning_data)
+        },
+    }
+}
+
+/// Keeps track of the font while a text is written with fallback fonts, so that
+/// `Tf` is only written when the font actually changes
+struct FontSwitch {
+    /// The font that was set with `set_font` (or the primary font, if none was set)
+    initial_font: Option<IndirectFontRef>,
+    /// The font that the text is currently written with
+    active_font: Option<IndirectFontRef>,
+    /// The font size of `set_font`, which the other fonts are set with
+    font_size: Option<f64>,
+}
+
+impl FontSwitch {
+
+    fn new(layer: &PdfLayer, fonts: &[IndirectFontRef]) -> Self {
+        let initial_font = layer.current_font.clone().or_else(|| fonts.first().cloned());
+        Self {
+            active_font: initial_font.clone(),
+            initial_font,
+            font_size: layer.current_font_size,
+        }
+    }
+
+    fn is_active(&self, font: &IndirectFontRef) -> bool {
+        self.active_font.as_ref() == Some(font)
+    }
+
+    /// Writes a `Tf` operation if the font is not the active font. Fails with
+    /// `PdfError::UnknownFontSize` if the font can't be set because no font size was
+    /// set with `set_font`, the text of the font can't be written then.
+    fn switch_to(&mut self, font: &IndirectFontRef, operations: &mut Vec<Operation>) -> Result<(), PdfError> {
+        if !self.is_active(font) {
+            let font_size = self.font_size.ok_or(PdfError::UnknownFontSize)?;
+            operations.push(Operation::new("Tf", vec![font.name.clone().into(), font_size.into()]));
+            self.active_font = Some(font.clone());
+        }
+        Ok(())
+    }
+
+    /// Switches back to the initial font, so that text written afterwards uses the font of `set_font`
+    fn switch_back(mut self, operations: &mut Vec<Operation>) {
+        if let Some(initial_font) = self.initial_font.take() {
+            // only fails if no other font was set either
+            let _ = self.switch_to(&initial_font, operations);
+        }
+    }
+}
+
+/// Builds a `Tj` operation for the encoded text, or a `TJ` operation if any of the
+/// glyphs is kerned. `kerning` contains the kerning before each glyph, in thousandths
+/// of an em (negative values move the glyph closer to the previous glyph), as integers
+/// or as real numbers for the fractional offsets of justified text.
+fn kerned_text_operation<K>(bytes: &[u8], bytes_per_glyph: usize, kerning: &[K]) -> Operation
+where K: Copy + Default + PartialEq + ::std::ops::Neg<Output = K> + Into<lopdf::Object>
+{
+    use lopdf::Object::*;
+    use lopdf::StringFormat::Hexadecimal;
+
+    if kerning.iter().all(|k| *k == K::default()) {
+        return Operation::new("Tj", vec![String(bytes.to_vec(), Hexadecimal)]);
+    }
+
+    // split the text at each kerned glyph, the offsets are in
+    // thousandths of a unit of text space and move the next glyph left
+    let mut list = Vec::new();
+    let mut current_run = Vec::new();
+    for (glyph, kern) in bytes.chunks(bytes_per_glyph).zip(kerning) {
+        if *kern != K::default() {
+            if !current_run.is_empty() {
+                list.push(String(::std::mem::take(&mut current_run), Hexadecimal));
+            }
+            list.push((-*kern).into());
+        }
+        current_run.extend_from_slice(glyph);
+    }
+    list.push(String(current_run, Hexadecimal));
+    Operation::new("TJ", vec![Array(list)])
+}
+
+/// Adds the text operations to the layer. If `actual_text` is set, the operations
+/// are wrapped in a marked content sequence with the `/ActualText`, which PDF
+/// readers use instead of the ToUnicode map when the text is copied.
+fn push_text_operations(layer: &mut PdfLayer, operations: Vec<Operation>, actual_text: Option<&str>) {
+    use lopdf::Object::*;
+    use lopdf::StringFormat::Hexadecimal;
+
+    layer.prepare_text_stroke();
+    let layer_operations = &mut layer.operations;
+
+    match actual_text {
+        Some(actual_text) => {
+            let properties = lopdf::Dictionary::from_iter(vec![
+                ("ActualText", String(encode_text_string(actual_text), Hexadecimal)),
+            ]);
+            layer_operations.push(Operation::new("BDC", vec![Name("Span".into()), Dictionary(properties)]));
+            layer_operations.extend(operations);
+            layer_operations.push(Operation::new("EMC", vec![]));
+        },
+        None => layer_operations.extend(operations),
+    }
+}
+
 pub enum GappedTextElement<'a> {
     Text(&'a str),
 
     /// The gap in thousandths of a unit of text space.
     Gap(f64),
 }
+
+#[test]
+fn test_synthetic_bold_stroke_state() {
+    use {FontSynthesis, PdfDocument, Rgb};
+
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let roboto = include_bytes!("../../assets/fonts/RobotoMedium.ttf");
+    let regular = doc.add_external_font(&roboto[..]).unwrap();
+    let bold = doc.add_external_font_with_synthesis(&roboto[..], FontSynthesis::bold()).unwrap();
+    let layer = doc.get_page(page).get_layer(layer);
+
+    let red = Color::Rgb(Rgb::new(1.0, 0.0, 0.0, None));
+    let blue = Color::Rgb(Rgb::new(0.0, 0.0, 1.0, None));
+    let operations = || doc.document.borrow().pages[page.0].layers[layer.layer.0].operations.clone();
+    let last_operand = |operations: &[Operation], operator: &str| operations.iter().rev()
+        .find(|operation| operation.operator == operator)
+        .map(|operation| operation.operands.iter().map(|operand| operand.as_f64().unwrap()).collect::<Vec<_>>());
+
+    layer.set_outline_thickness(2.0);
+    layer.set_outline_color(red);
+    layer.begin_text_section();
+    layer.set_fill_color(blue);
+    layer.set_font(&bold, 10.0);
+    layer.write_text("bold", &bold);
+
+    // the glyphs are stroked with the fill color
+    let bold_operations = operations();
+    assert_eq!(last_operand(&bold_operations, "RG"), Some(vec![0.0, 0.0, 1.0]));
+    assert_ne!(last_operand(&bold_operations, "w"), Some(vec![2.0]));
+
+    // switching back to a regular font restores the line width and outline color
+    layer.set_font(&regular, 10.0);
+    layer.write_text("regular", &regular);
+    let regular_operations = operations();
+    assert_eq!(last_operand(&regular_operations, "w"), Some(vec![2.0]));
+    assert_eq!(last_operand(&regular_operations, "RG"), Some(vec![1.0, 0.0, 0.0]));
+
+    // so does the end of the text section, if the bold font is still set
+    layer.set_font(&bold, 10.0);
+    layer.end_text_section();
+    let end_operations = operations();
+    assert_eq!(end_operations[end_operations.len() - 3].operator, "ET");
+    assert_eq!(last_operand(&end_operations, "w"), Some(vec![2.0]));
+    assert_eq!(last_operand(&end_operations, "RG"), Some(vec![1.0, 0.0, 0.0]));
+}
+
+#[test]
+fn test_synthetic_bold_outline_changes() {
+    use {FontSynthesis, PdfDocument, Rgb};
+
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let roboto = include_bytes!("../../assets/fonts/RobotoMedium.ttf");
+    let regular = doc.add_external_font(&roboto[..]).unwrap();
+    let bold = doc.add_external_font_with_synthesis(&roboto[..], FontSynthesis::bold()).unwrap();
+    let layer = doc.get_page(page).get_layer(layer);
+
+    let red = Color::Rgb(Rgb::new(1.0, 0.0, 0.0, None));
+    let blue = Color::Rgb(Rgb::new(0.0, 0.0, 1.0, None));
+    let operations = || doc.document.borrow().pages[page.0].layers[layer.layer.0].operations.clone();
+    let last_operand = |operations: &[Operation], operator: &str| operations.iter().rev()
+        .find(|operation| operation.operator == operator)
+        .map(|operation| operation.operands.iter().map(|operand| operand.as_f64().unwrap()).collect::<Vec<_>>());
+
+    layer.begin_text_section();
+    layer.set_fill_color(blue);
+    layer.set_font(&bold, 10.0);
+    let bold_width = last_operand(&operations(), "w");
+
+    // the outline is set while the bold font is set...
+    layer.set_outline_thickness(2.0);
+    layer.set_outline_color(red);
+    let outline_operations = operations();
+    assert_eq!(last_operand(&outline_operations, "w"), Some(vec![2.0]));
+    assert_eq!(last_operand(&outline_operations, "RG"), Some(vec![1.0, 0.0, 0.0]));
+
+    // ...and the stroke of the font is set again before its text
+    layer.write_text("bold", &bold);
+    let bold_operations = operations();
+    let text_index = bold_operations.iter().rposition(|operation| operation.operator == "Tj" || operation.operator == "TJ").unwrap();
+    assert_eq!(last_operand(&bold_operations[..text_index], "w"), bold_width);
+    assert_eq!(last_operand(&bold_operations[..text_index], "RG"), Some(vec![0.0, 0.0, 1.0]));
+
+    layer.end_text_section();
+    let end_operations = operations();
+    assert_eq!(last_operand(&end_operations, "w"), Some(vec![2.0]));
+    assert_eq!(last_operand(&end_operations, "RG"), Some(vec![1.0, 0.0, 0.0]));
+
+    // the font and stroke are restored with the graphics state
+    layer.begin_text_section();
+    layer.set_font(&regular, 12.0);
+    layer.end_text_section();
+    layer.save_graphics_state();
+    layer.begin_text_section();
+    layer.set_font(&bold, 10.0);
+    layer.end_text_section();
+    layer.restore_graphics_state();
+
+    let doc = doc.document.borrow();
+    let pdf_layer = &doc.pages[page.0].layers[layer.layer.0];
+    assert_eq!(pdf_layer.current_font, Some(regular.clone()));
+    assert_eq!(pdf_layer.current_font_size, Some(12.0));
+    assert_eq!(pdf_layer.current_text_stroke, None);
+}
+
+#[test]
+fn test_base_direction() {
+    use {Paragraph, PdfDocument};
+
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let roboto = include_bytes!("../../assets/fonts/RobotoMedium.ttf");
+    let font = doc.add_external_font(&roboto[..]).unwrap();
+    let layer = doc.get_page(page).get_layer(layer);
+
+    // the glyph ids (two bytes each) of the last text that was written
+    let glyphs = || {
+        let document = doc.document.borrow();
+        let operation = document.pages[page.0].layers[layer.layer.0].operations.iter().rev()
+            .find(|operation| operation.operator == "Tj" || operation.operator == "TJ")
+            .cloned()
+            .unwrap();
+        let bytes: Vec<u8> = match operation.operands[0] {
+            lopdf::Object::String(ref bytes, _) => bytes.clone(),
+            lopdf::Object::Array(ref items) => items.iter().filter_map(|item| item.as_str().ok()).flat_map(|bytes| bytes.to_vec()).collect(),
+            _ => Vec::new(),
+        };
+        bytes.chunks(2).map(|glyph| glyph.to_vec()).collect::<Vec<_>>()
+    };
+
+    // starts with a neutral character and a Latin word, so its direction is detected as
+    // left-to-right: "(ab) " + the reversed Hebrew word
+    let text = "(ab) \u{5D0}\u{5D1}";
+    layer.begin_text_section();
+    layer.set_font(&font, 10.0);
+    layer.write_text(text, &font);
+    let ltr = glyphs();
+    assert_eq!(ltr.len(), 7);
+
+    // right-to-left: the reversed Hebrew word + " (ab)"
+    layer.set_base_direction(Some(TextDirection::RightToLeft));
+    layer.write_text(text, &font);
+    let rtl = glyphs();
+    assert_eq!(rtl, [&ltr[5..], &ltr[4..5], &ltr[..4]].concat());
+
+    layer.write_justified_text(text, &font, Mm(50.0), 0.0);
+    assert_eq!(glyphs(), rtl);
+
+    // justified text is measured in the base direction: if the text already fills the
+    // width, no space is added between the glyphs (the mirrored parentheses are kerned
+    // differently if the text is reordered twice)
+    let offsets = || {
+        let document = doc.document.borrow();
+        let operations = &document.pages[page.0].layers[layer.layer.0].operations;
+        let operation = operations.iter().rev().find(|operation| operation.operator == "TJ").unwrap();
+        operation.operands[0].as_array().unwrap().iter().map(|item| match *item {
+            lopdf::Object::Integer(offset) => offset as f64,
+            lopdf::Object::Real(offset) => offset,
+            _ => 0.0,
+        }).sum::<f64>()
+    };
+    let kerned = "((V\u{5D0}";
+    let width = doc.document.borrow_mut().measure_text(&font, kerned, 10.0, Some(TextDirection::RightToLeft)).width;
+    layer.write_text(kerned, &font);
+    let kerning = offsets();
+    layer.write_justified_text(kerned, &font, width.into(), 1.0);
+    assert!((offsets() - kerning).abs() < 0.01);
+    layer.set_base_direction(None);
+    layer.end_text_section();
+
+    // paragraphs set the base direction of their lines
+    Paragraph::new(text, &font, 10.0, 12.0, Mm(100.0))
+        .with_base_direction(TextDirection::RightToLeft)
+        .render(&layer, Mm(10.0), Mm(200.0));
+    assert_eq!(glyphs(), rtl);
+    assert_eq!(doc.document.borrow().pages[page.0].layers[layer.layer.0].current_base_direction, None);
+}
+
+#[test]
+fn test_fallback_font_size() {
+    use {BuiltinFont, Error, FontFamily, PdfDocument};
+
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let roboto = doc.add_external_font(&include_bytes!("../../assets/fonts/RobotoMedium.ttf")[..]).unwrap();
+    let symbol = doc.add_builtin_font(BuiltinFont::Symbol).unwrap();
+    let family = FontFamily::new(&roboto).with_fallback(&symbol);
+    let layer = doc.get_page(page).get_layer(layer);
+    let font_sizes = || doc.document.borrow().pages[page.0].layers[layer.layer.0].operations.iter()
+        .filter(|operation| operation.operator == "Tf")
+        .map(|operation| operation.operands[1].as_float().unwrap())
+        .collect::<Vec<_>>();
+
+    // Roboto doesn't have the spade suit: without a font size, Symbol can't be set
+    layer.begin_text_section();
+    layer.write_text("a \u{2660}", &family);
+    assert!(font_sizes().is_empty());
+    match doc.check_for_errors() {
+        Err(Error::Pdf(PdfError::UnknownFontSize)) => { },
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // Symbol is set with the font size of `set_font`, then Roboto again
+    layer.set_font(&family, 12.0);
+    layer.write_text("a \u{2660}", &family);
+    layer.end_text_section();
+    assert_eq!(font_sizes(), vec![12.0, 12.0, 12.0]);
+}
@@ -34,6 +34,9 @@ pub enum TextMatrix {
     /// Text translate matrix, used for indenting (transforming) text
     /// (different to regular text placement)
     Translate(Mm, Mm),
+    /// Text translate matrix that also slants the text by the angle (in degrees,
+    /// positive angles slant the text to the right, like an oblique font)
+    Skew(Mm, Mm, f64),
 }
 
 impl Into<[f64; 6]> for TextMatrix {
@@ -49,6 +52,12 @@ impl Into<[f64; 6]> for TextMatrix {
                 [ 1.0, 0.0, 0.0, 1.0, x_pt.0, y_pt.0 ] 
             }
             Rotate(rot) => { let rad = (360.0 - rot).to_radians(); [rad.cos(), -rad.sin(), rad.sin(), rad.cos(), 0.0, 0.0 ] /* cos sin -sin cos 0 0 cm */ }
+            Skew(x, y, angle) => {
+                // 1 0 tan 1 x y Tm
+                let x_pt: Pt = x.into();
+                let y_pt: Pt = y.into();
+                [ 1.0, 0.0, angle.to_radians().tan(), 1.0, x_pt.0, y_pt.0 ]
+            }
         }
     }
 }
@@ -131,3 +140,13 @@ fn test_ctm_translate()
     let ctm_rot_arr: [f64; 6] = ctm_rot.into();
     assert_eq!([0.8660254037844384, 0.5000000000000004, -0.5000000000000004, 0.8660254037844384, 0.0, 0.0], ctm_rot_arr);
 }
+
+#[test]
+fn test_text_matrix_skew()
+{
+    // a 45 degree slant moves the top of a glyph by its height to the right
+    let tm_skew: [f64; 6] = TextMatrix::Skew(Mm(0.0), Mm(0.0), 45.0).into();
+    assert_eq!(tm_skew[0..2], [1.0, 0.0]);
+    assert!((tm_skew[2] - 1.0).abs() < 1e-12);
+    assert_eq!(tm_skew[3..6], [1.0, 0.0, 0.0]);
+}
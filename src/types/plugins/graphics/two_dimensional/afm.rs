@@ -0,0 +1,204 @@
+//! Parser for Adobe Font Metrics (AFM) files, which contain the metrics
+//! of Type1 fonts (such as the 14 builtin fonts)
+
+use std::collections::HashMap;
+use types::plugins::graphics::two_dimensional::glyph_names::glyph_name_to_unicode;
+
+/// Metrics of a single character in an AFM file
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AfmCharMetrics {
+    /// Code of the character in the builtin encoding of the font, `None` if the
+    /// character is not encoded
+    pub(crate) code: Option<u8>,
+    /// Advance width, in thousandths of an em
+    pub(crate) width: f64,
+    /// PostScript glyph name
+    pub(crate) name: String,
+}
+
+/// Font metrics, parsed from an AFM file. All values are in thousandths of an em.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AfmFontMetrics {
+    /// PostScript name of the font
+    pub(crate) font_name: String,
+    /// Encoding scheme of the builtin encoding, "AdobeStandardEncoding" for text fonts
+    pub(crate) encoding_scheme: String,
+    pub(crate) font_bbox: [f64; 4],
+    /// Italic angle in degrees (counter-clockwise from the vertical)
+    pub(crate) italic_angle: f64,
+    pub(crate) is_fixed_pitch: bool,
+    pub(crate) ascender: f64,
+    pub(crate) descender: f64,
+    pub(crate) cap_height: Option<f64>,
+    pub(crate) x_height: Option<f64>,
+    /// Dominant width of the vertical stems
+    pub(crate) std_vw: Option<f64>,
+    pub(crate) chars: Vec<AfmCharMetrics>,
+    /// Kerning pairs by glyph name
+    pub(crate) kern_pairs: HashMap<(String, String), f64>,
+    /// Index into `chars` by Unicode value
+    by_unicode: HashMap<char, usize>,
+}
+
+impl AfmFontMetrics {
+
+    /// Parses the AFM file. Returns `None` if the file doesn't contain any character metrics.
+    pub(crate) fn parse(afm: &str) -> Option<Self> {
+
+        let mut metrics = AfmFontMetrics {
+            font_name: String::new(),
+            encoding_scheme: String::new(),
+            font_bbox: [0.0; 4],
+            italic_angle: 0.0,
+            is_fixed_pitch: false,
+            ascender: 0.0,
+            descender: 0.0,
+            cap_height: None,
+            x_height: None,
+            std_vw: None,
+            chars: Vec::new(),
+            kern_pairs: HashMap::new(),
+            by_unicode: HashMap::new(),
+        };
+
+        let mut has_ascender = false;
+        let mut has_descender = false;
+
+        for line in afm.lines() {
+            let mut words = line.split_whitespace();
+            let key = match words.next() {
+                Some(key) => key,
+                None => continue,
+            };
+            let number = |s: Option<&str>| s.and_then(|s| s.parse::<f64>().ok()).unwrap_or(0.0);
+
+            match key {
+                "FontName" => metrics.font_name = words.next().unwrap_or_default().to_string(),
+                "EncodingScheme" => metrics.encoding_scheme = words.next().unwrap_or_default().to_string(),
+                "FontBBox" => {
+                    for value in metrics.font_bbox.iter_mut() {
+                        *value = number(words.next());
+                    }
+                },
+                "ItalicAngle" => metrics.italic_angle = number(words.next()),
+                "IsFixedPitch" => metrics.is_fixed_pitch = words.next() == Some("true"),
+                "CapHeight" => metrics.cap_height = Some(number(words.next())),
+                "XHeight" => metrics.x_height = Some(number(words.next())),
+                "StdVW" => metrics.std_vw = Some(number(words.next())),
+                "Ascender" => { metrics.ascender = number(words.next()); has_ascender = true; },
+                "Descender" => { metrics.descender = number(words.next()); has_descender = true; },
+                "C" | "CH" => {
+                    if let Some(char_metrics) = parse_char_metrics(line) {
+                        metrics.chars.push(char_metrics);
+                    }
+                },
+                "KPX" => {
+                    if let (Some(left), Some(right), Some(value)) = (words.next(), words.next(), words.next()) {
+                        if let Ok(value) = value.parse::<f64>() {
+                            metrics.kern_pairs.insert((left.to_string(), right.to_string()), value);
+                        }
+                    }
+                },
+                _ => { },
+            }
+        }
+
+        if metrics.chars.is_empty() {
+            return None;
+        }
+
+        // symbolic fonts don't have an ascender / descender, use the bounding box instead
+        if !has_ascender {
+            metrics.ascender = metrics.font_bbox[3];
+        }
+        if !has_descender {
+            metrics.descender = metrics.font_bbox[1];
+        }
+
+        // glyphs with a suffix (`a.sc`) are only used if there is no glyph without one
+        let (plain, suffixed): (Vec<_>, Vec<_>) = metrics.chars.iter().enumerate()
+            .partition(|(_, char_metrics)| !char_metrics.name.contains('.'));
+        for (index, char_metrics) in plain.into_iter().chain(suffixed) {
+            for unicode in glyph_name_to_unicode(&char_metrics.name) {
+                metrics.by_unicode.entry(unicode).or_insert(index);
+            }
+        }
+
+        Some(metrics)
+    }
+
+    /// Returns the metrics of the glyph for the character, if the font has one
+    pub(crate) fn char_metrics(&self, c: char) -> Option<&AfmCharMetrics> {
+        self.by_unicode(c).map(|index| &self.chars[index])
+    }
+
+    /// Returns the index of the glyph for the character in `chars`
+    pub(crate) fn by_unicode(&self, c: char) -> Option<usize> {
+        self.by_unicode.get(&c).cloned()
+    }
+
+    /// Returns the metrics of the glyph that is encoded with the code in the
+    /// builtin encoding of the font
+    pub(crate) fn code_metrics(&self, code: u8) -> Option<&AfmCharMetrics> {
+        self.chars.iter().find(|c| c.code == Some(code))
+    }
+
+    /// Returns the kerning between the two glyphs (negative values move the
+    /// glyphs closer together)
+    pub(crate) fn kerning(&self, left: &str, right: &str) -> f64 {
+        self.kern_pairs.get(&(left.to_string(), right.to_string())).cloned().unwrap_or(0.0)
+    }
+}
+
+/// Parses a line of the form `C 32 ; WX 278 ; N space ; B 0 0 0 0 ;`
+fn parse_char_metrics(line: &str) -> Option<AfmCharMetrics> {
+
+    let mut code = None;
+    let mut width = None;
+    let mut name = None;
+
+    for item in line.split(';') {
+        let mut words = item.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("C"), Some(c)) => code = c.parse::<i32>().ok().filter(|c| *c >= 0 && *c < 256).map(|c| c as u8),
+            (Some("CH"), Some(c)) => code = u8::from_str_radix(c.trim_matches(|c| c == '<' || c == '>'), 16).ok(),
+            (Some("WX"), Some(w)) | (Some("W0X"), Some(w)) => width = w.parse::<f64>().ok(),
+            (Some("N"), Some(n)) => name = Some(n.to_string()),
+            _ => { },
+        }
+    }
+
+    Some(AfmCharMetrics {
+        code,
+        width: width?,
+        name: name?,
+    })
+}
+
+#[test]
+fn test_parse_afm() {
+    let metrics = AfmFontMetrics::parse(include_str!("../../../../../assets/afm/Helvetica.afm")).unwrap();
+    assert_eq!(metrics.font_name, "Helvetica");
+    assert_eq!(metrics.ascender, 718.0);
+    assert_eq!((metrics.cap_height, metrics.std_vw), (Some(718.0), Some(88.0)));
+    assert_eq!(metrics.char_metrics('A').unwrap().width, 667.0);
+    assert_eq!(metrics.char_metrics('\u{e9}').unwrap().name, "eacute");
+    assert!(metrics.kerning("A", "V") < 0.0);
+}
+
+#[test]
+fn test_afm_glyph_names() {
+    // Adobe Glyph List names, `uniXXXX` / `uXXXXX` names and a suffixed variant that
+    // comes before the plain glyph
+    let metrics = AfmFontMetrics::parse("StartFontMetrics 4.1\nFontName Test\n\
+        C -1 ; WX 500 ; N a.sc ; B 0 0 400 500 ;\n\
+        C 97 ; WX 520 ; N a ; B 0 0 400 500 ;\n\
+        C -1 ; WX 600 ; N afii10017 ; B 0 0 500 700 ;\n\
+        C -1 ; WX 610 ; N uni0411 ; B 0 0 500 700 ;\n\
+        C -1 ; WX 900 ; N u1F600 ; B 0 0 800 800 ;\n\
+        EndFontMetrics\n").unwrap();
+    assert_eq!(metrics.char_metrics('a').unwrap().name, "a");
+    assert_eq!(metrics.char_metrics('\u{410}').unwrap().width, 600.0);
+    assert_eq!(metrics.char_metrics('\u{411}').unwrap().width, 610.0);
+    assert_eq!(metrics.char_metrics('\u{1F600}').unwrap().width, 900.0);
+}
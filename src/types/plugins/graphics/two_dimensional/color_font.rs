@@ -0,0 +1,349 @@
+//! Color glyphs (`COLR` / `CPAL` layers and `sbix` / `CBDT` bitmaps), which are
+//! drawn by the glyph procedures of a Type3 font
+
+use lopdf;
+use lopdf::content::{Content, Operation};
+use lopdf::Dictionary as LoDictionary;
+use lopdf::Object::{Dictionary, Integer, Name, Real, Reference};
+use std::iter::FromIterator;
+use ttf_parser::{colr, Face, GlyphId, OutlineBuilder, RgbaColor};
+
+/// The text colors that the layers are painted with to find the layers in the text color:
+/// their RGB values differ between the two passes, the colors of the palette don't
+const FOREGROUNDS: [RgbaColor; 2] = [
+    RgbaColor { red: 0, green: 0, blue: 0, alpha: 255 },
+    RgbaColor { red: 255, green: 255, blue: 255, alpha: 255 },
+];
+
+/// Returns true if the font has color glyphs (color layers or bitmaps)
+pub(crate) fn has_color_glyphs(face: &Face) -> bool {
+    let tables = face.tables();
+    tables.colr.is_some() || tables.sbix.is_some() || tables.cbdt.is_some()
+}
+
+/// The resources of the glyph procedures of a Type3 font: the images of bitmap
+/// glyphs and the graphics states for transparent layers
+#[derive(Debug, Default)]
+pub(crate) struct GlyphResources {
+    xobjects: LoDictionary,
+    ext_g_states: LoDictionary,
+}
+
+impl GlyphResources {
+
+    /// Returns the name of a graphics state with the fill alpha
+    fn alpha(&mut self, alpha: u8) -> String {
+        let name = format!("GS{}", alpha);
+        if !self.ext_g_states.has(name.as_bytes()) {
+            let ext_g_state = LoDictionary::from_iter(vec![("ca", Real(f64::from(alpha) / 255.0))]);
+            self.ext_g_states.set(name.clone(), Dictionary(ext_g_state));
+        }
+        name
+    }
+
+    /// Converts the resources into the `Resources` dictionary of the Type3 font
+    pub(crate) fn into_dictionary(self) -> LoDictionary {
+        let mut resources = LoDictionary::new();
+        if !self.xobjects.is_empty() {
+            resources.set("XObject", Dictionary(self.xobjects));
+        }
+        if !self.ext_g_states.is_empty() {
+            resources.set("ExtGState", Dictionary(self.ext_g_states));
+        }
+        resources
+    }
+}
+
+/// Draws the glyph in font units, as the content stream of a Type3 glyph procedure.
+///
+/// Color layers are used if the glyph has them, otherwise the largest PNG bitmap
+/// (if the `embedded_images` feature is enabled). Glyphs without color data are filled
+/// with their outline in the color of the text. Gradients (`COLR` version 1) are
+/// approximated by the color of their first stop.
+pub(crate) fn glyph_procedure(face: &Face, glyph_id: u16, resources: &mut GlyphResources, doc: &mut lopdf::Document) -> Vec<u8> {
+    let glyph = GlyphId(glyph_id);
+    let width = face.glyph_hor_advance(glyph).unwrap_or(0);
+
+    // d0 instead of d1, so that the glyph can set its own colors
+    let mut operations = vec![Operation::new("d0", vec![Integer(i64::from(width)), Integer(0)])];
+
+    let foreground = foreground_paints(face, glyph);
+    let mut painter = ColorPainter { face, path: Vec::new(), operations: Vec::new(), resources, foreground, paints: 0 };
+    if face.is_color_glyph(glyph) && face.paint_color_glyph(glyph, 0, FOREGROUNDS[0], &mut painter).is_some() {
+        operations.append(&mut painter.operations);
+    } else if let Some(image_operations) = raster_glyph(face, glyph, resources, doc) {
+        operations.extend(image_operations);
+    } else {
+        let mut path = Vec::new();
+        face.outline_glyph(glyph, &mut PathBuilder::new(&mut path));
+        if !path.is_empty() {
+            operations.extend(path);
+            operations.push(Operation::new("f", vec![]));
+        }
+    }
+
+    Content { operations }.encode().unwrap_or_default()
+}
+
+/// Returns for each paint of the color glyph (in the order in which they are painted)
+/// whether it is in the text color, which the painter can't tell from the color alone
+fn foreground_paints(face: &Face, glyph: GlyphId) -> Vec<bool> {
+    let mut passes = FOREGROUNDS.iter().map(|&foreground| {
+        let mut recorder = PaintRecorder { face, colors: Vec::new() };
+        face.paint_color_glyph(glyph, 0, foreground, &mut recorder);
+        recorder.colors
+    });
+    let (first, second) = (passes.next().unwrap_or_default(), passes.next().unwrap_or_default());
+    first.iter().zip(&second).map(|(a, b)| {
+        a.map(|a| (a.red, a.green, a.blue)) != b.map(|b| (b.red, b.green, b.blue))
+    }).collect()
+}
+
+/// Adds the bitmap of the glyph to the document as an image (with its alpha channel
+/// as soft mask) and returns the operations that draw it
+#[cfg(feature = "embedded_images")]
+fn raster_glyph(face: &Face, glyph: GlyphId, resources: &mut GlyphResources, doc: &mut lopdf::Document) -> Option<Vec<Operation>> {
+    use image;
+    use lopdf::Stream as LoStream;
+
+    let raster = face.glyph_raster_image(glyph, u16::MAX)?;
+    if raster.format != ttf_parser::RasterImageFormat::PNG {
+        return None;
+    }
+
+    let image = image::load_from_memory_with_format(raster.data, image::ImageFormat::Png).ok()?.to_rgba8();
+    let (width, height) = image.dimensions();
+
+    let mut rgb = Vec::with_capacity(image.len() / 4 * 3);
+    let mut alpha = Vec::with_capacity(image.len() / 4);
+    for pixel in image.pixels() {
+        rgb.extend_from_slice(&pixel.0[..3]);
+        alpha.push(pixel.0[3]);
+    }
+
+    let image_dictionary = |color_space: &str| LoDictionary::from_iter(vec![
+        ("Type", Name("XObject".into())),
+        ("Subtype", Name("Image".into())),
+        ("Width", Integer(i64::from(width))),
+        ("Height", Integer(i64::from(height))),
+        ("ColorSpace", Name(color_space.into())),
+        ("BitsPerComponent", Integer(8)),
+    ]);
+
+    let soft_mask_id = doc.add_object(LoStream::new(image_dictionary("DeviceGray"), alpha));
+    let mut image_stream = LoStream::new(image_dictionary("DeviceRGB"), rgb);
+    image_stream.dict.set("SMask", Reference(soft_mask_id));
+
+    let name = format!("Im{}", glyph.0);
+    resources.xobjects.set(name.clone(), Reference(doc.add_object(image_stream)));
+
+    // the position of the bitmap is given in pixels, relative to the glyph origin
+    let scale = f64::from(face.units_per_em()) / f64::from(raster.pixels_per_em.max(1));
+    Some(vec![
+        Operation::new("q", vec![]),
+        Operation::new("cm", vec![
+            Real(f64::from(width) * scale), Integer(0),
+            Integer(0), Real(f64::from(height) * scale),
+            Real(f64::from(raster.x) * scale), Real(f64::from(raster.y) * scale),
+        ]),
+        Operation::new("Do", vec![Name(name.into_bytes())]),
+        Operation::new("Q", vec![]),
+    ])
+}
+
+#[cfg(not(feature = "embedded_images"))]
+fn raster_glyph(_face: &Face, _glyph: GlyphId, _resources: &mut GlyphResources, _doc: &mut lopdf::Document) -> Option<Vec<Operation>> {
+    None
+}
+
+/// Writes a glyph outline as PDF path operations
+struct PathBuilder<'p> {
+    operations: &'p mut Vec<Operation>,
+    last: (f32, f32),
+}
+
+impl<'p> PathBuilder<'p> {
+    fn new(operations: &'p mut Vec<Operation>) -> Self {
+        Self { operations, last: (0.0, 0.0) }
+    }
+
+    fn push(&mut self, operator: &str, points: &[(f32, f32)]) {
+        let operands = points.iter().flat_map(|&(x, y)| vec![Real(f64::from(x)), Real(f64::from(y))]).collect();
+        self.operations.push(Operation::new(operator, operands));
+        if let Some(&last) = points.last() {
+            self.last = last;
+        }
+    }
+}
+
+impl<'p> OutlineBuilder for PathBuilder<'p> {
+
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.push("m", &[(x, y)]);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.push("l", &[(x, y)]);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        // PDF only has cubic curves, the quadratic curve is raised by one degree
+        let (x0, y0) = self.last;
+        let control_1 = (x0 + 2.0 / 3.0 * (x1 - x0), y0 + 2.0 / 3.0 * (y1 - y0));
+        let control_2 = (x + 2.0 / 3.0 * (x1 - x), y + 2.0 / 3.0 * (y1 - y));
+        self.push("c", &[control_1, control_2, (x, y)]);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.push("c", &[(x1, y1), (x2, y2), (x, y)]);
+    }
+
+    fn close(&mut self) {
+        self.push("h", &[]);
+    }
+}
+
+/// Returns the color of a paint, gradients are approximated by their first stop
+fn paint_color(paint: colr::Paint, coords: &[ttf_parser::NormalizedCoordinate]) -> Option<RgbaColor> {
+    match paint {
+        colr::Paint::Solid(color) => Some(color),
+        colr::Paint::LinearGradient(gradient) => gradient.stops(0, coords).next().map(|stop| stop.color),
+        colr::Paint::RadialGradient(gradient) => gradient.stops(0, coords).next().map(|stop| stop.color),
+        colr::Paint::SweepGradient(gradient) => gradient.stops(0, coords).next().map(|stop| stop.color),
+    }
+}
+
+/// Only records the colors of the paints of a color glyph
+struct PaintRecorder<'f> {
+    face: &'f Face<'f>,
+    colors: Vec<Option<RgbaColor>>,
+}
+
+impl<'a, 'f> colr::Painter<'a> for PaintRecorder<'f> {
+    fn outline_glyph(&mut self, _glyph_id: GlyphId) { }
+    fn paint(&mut self, paint: colr::Paint<'a>) {
+        self.colors.push(paint_color(paint, self.face.variation_coordinates()));
+    }
+    fn push_clip(&mut self) { }
+    fn push_clip_box(&mut self, _clipbox: colr::ClipBox) { }
+    fn pop_clip(&mut self) { }
+    fn push_layer(&mut self, _mode: colr::CompositeMode) { }
+    fn pop_layer(&mut self) { }
+    fn push_transform(&mut self, _transform: ttf_parser::Transform) { }
+    fn pop_transform(&mut self) { }
+}
+
+/// Converts the color layers of a glyph into PDF operations
+struct ColorPainter<'f, 'r> {
+    face: &'f Face<'f>,
+    /// The outline that was stored last, as path operations
+    path: Vec<Operation>,
+    operations: Vec<Operation>,
+    resources: &'r mut GlyphResources,
+    /// Whether each paint is in the text color (see `foreground_paints`)
+    foreground: Vec<bool>,
+    /// Number of paints so far
+    paints: usize,
+}
+
+impl<'a, 'f, 'r> colr::Painter<'a> for ColorPainter<'f, 'r> {
+
+    fn outline_glyph(&mut self, glyph_id: GlyphId) {
+        self.path.clear();
+        self.face.outline_glyph(glyph_id, &mut PathBuilder::new(&mut self.path));
+    }
+
+    fn paint(&mut self, paint: colr::Paint<'a>) {
+        let is_foreground = self.foreground.get(self.paints).cloned().unwrap_or(false);
+        self.paints += 1;
+        let color = match paint_color(paint, self.face.variation_coordinates()) {
+            Some(color) if !self.path.is_empty() => color,
+            _ => return,
+        };
+
+        self.operations.push(Operation::new("q", vec![]));
+        if !is_foreground {
+            let components = [color.red, color.green, color.blue].iter().map(|&c| Real(f64::from(c) / 255.0)).collect();
+            self.operations.push(Operation::new("rg", components));
+        }
+        if color.alpha < 255 {
+            let ext_g_state = self.resources.alpha(color.alpha);
+            self.operations.push(Operation::new("gs", vec![Name(ext_g_state.into_bytes())]));
+        }
+        self.operations.extend(self.path.iter().cloned());
+        self.operations.push(Operation::new("f", vec![]));
+        self.operations.push(Operation::new("Q", vec![]));
+    }
+
+    fn push_clip(&mut self) {
+        self.operations.push(Operation::new("q", vec![]));
+        self.operations.extend(self.path.iter().cloned());
+        self.operations.push(Operation::new("W", vec![]));
+        self.operations.push(Operation::new("n", vec![]));
+    }
+
+    fn push_clip_box(&mut self, clipbox: colr::ClipBox) {
+        self.operations.push(Operation::new("q", vec![]));
+        self.operations.push(Operation::new("re", vec![
+            Real(f64::from(clipbox.x_min)), Real(f64::from(clipbox.y_min)),
+            Real(f64::from(clipbox.x_max - clipbox.x_min)), Real(f64::from(clipbox.y_max - clipbox.y_min)),
+        ]));
+        self.operations.push(Operation::new("W", vec![]));
+        self.operations.push(Operation::new("n", vec![]));
+    }
+
+    fn pop_clip(&mut self) {
+        self.operations.push(Operation::new("Q", vec![]));
+    }
+
+    fn push_layer(&mut self, _mode: colr::CompositeMode) {
+        // composite modes aren't supported, layers are drawn over each other
+        self.operations.push(Operation::new("q", vec![]));
+    }
+
+    fn pop_layer(&mut self) {
+        self.operations.push(Operation::new("Q", vec![]));
+    }
+
+    fn push_transform(&mut self, transform: ttf_parser::Transform) {
+        let ttf_parser::Transform { a, b, c, d, e, f } = transform;
+        self.operations.push(Operation::new("q", vec![]));
+        self.operations.push(Operation::new("cm", [a, b, c, d, e, f].iter().map(|&v| Real(f64::from(v))).collect()));
+    }
+
+    fn pop_transform(&mut self) {
+        self.operations.push(Operation::new("Q", vec![]));
+    }
+}
+
+#[test]
+fn test_color_glyph_procedure() {
+    // Roboto with a COLR / CPAL table: "a" is drawn as a red "a" with an "o" in the text color
+    // on top and another "a" in a palette color that could be mistaken for the text color
+    let roboto = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let face = Face::parse(roboto, 0).unwrap();
+    let (a, o) = (face.glyph_index('a').unwrap().0, face.glyph_index('o').unwrap().0);
+
+    let mut colr = vec![0, 0, 0, 1, 0, 0, 0, 14, 0, 0, 0, 20, 0, 3];
+    for value in &[a, 0, 3, a, 0, o, 0xFFFF, a, 1] {
+        colr.extend_from_slice(&value.to_be_bytes());
+    }
+    // one palette with two colors, stored as BGRA
+    let cpal = vec![0, 0, 0, 2, 0, 1, 0, 2, 0, 0, 0, 14, 0, 0, 0, 0, 255, 255, 0, 0, 0, 255];
+
+    let color_font = super::sfnt::patched_roboto(|_, tables| {
+        tables.push((*b"COLR", colr));
+        tables.push((*b"CPAL", cpal));
+    });
+    let color_face = Face::parse(&color_font, 0).unwrap();
+    assert!(has_color_glyphs(&color_face) && !has_color_glyphs(&face));
+
+    let mut resources = GlyphResources::default();
+    let procedure = glyph_procedure(&color_face, a, &mut resources, &mut lopdf::Document::new());
+    let procedure = String::from_utf8(procedure).unwrap();
+    let operators: Vec<&str> = procedure.split_whitespace()
+        .filter(|token| ["d0", "q", "Q", "rg", "gs", "f"].contains(token))
+        .collect();
+    assert_eq!(operators, vec!["d0", "q", "rg", "f", "Q", "q", "f", "Q", "q", "rg", "f", "Q"]);
+    assert!(procedure.contains("1 0 0 rg") && procedure.contains("0 0 0 rg"));
+}
@@ -0,0 +1,39 @@
+//! Single-byte encodings for the builtin fonts
+
+/// Characters of the codes 0x80 - 0x9F in WinAnsiEncoding (see PDF Reference 1.7, Appendix D).
+/// All other codes from 0x20 to 0xFF are the same as in Unicode (Latin-1).
+const WIN_ANSI_0X80: [Option<char>; 32] = [
+    Some('\u{20AC}'), None,             Some('\u{201A}'), Some('\u{0192}'),
+    Some('\u{201E}'), Some('\u{2026}'), Some('\u{2020}'), Some('\u{2021}'),
+    Some('\u{02C6}'), Some('\u{2030}'), Some('\u{0160}'), Some('\u{2039}'),
+    Some('\u{0152}'), None,             Some('\u{017D}'), None,
+    None,             Some('\u{2018}'), Some('\u{2019}'), Some('\u{201C}'),
+    Some('\u{201D}'), Some('\u{2022}'), Some('\u{2013}'), Some('\u{2014}'),
+    Some('\u{02DC}'), Some('\u{2122}'), Some('\u{0161}'), Some('\u{203A}'),
+    Some('\u{0153}'), None,             Some('\u{017E}'), Some('\u{0178}'),
+];
+
+/// Returns the code of the character in WinAnsiEncoding
+pub(crate) fn win_ansi_code(c: char) -> Option<u8> {
+    match c as u32 {
+        0x20..=0x7E | 0xA0..=0xFF => Some(c as u8),
+        _ => WIN_ANSI_0X80.iter().position(|&w| w == Some(c)).map(|i| 0x80 + i as u8),
+    }
+}
+
+/// Returns the character that the code represents in WinAnsiEncoding
+pub(crate) fn win_ansi_char(code: u8) -> Option<char> {
+    match code {
+        0x20..=0x7E | 0xA0..=0xFF => Some(code as char),
+        0x80..=0x9F => WIN_ANSI_0X80[(code - 0x80) as usize],
+        _ => None,
+    }
+}
+
+#[test]
+fn test_win_ansi_encoding() {
+    assert_eq!(win_ansi_code('\u{e9}'), Some(0xE9));
+    assert_eq!(win_ansi_code('\u{20AC}'), Some(0x80));
+    assert_eq!(win_ansi_code('\u{3A9}'), None);
+    assert_eq!(win_ansi_char(0x9F), Some('\u{178}'));
+}
@@ -0,0 +1,251 @@
+//! Text flow: paragraphs that continue from one frame (a column or a page) to the next
+
+use std::collections::VecDeque;
+use std::fmt;
+use indices::PdfLayerIndex;
+use {Mm, Paragraph, PdfLayerReference, PdfPageReference};
+
+/// A rectangle on a page that text can be flowed into
+pub struct Frame {
+    page: PdfPageReference,
+    layer: PdfLayerIndex,
+    /// Lower left corner
+    x: Mm,
+    y: Mm,
+    width: Mm,
+    height: Mm,
+}
+
+/// Fills a sequence of frames with paragraphs: when a paragraph doesn't fit into the
+/// rest of the current frame, the lines that don't fit are continued at the top of the
+/// next frame. When all frames are filled, the closure is called to add a new page
+/// and to return the frames on it.
+///
+/// ```rust,ignore
+/// let column = |page: PdfPageIndex, x: f64| Frame::new(doc.get_page(page), Mm(x), Mm(20.0), Mm(80.0), Mm(257.0));
+/// let mut flow = TextFlow::new(vec![column(page1, 20.0), column(page1, 110.0)], || {
+///     let (page, _) = doc.add_page(Mm(210.0), Mm(297.0), "Layer 1");
+///     vec![column(page, 20.0), column(page, 110.0)]
+/// });
+/// for text in texts {
+///     flow.add_paragraph(&Paragraph::new(text, &font, 10.0, 12.0, Mm(80.0)).with_align(TextAlign::Justify));
+///     flow.add_space(Mm(3.0));
+/// }
+/// ```
+pub struct TextFlow<F> where F: FnMut() -> Vec<Frame> {
+    /// The current frame and the frames after it
+    frames: VecDeque<Frame>,
+    /// Height that is used in the current frame
+    used: Mm,
+    new_frames: F,
+}
+
+impl Frame {
+
+    /// Creates a frame with the lower left corner at `x` / `y` on the first layer of the page
+    pub fn new(page: PdfPageReference, x: Mm, y: Mm, width: Mm, height: Mm)
+    -> Self
+    {
+        Self {
+            page,
+            layer: PdfLayerIndex(0),
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Sets the layer of the page that the text is written to
+    pub fn with_layer(mut self, layer: PdfLayerIndex)
+    -> Self
+    {
+        self.layer = layer;
+        self
+    }
+
+    /// Returns the layer that the text is written to
+    pub fn layer(&self)
+    -> PdfLayerReference
+    {
+        self.page.get_layer(self.layer)
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("page", &self.page.page)
+            .field("layer", &self.layer)
+            .field("x", &self.x)
+            .field("y", &self.y)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
+    }
+}
+
+impl<F> fmt::Debug for TextFlow<F> where F: FnMut() -> Vec<Frame> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TextFlow")
+            .field("frames", &self.frames)
+            .field("used", &self.used)
+            .finish()
+    }
+}
+
+impl<F> TextFlow<F> where F: FnMut() -> Vec<Frame> {
+
+    /// Creates a flow that starts in the first of the frames. `new_frames` is called
+    /// when all frames are filled, it should add a page (see `PdfDocumentReference::add_page`)
+    /// and return the frames on it.
+    pub fn new(frames: Vec<Frame>, new_frames: F)
+    -> Self
+    {
+        Self {
+            frames: frames.into(),
+            used: Mm(0.0),
+            new_frames,
+        }
+    }
+
+    /// Writes the paragraph below the previous one. The paragraph is broken into lines
+    /// of the width of each frame it is written to (instead of its own width), its maximum
+    /// height is ignored.
+    ///
+    /// Returns the text that couldn't be written: if `new_frames` returned no frames,
+    /// or frames that are too small for a single line.
+    pub fn add_paragraph(&mut self, paragraph: &Paragraph)
+    -> Option<String>
+    {
+        let mut paragraph = paragraph.clone();
+        // whether the frames were just created and nothing was written into them yet
+        let mut stalled = false;
+
+        loop {
+            if self.frames.is_empty() {
+                if stalled {
+                    return Some(paragraph.text().to_string());
+                }
+                self.frames = (self.new_frames)().into();
+                self.used = Mm(0.0);
+                stalled = true;
+                continue;
+            }
+
+            let layout = {
+                let frame = &self.frames[0];
+                let top = Mm(frame.y.0 + frame.height.0 - self.used.0);
+                paragraph.clone()
+                    .with_width(frame.width)
+                    .with_max_height(Mm((frame.height.0 - self.used.0).max(0.0)))
+                    .render(&frame.layer(), frame.x, top)
+            };
+            self.used = Mm(self.used.0 + layout.height.0);
+
+            if layout.lines > 0 {
+                stalled = false;
+            }
+
+            match layout.overflow {
+                Some(overflow) => {
+                    paragraph = paragraph.with_text(overflow);
+                    self.next_frame();
+                },
+                None => return None,
+            }
+        }
+    }
+
+    /// Adds vertical space below the last paragraph. If the space doesn't fit into the
+    /// current frame, the next paragraph starts at the top of the next frame.
+    pub fn add_space(&mut self, height: Mm)
+    {
+        let fits = match self.frames.front() {
+            Some(frame) => self.used.0 + height.0 <= frame.height.0 + 1e-9,
+            None => true,
+        };
+        if fits {
+            self.used = Mm(self.used.0 + height.0);
+        } else {
+            self.next_frame();
+        }
+    }
+
+    /// Continues in the next frame (like a column break), the rest of the current
+    /// frame stays empty
+    pub fn next_frame(&mut self)
+    {
+        self.frames.pop_front();
+        self.used = Mm(0.0);
+    }
+
+    /// Returns the current frame and the height (from its top) that is already used,
+    /// for example to draw something else below the text
+    pub fn current_frame(&self)
+    -> Option<(&Frame, Mm)>
+    {
+        self.frames.front().map(|frame| (frame, self.used))
+    }
+}
+
+#[test]
+fn test_text_flow() {
+    use lopdf::Object;
+    use std::cell::Cell;
+    use {BuiltinFont, PdfDocument, Pt};
+
+    let (doc, page, _) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+
+    // two columns with room for two lines each on the first page, one column with
+    // room for 20 lines on the next pages
+    let column = |x: f64| Frame::new(doc.get_page(page), Mm(x), Mm(200.0), Pt(40.0).into(), Pt(24.0).into());
+    let new_pages = Cell::new(0);
+    let mut flow = TextFlow::new(vec![column(10.0), column(100.0)], || {
+        new_pages.set(new_pages.get() + 1);
+        let (page, layer) = doc.add_page(Mm(210.0), Mm(297.0), "Layer 1");
+        vec![Frame::new(doc.get_page(page), Mm(10.0), Mm(20.0), Pt(40.0).into(), Pt(250.0).into()).with_layer(layer)]
+    });
+
+    // the lines written on a page, with the x position (in points) they start at
+    let lines = |page: usize| {
+        let mut x = 0.0;
+        let mut lines = Vec::new();
+        for operation in &doc.document.borrow().pages[page].layers[0].operations {
+            let strings = match &operation.operator[..] {
+                "Tm" => { x = operation.operands[4].as_f64().unwrap_or(0.0); continue; },
+                "Tj" => operation.operands.clone(),
+                "TJ" => operation.operands[0].as_array().cloned().unwrap_or_default(),
+                _ => continue,
+            };
+            let text: String = strings.iter().filter_map(|object| match *object {
+                Object::String(ref bytes, _) => Some(String::from_utf8_lossy(bytes).into_owned()),
+                _ => None,
+            }).collect();
+            lines.push((x.round(), text));
+        }
+        lines
+    };
+
+    // "aaa bbb" and "ccc ddd" fill the first column, "eee" starts the second one
+    let paragraph = Paragraph::new("aaa bbb ccc ddd eee", &font, 10.0, 12.0, Mm(100.0));
+    assert_eq!(flow.add_paragraph(&paragraph), None);
+    assert_eq!(flow.current_frame().map(|(_, used)| Pt::from(used).0.round()), Some(12.0));
+    assert_eq!(new_pages.get(), 0);
+    let (left, right) = (Pt::from(Mm(10.0)).0.round(), Pt::from(Mm(100.0)).0.round());
+    assert_eq!(lines(0), vec![(left, "aaa bbb".to_string()), (left, "ccc ddd".to_string()), (right, "eee".to_string())]);
+
+    // after the space, the second column is full: the second paragraph is written on a new page
+    flow.add_space(Pt(12.0).into());
+    assert_eq!(flow.add_paragraph(&paragraph), None);
+    assert_eq!(new_pages.get(), 1);
+    assert_eq!(doc.document.borrow().pages.len(), 2);
+    assert_eq!(lines(0).len(), 3);
+    assert_eq!(lines(1), vec![(left, "aaa bbb".to_string()), (left, "ccc ddd".to_string()), (left, "eee".to_string())]);
+    assert!(format!("{:?}", flow).starts_with("TextFlow { frames: [Frame { page: PdfPageIndex(1)"));
+
+    // frames that are too small for a line
+    let mut flow = TextFlow::new(Vec::new(), || vec![Frame::new(doc.get_page(page), Mm(10.0), Mm(20.0), Mm(40.0), Pt(5.0).into())]);
+    assert_eq!(flow.add_paragraph(&paragraph), Some("aaa bbb ccc ddd eee".to_string()));
+}
@@ -2,23 +2,37 @@
 
 //! Embedding fonts in 2D for Pdf
 use lopdf;
+use subsetter;
 use lopdf::{Stream as LoStream, Dictionary as LoDictionary};
 use lopdf::StringFormat;
 use std::collections::{HashMap, BTreeMap};
 use std::iter::FromIterator;
-use Error;
+use std::ops::Range;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use {Error, PdfError};
+use scale::{Mm, Pt};
+use super::afm::{AfmCharMetrics, AfmFontMetrics};
+use super::glyph_names::glyph_name_to_unicode;
+use super::color_font::{glyph_procedure, has_color_glyphs, GlyphResources};
+use super::encoding::{win_ansi_char, win_ansi_code};
+use super::sfnt::{extract_collection_face, glyph_hor_advance, instance_glyf_font};
+use super::shaping::{visual_text, TextDirection};
+use super::type1::Type1Program;
+use super::woff::decode_web_font;
+use utils::{kerning_for_glyphs, vertical_advance, vertical_glyph_ids};
 
-use rusttype::FontCollection;
-use rusttype::Codepoint as Cp;
-use rusttype::GlyphId as Gid;
 
 /// The font
 #[derive(Debug, Clone, PartialEq)]
 pub enum Font {
     /// Represents one of the 14 built-in fonts (Arial, Helvetica, etc.)
     BuiltinFont(BuiltinFont),
-    /// Represents a font loaded from an external file
-    ExternalFont(ExternalFont),
+    /// Represents a font loaded from an external file (boxed, since the parsed
+    /// font is a few kilobytes large)
+    ExternalFont(Box<ExternalFont>),
+    /// Represents a PostScript Type1 font loaded from a PFB / PFA and an AFM file
+    Type1Font(Box<Type1Font>),
 }
 
 /// Standard built-in PDF fonts
@@ -62,6 +76,98 @@ impl Into<&'static str> for BuiltinFont {
     }
 }
 
+impl BuiltinFont {
+
+    /// Returns the metrics of the font, parsed from the AFM files of the 14 standard fonts
+    pub(crate) fn metrics(&self) -> &'static AfmFontMetrics {
+        static METRICS: [OnceLock<AfmFontMetrics>; 14] = [const { OnceLock::new() }; 14];
+
+        METRICS[*self as usize].get_or_init(|| {
+            AfmFontMetrics::parse(self.afm()).expect("invalid AFM file for builtin font")
+        })
+    }
+
+    /// Returns true for the fonts that use their own builtin encoding
+    /// (Symbol and ZapfDingbats) instead of WinAnsiEncoding
+    fn has_builtin_encoding(&self) -> bool {
+        matches!(*self, BuiltinFont::Symbol | BuiltinFont::ZapfDingbats)
+    }
+
+    /// Encodes the text as single-byte codes, with WinAnsiEncoding (or the builtin
+    /// encoding of Symbol and ZapfDingbats). Fails with `PdfError::UnencodableCharacter`
+    /// for the first character that is not available in the encoding.
+    pub fn encode_text(&self, text: &str) -> Result<Vec<u8>, Error> {
+        text.chars()
+            .map(|c| self.encode_char(c).ok_or_else(|| PdfError::UnencodableCharacter(c).into()))
+            .collect()
+    }
+
+    /// Encodes the text like `encode_text`, but characters that are not available
+    /// in the encoding are replaced (with `?`, or a space in ZapfDingbats).
+    /// Returns the codes and the characters that were replaced.
+    pub(crate) fn encode_text_lossy(&self, text: &str) -> (Vec<u8>, Vec<char>) {
+        let replacement = if *self == BuiltinFont::ZapfDingbats { b' ' } else { b'?' };
+        let mut unencodable = Vec::new();
+        let codes = text.chars().map(|c| {
+            self.encode_char(c).unwrap_or_else(|| {
+                unencodable.push(c);
+                replacement
+            })
+        }).collect();
+        (codes, unencodable)
+    }
+
+    /// Returns the code of the character in the encoding of the font
+    fn encode_char(&self, c: char) -> Option<u8> {
+        if self.has_builtin_encoding() {
+            self.metrics().char_metrics(c).and_then(|m| m.code)
+        } else {
+            win_ansi_code(c)
+        }
+    }
+
+    /// Returns the metrics of the glyph that the code stands for
+    fn code_metrics(&self, code: u8) -> Option<&'static AfmCharMetrics> {
+        if self.has_builtin_encoding() {
+            self.metrics().code_metrics(code)
+        } else {
+            win_ansi_char(code).and_then(|c| self.metrics().char_metrics(c))
+        }
+    }
+
+    /// Returns the kerning that has to be applied before each code, in thousandths
+    /// of an em (same as `kerning_for_glyphs` for external fonts)
+    pub(crate) fn kerning_for_codes(&self, codes: &[u8]) -> Vec<i64> {
+        let mut kerning = vec![0; codes.len()];
+        for (i, pair) in codes.windows(2).enumerate() {
+            if let (Some(left), Some(right)) = (self.code_metrics(pair[0]), self.code_metrics(pair[1])) {
+                kerning[i + 1] = self.metrics().kerning(&left.name, &right.name).round() as i64;
+            }
+        }
+        kerning
+    }
+
+    fn afm(&self) -> &'static str {
+        use BuiltinFont::*;
+        match self {
+            TimesRoman              => include_str!("../../../../../assets/afm/Times-Roman.afm"),
+            TimesBold               => include_str!("../../../../../assets/afm/Times-Bold.afm"),
+            TimesItalic             => include_str!("../../../../../assets/afm/Times-Italic.afm"),
+            TimesBoldItalic         => include_str!("../../../../../assets/afm/Times-BoldItalic.afm"),
+            Helvetica               => include_str!("../../../../../assets/afm/Helvetica.afm"),
+            HelveticaBold           => include_str!("../../../../../assets/afm/Helvetica-Bold.afm"),
+            HelveticaOblique        => include_str!("../../../../../assets/afm/Helvetica-Oblique.afm"),
+            HelveticaBoldOblique    => include_str!("../../../../../assets/afm/Helvetica-BoldOblique.afm"),
+            Courier                 => include_str!("../../../../../assets/afm/Courier.afm"),
+            CourierOblique          => include_str!("../../../../../assets/afm/Courier-Oblique.afm"),
+            CourierBold             => include_str!("../../../../../assets/afm/Courier-Bold.afm"),
+            CourierBoldOblique      => include_str!("../../../../../assets/afm/Courier-BoldOblique.afm"),
+            Symbol                  => include_str!("../../../../../assets/afm/Symbol.afm"),
+            ZapfDingbats            => include_str!("../../../../../assets/afm/ZapfDingbats.afm"),
+        }
+    }
+}
+
 impl Into<LoDictionary> for BuiltinFont {
     fn into(self) -> LoDictionary {
         use lopdf::Object;
@@ -70,26 +176,530 @@ impl Into<LoDictionary> for BuiltinFont {
         let font_id: &'static str = self.into();
 
         // Begin setting required font attributes
-        let font_vec: Vec<(::std::string::String, Object)> = vec![
+        let mut font_vec: Vec<(::std::string::String, Object)> = vec![
             ("Type".into(), Name("Font".into())),
             ("Subtype".into(), Name("Type1".into())),
             ("BaseFont".into(), Name(font_id.into())),
-            ("Encoding".into(), Name("WinAnsiEncoding".into())),
-            // Missing DescendantFonts and ToUnicode
         ];
 
+        // Symbol and ZapfDingbats have to use their builtin encoding
+        let (first_char, last_char) = if self.has_builtin_encoding() {
+            let codes = self.metrics().chars.iter().filter_map(|c| c.code);
+            (codes.clone().min().unwrap_or(0), codes.max().unwrap_or(0))
+        } else {
+            font_vec.push(("Encoding".into(), Name("WinAnsiEncoding".into())));
+            (32, 255)
+        };
+
+        let widths = (first_char..=last_char).map(|code| {
+            Integer(self.code_metrics(code).map(|m| m.width.round() as i64).unwrap_or(0))
+        }).collect();
+
+        font_vec.push(("FirstChar".into(), Integer(i64::from(first_char))));
+        font_vec.push(("LastChar".into(), Integer(i64::from(last_char))));
+        font_vec.push(("Widths".into(), Array(widths)));
+
         LoDictionary::from_iter(font_vec)
     }
 }
 
+/// A PostScript Type1 font, loaded from the font program (PFB or PFA file) and the
+/// AFM file with its metrics (see `PdfDocumentReference::add_type1_font`)
+///
+/// The font is embedded as a simple font with single-byte codes: each glyph that is
+/// written gets a code (the code of the builtin encoding of the font if possible) and
+/// the `Differences` of the encoding map the codes to the glyph names. So at most 256
+/// different glyphs can be used per font. The font program isn't subsetted.
+#[derive(Debug, Clone)]
+pub struct Type1Font {
+    /// The font program, embedded as `FontFile`
+    program: Type1Program,
+    /// Metrics of the font, from the AFM file
+    metrics: AfmFontMetrics,
+    /// Font name, for adding as a resource on the document
+    pub(crate) face_name: String,
+    /// PostScript name of the font
+    font_name: String,
+    /// Glyphs that were written (indices into the characters of `metrics`) by their code
+    codes: BTreeMap<u8, usize>,
+}
+
+impl Type1Font {
+
+    /// Creates a new font from the font program (PFB or PFA) and the AFM file with the
+    /// metrics. The `index` is used for naming / identifying the font
+    pub fn new<R, A>(mut font_stream: R, mut afm_stream: A, font_index: usize)
+    -> Result<Self, Error> where R: ::std::io::Read, A: ::std::io::Read
+    {
+        let mut buf = Vec::<u8>::new();
+        font_stream.read_to_end(&mut buf)?;
+        let program = Type1Program::parse(&buf)?;
+
+        // AFM files are ASCII, apart from comments in some other encoding
+        let mut afm = Vec::<u8>::new();
+        afm_stream.read_to_end(&mut afm)?;
+        let metrics = AfmFontMetrics::parse(&String::from_utf8_lossy(&afm)).ok_or(PdfError::FontFaceError)?;
+
+        let face_name = format!("F{}", font_index);
+        let font_name = Some(metrics.font_name.clone()).filter(|name| !name.is_empty())
+            .or_else(|| program.font_name())
+            .unwrap_or_else(|| face_name.clone());
+
+        Ok(Self {
+            program,
+            metrics,
+            face_name,
+            font_name,
+            codes: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the PostScript name of the font
+    #[inline]
+    pub fn font_name(&self) -> &str {
+        &self.font_name
+    }
+
+    /// Returns the metrics of the font
+    #[inline]
+    pub(crate) fn metrics(&self) -> &AfmFontMetrics {
+        &self.metrics
+    }
+
+    /// Returns true if the font has a glyph for the character
+    pub(crate) fn has_char(&self, c: char) -> bool {
+        self.metrics.char_metrics(c).is_some()
+    }
+
+    /// Encodes the text as single-byte codes, characters that the font doesn't have (or
+    /// that don't get a code anymore) are replaced with `?`. Returns the codes and the
+    /// characters that were replaced.
+    pub(crate) fn encode_text_lossy(&mut self, text: &str) -> (Vec<u8>, Vec<char>) {
+        let mut unencodable = Vec::new();
+        let codes = text.chars().map(|c| {
+            self.encode_char(c).unwrap_or_else(|| {
+                unencodable.push(c);
+                self.encode_char('?').unwrap_or(0)
+            })
+        }).collect();
+        (codes, unencodable)
+    }
+
+    /// Returns the code of the character, the glyph gets a code if it doesn't have one yet
+    fn encode_char(&mut self, c: char) -> Option<u8> {
+        let index = self.metrics.by_unicode(c)?;
+        if let Some((&code, _)) = self.codes.iter().find(|&(_, &code_index)| code_index == index) {
+            return Some(code);
+        }
+
+        // the code of the builtin encoding (so that the space is code 32, which word
+        // spacing applies to), otherwise a code that isn't used by the builtin encoding
+        let is_free = |code: &u8| !self.codes.contains_key(code);
+        let code = self.metrics.chars[index].code.filter(is_free)
+            .or_else(|| (1..=255).filter(is_free).find(|&code| self.metrics.code_metrics(code).is_none()))
+            .or_else(|| (1..=255).find(is_free))?;
+
+        self.codes.insert(code, index);
+        Some(code)
+    }
+
+    /// Returns the metrics of the glyph that the code was assigned to
+    fn code_metrics(&self, code: u8) -> Option<&AfmCharMetrics> {
+        self.codes.get(&code).map(|&index| &self.metrics.chars[index])
+    }
+
+    /// Returns the kerning that has to be applied before each code, in thousandths
+    /// of an em (same as `BuiltinFont::kerning_for_codes`)
+    pub(crate) fn kerning_for_codes(&self, codes: &[u8]) -> Vec<i64> {
+        let mut kerning = vec![0; codes.len()];
+        for (i, pair) in codes.windows(2).enumerate() {
+            if let (Some(left), Some(right)) = (self.code_metrics(pair[0]), self.code_metrics(pair[1])) {
+                kerning[i + 1] = self.metrics.kerning(&left.name, &right.name).round() as i64;
+            }
+        }
+        kerning
+    }
+
+    /// Adds the font program to the document and returns the font dictionary: a
+    /// `Type1` font whose encoding has the names of the glyphs that were written as
+    /// `Differences`, with a ToUnicode map for the codes
+    pub(crate) fn into_with_document(self, doc: &mut lopdf::Document)
+    -> LoDictionary
+    {
+        use lopdf::Object::*;
+
+        const FIXED_PITCH: i64 = 1 << 0;
+        const SYMBOLIC: i64 = 1 << 2;
+        const NONSYMBOLIC: i64 = 1 << 5;
+        const ITALIC: i64 = 1 << 6;
+
+        let metrics = &self.metrics;
+        let first_char = self.codes.keys().next().cloned().unwrap_or(32);
+        let last_char = self.codes.keys().next_back().cloned().unwrap_or(32);
+
+        let widths = (first_char..=last_char).map(|code| {
+            Integer(self.code_metrics(code).map(|m| m.width.round() as i64).unwrap_or(0))
+        }).collect();
+
+        // consecutive codes only need the first code: [32 /space /exclam 200 /eacute]
+        let mut differences = Vec::new();
+        let mut next_code = None;
+        for (&code, &index) in &self.codes {
+            if next_code != Some(code) {
+                differences.push(Integer(i64::from(code)));
+            }
+            differences.push(Name(metrics.chars[index].name.clone().into_bytes()));
+            next_code = code.checked_add(1);
+        }
+
+        let code_texts: CmapBlock = self.codes.iter().filter_map(|(&code, &index)| {
+            glyph_name_to_unicode(&metrics.chars[index].name).next().map(|c| (u32::from(code), c.to_string()))
+        }).collect();
+        let to_unicode = generate_cid_to_unicode_map(self.font_name.clone(), code_texts.chunks(100).map(|block| block.to_vec()).collect(), 1);
+        let to_unicode_id = doc.add_object(LoStream::new(LoDictionary::new(), to_unicode.into_bytes()));
+
+        let font_stream = LoStream::new(
+            LoDictionary::from_iter(vec![
+                ("Length1", Integer(self.program.length1 as i64)),
+                ("Length2", Integer(self.program.length2 as i64)),
+                ("Length3", Integer(self.program.length3 as i64)),
+            ]),
+            self.program.data.clone())
+        .with_compression(false);
+
+        // text fonts use the standard Latin character set
+        let mut flags = if metrics.encoding_scheme == "AdobeStandardEncoding" { NONSYMBOLIC } else { SYMBOLIC };
+        if metrics.is_fixed_pitch {
+            flags |= FIXED_PITCH;
+        }
+        if metrics.italic_angle != 0.0 {
+            flags |= ITALIC;
+        }
+
+        let number = |value: f64| Integer(value.round() as i64);
+        let mut font_descriptor = LoDictionary::from_iter(vec![
+            ("Type", Name("FontDescriptor".into())),
+            ("FontName", Name(self.font_name.clone().into_bytes())),
+            ("Flags", Integer(flags)),
+            ("FontBBox", Array(metrics.font_bbox.iter().map(|&value| number(value)).collect())),
+            ("ItalicAngle", Real(metrics.italic_angle)),
+            ("Ascent", number(metrics.ascender)),
+            ("Descent", number(metrics.descender)),
+            ("CapHeight", number(metrics.cap_height.unwrap_or(metrics.ascender))),
+            // there is no stem width in AFM files without StdVW, 80 is a regular weight
+            ("StemV", number(metrics.std_vw.unwrap_or(80.0))),
+            ("FontFile", Reference(doc.add_object(font_stream))),
+        ]);
+        if let Some(x_height) = metrics.x_height {
+            font_descriptor.set("XHeight", number(x_height));
+        }
+
+        LoDictionary::from_iter(vec![
+            ("Type", Name("Font".into())),
+            ("Subtype", Name("Type1".into())),
+            ("BaseFont", Name(self.font_name.clone().into_bytes())),
+            ("FirstChar", Integer(i64::from(first_char))),
+            ("LastChar", Integer(i64::from(last_char))),
+            ("Widths", Array(widths)),
+            ("Encoding", Dictionary(LoDictionary::from_iter(vec![
+                ("Type", Name("Encoding".into())),
+                ("Differences", Array(differences)),
+            ]))),
+            ("FontDescriptor", Reference(doc.add_object(font_descriptor))),
+            ("ToUnicode", Reference(to_unicode_id)),
+        ])
+    }
+}
+
+impl PartialEq for Type1Font {
+    /// Two fonts are equal if their names are equal, the contents aren't checked
+    fn eq(&self, other: &Type1Font) -> bool {
+        self.face_name == other.face_name
+    }
+}
+
+/// Metrics of a text, as returned by `PdfDocumentReference::measure_text`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextMetrics {
+    /// Advance width of the whole text
+    pub width: Pt,
+    /// Ascent of the font (distance from the baseline to the top), positive
+    pub ascent: Pt,
+    /// Descent of the font (distance from the baseline to the bottom), negative
+    pub descent: Pt,
+    /// Advances of the individual glyphs, including kerning, in the order the
+    /// glyphs are written (visual order for right-to-left text)
+    pub glyph_advances: Vec<Pt>,
+}
+
+impl TextMetrics {
+    /// Returns the advance width of the whole text in millimeter
+    #[inline]
+    pub fn width_mm(&self) -> Mm {
+        self.width.into()
+    }
+
+    /// Returns the ascent of the font in millimeter
+    #[inline]
+    pub fn ascent_mm(&self) -> Mm {
+        self.ascent.into()
+    }
+
+    /// Returns the descent of the font in millimeter
+    #[inline]
+    pub fn descent_mm(&self) -> Mm {
+        self.descent.into()
+    }
+
+    /// Returns the advances of the individual glyphs in millimeter
+    pub fn glyph_advances_mm(&self) -> Vec<Mm> {
+        self.glyph_advances.iter().map(|&advance| advance.into()).collect()
+    }
+}
+
+impl Font {
+
+    /// Measures the text as `write_text` would write it (including kerning), reordered
+    /// for the base direction (see `PdfLayerReference::set_base_direction`). Character and
+    /// word spacing are not included. For vertical fonts, the width is the height of the text.
+    pub(crate) fn measure_text(&mut self, text: &str, font_size: f64, base_direction: Option<TextDirection>) -> TextMetrics {
+
+        // the kerning is applied between the glyphs in visual order
+        let visual = visual_text(text, base_direction);
+        let text = visual.as_deref().unwrap_or(text);
+
+        // all widths are in thousandths of an em
+        let (ascent, descent, advances) = match *self {
+            Font::ExternalFont(ref mut font) => {
+                let glyph_ids: Vec<u16> = text.chars().map(|c| font.glyph_id(c)).collect();
+
+                // vertical text is written with the vertical glyph forms and without kerning
+                let (glyph_ids, kerning) = if font.vertical_writing {
+                    let kerning = vec![0; glyph_ids.len()];
+                    (vertical_glyph_ids(font.face(), &glyph_ids), kerning)
+                } else {
+                    let kerning = kerning_for_glyphs(font.face(), &glyph_ids);
+                    (glyph_ids, kerning)
+                };
+
+                // same scaling and rounding as the widths (heights) in the font dictionary
+                let scale = 1000.0 / f64::from(font.face().units_per_em());
+                let bold_advance = font.synthetic_bold_advance();
+                let advances: Vec<f64> = glyph_ids.iter().enumerate().map(|(i, &glyph_id)| {
+                    let width = (f64::from(font.glyph_advance(glyph_id)) * scale) as i64 + bold_advance;
+                    // the kerning before the next glyph is part of this glyphs advance
+                    // (and the one before the first glyph part of the first advance)
+                    let kerning_before = if i == 0 { kerning[0] } else { 0 };
+                    (width + kerning_before + kerning.get(i + 1).cloned().unwrap_or(0)) as f64
+                }).collect();
+
+                (f64::from(font.face().ascender()) * scale, f64::from(font.face().descender()) * scale, advances)
+            },
+            Font::BuiltinFont(ref font) => {
+                // characters that can't be encoded are measured as their replacement
+                let (codes, _) = font.encode_text_lossy(text);
+                let kerning = font.kerning_for_codes(&codes);
+
+                let advances: Vec<f64> = codes.iter().enumerate().map(|(i, &code)| {
+                    let width = font.code_metrics(code).map(|m| m.width).unwrap_or(0.0);
+                    width + kerning.get(i + 1).cloned().unwrap_or(0) as f64
+                }).collect();
+
+                let metrics = font.metrics();
+                (metrics.ascender, metrics.descender, advances)
+            },
+            Font::Type1Font(ref font) => {
+                // characters that the font doesn't have are measured as their replacement
+                let metrics = font.metrics();
+                let glyphs: Vec<&AfmCharMetrics> = text.chars()
+                    .filter_map(|c| metrics.char_metrics(c).or_else(|| metrics.char_metrics('?')))
+                    .collect();
+
+                let advances: Vec<f64> = glyphs.iter().enumerate().map(|(i, glyph)| {
+                    let kerning = glyphs.get(i + 1).map(|next| metrics.kerning(&glyph.name, &next.name).round()).unwrap_or(0.0);
+                    glyph.width + kerning
+                }).collect();
+
+                (metrics.ascender, metrics.descender, advances)
+            },
+        };
+
+        let to_pt = |value: f64| Pt(value * font_size / 1000.0);
+        let glyph_advances: Vec<Pt> = advances.into_iter().map(to_pt).collect();
+
+        TextMetrics {
+            width: Pt(glyph_advances.iter().map(|advance| advance.0).sum()),
+            ascent: to_pt(ascent),
+            descent: to_pt(descent),
+            glyph_advances,
+        }
+    }
+}
+
+/// Selects a face in a font collection (`.ttc` / `.otc`)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CollectionFace {
+    /// Index of the face in the collection, starting at 0
+    Index(u32),
+    /// PostScript name of the face, for example "Helvetica-Bold"
+    PostScriptName(String),
+}
+
+impl From<u32> for CollectionFace {
+    fn from(face_index: u32) -> Self {
+        CollectionFace::Index(face_index)
+    }
+}
+
+impl<'a> From<&'a str> for CollectionFace {
+    fn from(postscript_name: &'a str) -> Self {
+        CollectionFace::PostScriptName(postscript_name.to_string())
+    }
+}
+
+impl From<String> for CollectionFace {
+    fn from(postscript_name: String) -> Self {
+        CollectionFace::PostScriptName(postscript_name)
+    }
+}
+
+/// Embedding permission of a font, from the `fsType` field of its `OS/2` table
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum EmbeddingPermission {
+    /// The font may be embedded without restrictions
+    Installable,
+    /// The font must not be embedded ("restricted license embedding"). Fonts that
+    /// only allow embedding their bitmaps are restricted as well, since the outlines
+    /// are embedded
+    Restricted,
+    /// The font may only be embedded in documents that are viewed and printed
+    PreviewAndPrint,
+    /// The font may be embedded in documents that are viewed, printed and edited
+    Editable,
+}
+
+/// Width of the outline stroke that is used for synthetic bold text, in ems
+/// (scaled to the font size when the font is set)
+pub(crate) const SYNTHETIC_BOLD_STROKE: f64 = 0.025;
+
+/// Synthetic styles for fonts that don't come with a bold or italic face
+/// (see `PdfDocumentReference::add_external_font_with_synthesis`)
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct FontSynthesis {
+    /// Emboldens the glyphs by filling and stroking their outlines (text rendering
+    /// mode `FillStroke`). The glyph widths grow by the width of the stroke.
+    pub bold: bool,
+    /// Slants the glyphs by the angle in degrees (positive angles slant the text
+    /// to the right, 12 is typical for an oblique face). 0 for upright text
+    pub oblique_angle: f64,
+}
+
+impl FontSynthesis {
+    /// Creates the default synthesis (upright and not emboldened)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Synthetic bold, upright
+    pub fn bold() -> Self {
+        Self { bold: true, oblique_angle: 0.0 }
+    }
+
+    /// Synthetic oblique at the given angle in degrees, not emboldened
+    pub fn oblique(angle: f64) -> Self {
+        Self { bold: false, oblique_angle: angle }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ExternalFont {
-    /// Font data
-    pub(crate) font_bytes: Vec<u8>,
+    /// The parsed font together with the font data, so that the font doesn't have to be
+    /// parsed for every text
+    pub(crate) face: ParsedFace,
     /// Font name, for adding as a resource on the document
     pub(crate) face_name: String,
     /// Is the font written vertically? Default: false
     pub(crate) vertical_writing: bool,
+    /// Axis coordinates of the instance that is used, if the font is a variable font
+    pub(crate) variations: Vec<(ttf_parser::Tag, f32)>,
+    /// Synthetic bold / oblique style that the text of this font is written with
+    pub(crate) synthesis: FontSynthesis,
+    /// Embedding permission from the `OS/2` table
+    embedding_permission: EmbeddingPermission,
+    /// Whether the license of the font allows embedding a subset (if not, the whole font is embedded)
+    subsetting_allowed: bool,
+    /// Glyph IDs by character code, if the font has color glyphs and is embedded as a
+    /// Type3 font (see `is_color_font`). Code 0 is always `.notdef`
+    color_glyphs: Option<Vec<u16>>,
+    /// Glyph IDs of the characters that were looked up so far
+    glyph_ids: HashMap<char, u16>,
+    /// Advances (widths, or heights for vertical fonts) of the glyphs that were looked up so far, in font units
+    advances: HashMap<u16, u16>,
+}
+
+/// A font face that is parsed once, together with the font data of its `ExternalFont`
+/// (the only place where the data is kept, see `bytes`)
+///
+/// `rustybuzz::Face` borrows the data it was parsed from, so the face is stored with a
+/// `'static` lifetime next to the `Arc` that keeps the data alive. This is sound as long
+/// as the following holds (both fields are private to keep it that way):
+///
+/// - `face` is dropped before `bytes` (fields are dropped in declaration order)
+/// - `bytes` is never replaced or mutated while `face` exists: the data lives in the heap
+///   allocation of the `Arc`, so moving the `ParsedFace` doesn't move it, and a clone
+///   keeps the same allocation alive with its own `Arc`
+/// - the face is only handed out with the lifetime of `&self` (see `face`), so the
+///   `'static` lifetime never leaves this struct
+#[derive(Clone)]
+pub(crate) struct ParsedFace {
+    // declared before `bytes`, so that it is dropped before the data it borrows
+    face: rustybuzz::Face<'static>,
+    bytes: Arc<[u8]>,
+}
+
+impl ParsedFace {
+
+    /// Parses the first face in the font data and sets the axis coordinates of variable
+    /// fonts, returns `None` if the data isn't a valid font
+    fn parse(bytes: Arc<[u8]>, variations: &[(ttf_parser::Tag, f32)]) -> Option<Self> {
+        // SAFETY: the slice points into the heap allocation of `bytes`, which is moved into
+        // the returned `ParsedFace` next to the face. The allocation is never mutated, is
+        // kept alive until after the face is dropped, and the face is only handed out with
+        // the lifetime of `&self` (see the invariants above), so the `'static` borrow never
+        // outlives the data.
+        let data: &'static [u8] = unsafe { &*Arc::as_ptr(&bytes) };
+        let mut face = rustybuzz::Face::from_slice(data, 0)?;
+        for &(axis, value) in variations {
+            face.set_variation(axis, value)?;
+        }
+        Some(Self { face, bytes })
+    }
+
+    /// Parses the same font data again with other axis coordinates
+    fn with_variations(&self, variations: &[(ttf_parser::Tag, f32)]) -> Option<Self> {
+        Self::parse(self.bytes.clone(), variations)
+    }
+
+    /// Returns the parsed face (which derefs to a `ttf_parser::Face`)
+    #[inline]
+    pub(crate) fn face(&self) -> &rustybuzz::Face<'_> {
+        &self.face
+    }
+
+    /// Returns the font data the face was parsed from
+    #[inline]
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Debug for ParsedFace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ParsedFace")
+            .field("bytes", &self.bytes.len())
+            .field("number_of_glyphs", &self.face.number_of_glyphs())
+            .finish()
+    }
 }
 
 /// The text rendering mode determines how a text is drawn
@@ -130,50 +740,322 @@ impl Into<i64> for TextRenderingMode {
 
 impl ExternalFont {
 
-    /// Creates a new font. The `index` is used for naming / identifying the font
-    pub fn new<R>(mut font_stream: R, font_index: usize)
+    /// Creates a new font. The `index` is used for naming / identifying the font.
+    /// Besides TrueType / OpenType fonts, the stream can contain a WOFF or WOFF2
+    /// web font, which is decompressed to the font file that gets embedded. Of a
+    /// font collection, only the first face is used (see `from_collection`).
+    pub fn new<R>(font_stream: R, font_index: usize)
+    -> Result<Self, Error> where R: ::std::io::Read
+    {
+        Self::from_collection(font_stream, &CollectionFace::Index(0), font_index)
+    }
+
+    /// Creates a new font from one face of a font collection (`.ttc` / `.otc`). Only the
+    /// tables of this face are copied into the font, so the rest of the collection isn't
+    /// embedded. A single font file is treated as a collection with one face.
+    pub fn from_collection<R>(mut font_stream: R, face: &CollectionFace, font_index: usize)
     -> Result<Self, Error> where R: ::std::io::Read
     {
-        // read font from stream and parse font metrics
         let mut buf = Vec::<u8>::new();
         font_stream.read_to_end(&mut buf)?;
-
-        let face_name = {
-            let collection = FontCollection::from_bytes(buf.clone())?;
-            collection.clone().into_font().unwrap_or(collection.font_at(0)?);
-            format!("F{}", font_index)
+        let buf = decode_web_font(buf)?;
+
+        let face_index = match *face {
+            CollectionFace::Index(face_index) => face_index,
+            CollectionFace::PostScriptName(ref name) => {
+                let face_count = ttf_parser::fonts_in_collection(&buf).unwrap_or(1);
+                (0..face_count).find(|&face_index| {
+                    ttf_parser::Face::parse(&buf, face_index).ok().and_then(|face| postscript_name(&face)).as_ref() == Some(name)
+                }).ok_or(PdfError::FaceNotInCollection)?
+            },
         };
 
+        Self::from_bytes(extract_collection_face(buf, face_index)?, font_index)
+    }
+
+    /// Parses the font. Fails with `PdfError::FontEmbeddingRestricted` if the license
+    /// of the font doesn't allow embedding it (see `EmbeddingPermission`).
+    fn from_bytes(buf: Vec<u8>, font_index: usize)
+    -> Result<Self, Error>
+    {
+        // TrueType (glyf) and CFF outlines are both supported
+        let face = ParsedFace::parse(buf.into(), &[]).ok_or(PdfError::FontFaceError)?;
+        let face_name = format!("F{}", font_index);
+        let color_glyphs = if has_color_glyphs(face.face()) { Some(vec![0]) } else { None };
+
+        let (embedding_permission, subsetting_allowed) = embedding_permission(face.face());
+        match embedding_permission {
+            EmbeddingPermission::Restricted => return Err(PdfError::FontEmbeddingRestricted.into()),
+            EmbeddingPermission::PreviewAndPrint => {
+                #[cfg(feature = "logging")] {
+                    warn!("Font {} may only be embedded for preview & print, the document must not be edited", face_name);
+                }
+            },
+            _ => { },
+        }
+
         Ok(Self {
-            font_bytes: buf,
-            face_name: face_name,
+            face,
+            face_name,
             vertical_writing: false,
+            variations: Vec::new(),
+            synthesis: FontSynthesis::default(),
+            embedding_permission,
+            subsetting_allowed,
+            color_glyphs,
+            glyph_ids: HashMap::new(),
+            advances: HashMap::new(),
         })
     }
 
-    /// Takes the font and adds it to the document and consumes the font
-    pub(crate) fn into_with_document(self, doc: &mut lopdf::Document)
+    /// Selects an instance of a variable font by its axis coordinates, for example
+    /// `[("wght", 700.0), ("wdth", 75.0)]`. Axes that aren't given keep their default value.
+    ///
+    /// Text is measured and shaped with the metrics of the instance, and a static
+    /// instance of the font is embedded. Only variable fonts with TrueType outlines
+    /// are supported: fonts with CFF2 outlines fail with `VariationsNotSupported`.
+    pub fn with_variations(mut self, variations: &[(&str, f32)])
+    -> Result<Self, Error>
+    {
+        if self.face().tables().cff2.is_some() {
+            return Err(PdfError::VariationsNotSupported.into());
+        }
+
+        let mut coordinates = Vec::with_capacity(variations.len());
+        for &(axis, value) in variations {
+            let tag = ttf_parser::Tag::from_bytes_lossy(axis.as_bytes());
+            if !self.face().variation_axes().into_iter().any(|a| a.tag == tag) {
+                return Err(PdfError::VariationAxisNotFound(tag.to_bytes()).into());
+            }
+            coordinates.push((tag, value));
+        }
+
+        self.face = self.face.with_variations(&coordinates).ok_or(PdfError::FontFaceError)?;
+        self.variations = coordinates;
+        self.glyph_ids.clear();
+        self.advances.clear();
+        Ok(self)
+    }
+
+    /// Returns the embedding permission of the font (fonts with `Restricted`
+    /// embedding can't be loaded at all)
+    #[inline]
+    pub fn embedding_permission(&self) -> EmbeddingPermission {
+        self.embedding_permission
+    }
+
+    /// Returns whether the license of the font allows embedding a subset of the font.
+    /// Fonts that allow it are always subsetted, other fonts are embedded as a whole.
+    #[inline]
+    pub fn is_subsetting_allowed(&self) -> bool {
+        self.subsetting_allowed
+    }
+
+    /// Writes the text of this font with a synthetic bold and / or oblique style
+    /// (see `FontSynthesis`). Color fonts can't be emboldened.
+    pub fn with_synthesis(mut self, synthesis: FontSynthesis)
+    -> Self
+    {
+        self.synthesis = synthesis;
+        self
+    }
+
+    /// Writes the text of this font from top to bottom (for CJK text): the font uses
+    /// the `Identity-V` encoding, the glyph heights are taken from the `vmtx` / `vhea`
+    /// tables of the font and the vertical glyph forms (`vrt2` or `vert` feature) are
+    /// used where available.
+    ///
+    /// The text cursor is the top center of the first glyph.
+    pub fn with_vertical_writing(mut self)
+    -> Self
+    {
+        self.vertical_writing = true;
+        // the cached advances are widths
+        self.advances.clear();
+        self
+    }
+
+    /// Returns the width of the synthetic bold stroke in ems, or `None` if the
+    /// font isn't emboldened
+    pub(crate) fn synthetic_bold_stroke(&self) -> Option<f64> {
+        if self.synthesis.bold && !self.is_color_font() {
+            Some(SYNTHETIC_BOLD_STROKE)
+        } else {
+            None
+        }
+    }
+
+    /// Returns how much the stroke of a synthetic bold font adds to the width of each
+    /// glyph, in thousandths of an em (0 for vertical fonts, whose widths aren't advances)
+    pub(crate) fn synthetic_bold_advance(&self) -> i64 {
+        match self.synthetic_bold_stroke() {
+            Some(stroke) if !self.vertical_writing => (stroke * 1000.0).round() as i64,
+            _ => 0,
+        }
+    }
+
+    /// Returns the parsed font
+    #[inline]
+    pub(crate) fn face(&self) -> &rustybuzz::Face<'_> {
+        self.face.face()
+    }
+
+    /// Returns the glyph ID of the character, or 0 (`.notdef`) if the font doesn't have it
+    pub(crate) fn glyph_id(&mut self, c: char) -> u16 {
+        let face = self.face.face();
+        *self.glyph_ids.entry(c).or_insert_with(|| face.glyph_index(c).map(|glyph| glyph.0).unwrap_or(0))
+    }
+
+    /// Maps each character of the text to its glyph ID in the font. Characters
+    /// that are not in the font are mapped to glyph 0 (`.notdef`)
+    pub(crate) fn glyph_ids_for_text(&mut self, text: &str) -> Vec<(u16, char)> {
+        text.chars().map(|c| (self.glyph_id(c), c)).collect()
+    }
+
+    /// Returns true if the font has color glyphs (`COLR` / `CPAL` layers or `sbix` / `CBDT`
+    /// bitmaps). Color fonts are embedded as Type3 fonts that draw the glyphs used, so
+    /// their glyphs are written as single-byte codes and at most 256 different glyphs
+    /// can be used per font. Color fonts can't be written vertically.
+    pub(crate) fn is_color_font(&self) -> bool {
+        self.color_glyphs.is_some() && !self.vertical_writing
+    }
+
+    /// Encodes the glyph IDs as character codes: two bytes (the glyph ID itself) per
+    /// glyph, or one byte for color fonts, where the codes are assigned in the order the
+    /// glyphs are first written. Returns the codes, the number of bytes per glyph and
+    /// whether all glyphs could be encoded (glyphs that don't fit into a color font
+    /// anymore are written as `.notdef`).
+    pub(crate) fn encode_glyph_ids(&mut self, glyph_ids: &[u16]) -> (Vec<u8>, usize, bool) {
+        let codes = match self.color_glyphs {
+            Some(ref mut codes) if !self.vertical_writing => codes,
+            _ => return (glyph_ids.iter().flat_map(|glyph_id| glyph_id.to_be_bytes()).collect(), 2, true),
+        };
+
+        let mut all_encoded = true;
+        let bytes = glyph_ids.iter().map(|&glyph_id| {
+            match codes.iter().position(|&code_glyph_id| code_glyph_id == glyph_id) {
+                Some(code) => code as u8,
+                None if codes.len() < 256 => {
+                    codes.push(glyph_id);
+                    (codes.len() - 1) as u8
+                },
+                None => {
+                    all_encoded = false;
+                    0
+                },
+            }
+        }).collect();
+
+        (bytes, 1, all_encoded)
+    }
+
+    /// Returns the advance of the glyph in font units: the width, or the height if the font is written vertically
+    pub(crate) fn glyph_advance(&mut self, glyph_id: u16) -> u16 {
+        let face = self.face.face();
+        let vertical_writing = self.vertical_writing;
+        *self.advances.entry(glyph_id).or_insert_with(|| {
+            if vertical_writing {
+                vertical_advance(face, glyph_id)
+            } else {
+                glyph_hor_advance(face, ttf_parser::GlyphId(glyph_id)).unwrap_or(0)
+            }
+        })
+    }
+
+    /// Takes the font and adds it to the document and consumes the font.
+    ///
+    /// Only the glyphs in `used_glyphs` (plus `.notdef`) are embedded: the font
+    /// program is subsetted, and the widths array and the ToUnicode map only
+    /// cover these glyphs. The glyph IDs themselves are not changed by subsetting.
+    /// Fonts whose license doesn't allow subsetting are embedded as a whole.
+    ///
+    /// Fonts with TrueType outlines are embedded as `CIDFontType2` (`FontFile2`),
+    /// fonts with CFF outlines as `CIDFontType0` with the whole OpenType font
+    /// in a `FontFile3` (which requires PDF 1.6).
+    pub(crate) fn into_with_document(self, doc: &mut lopdf::Document, used_glyphs: &BTreeMap<u16, Option<String>>)
     -> LoDictionary
     {
         use lopdf::Object;
         use lopdf::Object::*;
 
-        let face_name = self.face_name.clone();
+        if self.is_color_font() {
+            return self.into_type3_with_document(doc, used_glyphs);
+        }
 
-        let font_buf_ref: Box<[u8]> = self.font_bytes.into_boxed_slice();
-        let collection = FontCollection::from_bytes(font_buf_ref.clone()).unwrap();
-        let font = collection.clone().into_font().unwrap_or_else(|_| {
-            collection.font_at(0).unwrap()
-        });
+        let face = self.face();
+        let is_cff = face.tables().cff.is_some() || face.tables().cff2.is_some();
 
-        // Extract basic font information
-        let face_metrics = font.v_metrics_unscaled();
+        // Glyph 0 (.notdef) must always be present in the embedded font
+        let mut glyph_ids: Vec<u16> = used_glyphs.keys().cloned().collect();
+        if !used_glyphs.contains_key(&0) {
+            glyph_ids.insert(0, 0);
+        }
 
-        let font_stream = LoStream::new(
-            LoDictionary::from_iter(vec![
-                ("Length1", Integer(font_buf_ref.len() as i64)),
-                ]),
-            font_buf_ref.to_vec())
+        // Fonts have to be tagged with six uppercase letters if they are subsetted
+        let postscript_name = postscript_name(face).unwrap_or_else(|| self.face_name.clone());
+        let postscript_name = instance_postscript_name(postscript_name, &self.variations);
+        let face_name = if self.subsetting_allowed {
+            format!("{}+{}", subset_tag(&glyph_ids), postscript_name)
+        } else {
+            postscript_name
+        };
+
+        // variable fonts are embedded as a static instance, which is then subsetted
+        let instance = if self.variations.is_empty() {
+            None
+        } else {
+            instance_glyf_font(face, &self.variations, &glyph_ids).map_err(|_e| {
+                #[cfg(feature = "logging")] {
+                    warn!("Could not instance font {}: {}, embedding the default instance", self.face_name, _e);
+                }
+            }).ok()
+        };
+        let font_bytes = instance.as_deref().unwrap_or(self.face.bytes());
+
+        let font_buf_ref = if !self.subsetting_allowed {
+            font_bytes.to_vec()
+        } else {
+            match subsetter::subset(font_bytes, 0, subsetter::Profile::pdf(&glyph_ids)) {
+                Ok(subset) => subset,
+                Err(_e) => {
+                    #[cfg(feature = "logging")] {
+                        warn!("Could not subset font {}: {}, embedding the full font", self.face_name, _e);
+                    }
+                    font_bytes.to_vec()
+                },
+            }
+        };
+
+        // Extract basic font information, all metrics in the font descriptor
+        // are in thousandths of an em
+        let percentage_font_scaling = 1000.0 / f64::from(face.units_per_em());
+        let scaled = |value: i16| Integer((f64::from(value) * percentage_font_scaling).round() as i64);
+
+        let glyph_height = |c: char| face.glyph_index(c).and_then(|glyph| face.glyph_bounding_box(glyph)).map(|bbox| bbox.y_max);
+        let cap_height = face.capital_height().filter(|h| *h > 0).or_else(|| glyph_height('H')).unwrap_or_else(|| face.ascender());
+        let x_height = face.x_height().filter(|h| *h > 0).or_else(|| glyph_height('x')).unwrap_or(cap_height / 2);
+        let bbox = face.global_bounding_box();
+
+        // there is no stem width in TrueType / OpenType fonts, so it is estimated from the weight
+        let weight = self.variations.iter()
+            .find(|&&(axis, _)| axis == ttf_parser::Tag::from_bytes(b"wght"))
+            .map(|&(_, weight)| f64::from(weight))
+            .unwrap_or_else(|| f64::from(face.weight().to_number()));
+        let stem_v = 10.0 + 0.244 * (weight - 50.0);
+
+        let font_stream = if is_cff {
+            // the subsetted font is still an OpenType font, not a bare CFF table
+            if doc.version.as_str() < "1.6" {
+                doc.version = "1.6".into();
+            }
+            LoStream::new(LoDictionary::from_iter(vec![("Subtype", Name("OpenType".into()))]), font_buf_ref)
+        } else {
+            LoStream::new(
+                LoDictionary::from_iter(vec![
+                    ("Length1", Integer(font_buf_ref.len() as i64)),
+                    ]),
+                font_buf_ref)
+        }
         .with_compression(false); /* important! font stream must not be compressed! */
 
         // Begin setting required font attributes
@@ -182,62 +1064,53 @@ impl ExternalFont {
             ("Subtype".into(), Name("Type0".into())),
             ("BaseFont".into(), Name(face_name.clone().into_bytes())),
             // Identity-H for horizontal writing, Identity-V for vertical writing
-            ("Encoding".into(), Name("Identity-H".into())),
+            ("Encoding".into(), Name(if self.vertical_writing { "Identity-V" } else { "Identity-H" }.into())),
             // Missing DescendantFonts and ToUnicode
         ];
 
         let mut font_descriptor_vec: Vec<(::std::string::String, Object)> = vec![
             ("Type".into(), Name("FontDescriptor".into())),
             ("FontName".into(), Name(face_name.clone().into_bytes())),
-            ("Ascent".into(), Integer(face_metrics.ascent as i64)),
-            ("Descent".into(), Integer(face_metrics.descent as i64)),
-            ("CapHeight".into(), Integer(face_metrics.ascent as i64)),
-            ("ItalicAngle".into(), Integer(0)),
-            ("Flags".into(), Integer(32)),
-            ("StemV".into(), Integer(80)),
+            ("Flags".into(), Integer(font_descriptor_flags(face))),
+            ("FontBBox".into(), Array(vec![scaled(bbox.x_min), scaled(bbox.y_min), scaled(bbox.x_max), scaled(bbox.y_max)])),
+            ("ItalicAngle".into(), Real(f64::from(face.italic_angle()))),
+            ("Ascent".into(), scaled(face.ascender())),
+            ("Descent".into(), scaled(face.descender())),
+            ("CapHeight".into(), scaled(cap_height)),
+            ("XHeight".into(), scaled(x_height)),
+            ("StemV".into(), Integer(stem_v.round() as i64)),
         ];
 
         // End setting required font arguments
 
-        // Maximum height of a single character in the font
-        let mut max_height = 0;
-        // Total width of all characters
-        let mut total_width = 0;
         // Widths (or heights, depends on self.vertical_writing)
         // of the individual characters, indexed by glyph id
         let mut widths = Vec::<(u32, u32)>::new();
 
-        // Glyph IDs - (Unicode IDs - character width, character height)
-        let mut cmap = BTreeMap::<u32, (u32, u32, u32)>::new();
-        cmap.insert(0, (0, 1000, 1000));
+        // Glyphs that were written without knowing which text they represent
+        // (see `write_codepoints`) have to be looked up in the fonts cmap
+        let reverse_cmap = lookup_unicode_for_glyphs(face, used_glyphs);
 
-        for unicode in 0x0000..0xffff {
+        // Glyph IDs - (Unicode text - character width)
+        let mut cmap = BTreeMap::<u32, (::std::string::String, u32)>::new();
+        cmap.insert(0, (::std::string::String::new(), 1000));
 
-            let glyph = font.glyph(Cp(unicode));
+        for (&glyph_id, text) in used_glyphs {
 
-            if glyph.id().0 == 0 {
+            if glyph_id == 0 {
                 continue;
             }
 
-            let glyph_id = glyph.id().0;
-            let glyph = font.glyph(Gid(glyph_id));
-
-            if let Some(glyph_metrics) = glyph.standalone().get_data() {
+            let glyph = ttf_parser::GlyphId(glyph_id);
 
-                let w = glyph_metrics.unit_h_metrics.advance_width;
+            if let Some(w) = glyph_hor_advance(face, glyph) {
 
-                // Note: extents can be None, but then the character may still have a
-                // horizontal advance!
-                let h = glyph_metrics.extents.and_then(|extents| {
-                    Some(extents.max.y - extents.min.y - face_metrics.descent as i32)
-                }).unwrap_or(1000);
-
-                if h > max_height {
-                    max_height = h;
+                let text = match *text {
+                    Some(ref text) => text.clone(),
+                    None => reverse_cmap.get(&glyph_id).map(|c| c.to_string()).unwrap_or_default(),
                 };
 
-                total_width += w as u32;
-                cmap.insert(glyph_id, (unicode as u32, w as u32, h as u32));
+                cmap.insert(glyph_id as u32, (text, u32::from(w)));
             }
         }
 
@@ -267,15 +1140,17 @@ impl ExternalFont {
                     cur_first_bit = (*glyph_id >> 8) as u16;
                 }
 
-                let (unicode, width, _) = *unicode_width_tuple;
-                current_cmap_block.push((*glyph_id, unicode));
+                let (ref text, width) = *unicode_width_tuple;
+                if !text.is_empty() {
+                    current_cmap_block.push((*glyph_id, text.clone()));
+                }
                 widths.push((*glyph_id, width));
             };
 
             all_cmap_blocks.push(current_cmap_block);
         }
 
-        let cid_to_unicode_map = generate_cid_to_unicode_map(face_name.clone(), all_cmap_blocks);
+        let cid_to_unicode_map = generate_cid_to_unicode_map(face_name.clone(), all_cmap_blocks, 2);
 
         let cid_to_unicode_map_stream = LoStream::new(LoDictionary::new(), cid_to_unicode_map.as_bytes().to_vec());
         let cid_to_unicode_map_stream_id = doc.add_object(cid_to_unicode_map_stream);
@@ -291,54 +1166,51 @@ impl ExternalFont {
         let mut current_high_gid = 0;
         let mut current_width_vec = Vec::<Object>::new();
 
-        // scale the font width so that it sort-of fits into an 1000 unit square
-        let percentage_font_scaling = 1000.0 / (font.units_per_em() as f64);
+        // scale the font width so that it sort-of fits into an 1000 unit square,
+        // synthetic bold glyphs are wider by the width of their stroke
+        let bold_advance = self.synthetic_bold_advance();
 
         for (gid, width) in widths {
             if gid == current_high_gid {
-                current_width_vec.push(Integer((width as f64 * percentage_font_scaling) as i64));
+                current_width_vec.push(Integer((width as f64 * percentage_font_scaling) as i64 + bold_advance));
                 current_high_gid += 1;
             } else {
                 widths_list.push(Integer(current_low_gid as i64));
-                widths_list.push(Array(current_width_vec.drain(..).collect()));
+                widths_list.push(Array(::std::mem::take(&mut current_width_vec)));
 
-                current_width_vec.push(Integer((width as f64 * percentage_font_scaling) as i64));
+                current_width_vec.push(Integer((width as f64 * percentage_font_scaling) as i64 + bold_advance));
                 current_low_gid = gid;
                 current_high_gid = gid + 1;
             }
         }
         // push the last widths, because the loop is delayed by one iteration
         widths_list.push(Integer(current_low_gid as i64));
-        widths_list.push(Array(current_width_vec.drain(..).collect()));
-
-        let w = {
-            if self.vertical_writing { ("W2",  Array(widths_list)) }
-            else { ("W",  Array(widths_list)) }
-        };
-
-        // default width for characters
-        let dw = {
-            if self.vertical_writing { ("DW2", Integer(1000)) }
-            else { ("DW", Integer(1000)) }
-        };
+        widths_list.push(Array(::std::mem::take(&mut current_width_vec)));
 
         let mut desc_fonts = LoDictionary::from_iter(vec![
             ("Type", Name("Font".into())),
-            ("Subtype", Name("CIDFontType2".into())),
+            ("Subtype", Name(if is_cff { "CIDFontType0" } else { "CIDFontType2" }.into())),
             ("BaseFont", Name(face_name.clone().into())),
             ("CIDSystemInfo", Dictionary(LoDictionary::from_iter(vec![
                     ("Registry", String("Adobe".into(), StringFormat::Literal)),
                     ("Ordering", String("Identity".into(), StringFormat::Literal)),
                     ("Supplement", Integer(0)),
             ]))),
-            w, dw,
+            ("W", Array(widths_list)),
+            // default width for characters
+            ("DW", Integer(1000)),
         ]);
 
-        let font_bbox = vec![ Integer(0), Integer(max_height as i64), Integer(total_width as i64), Integer(max_height as i64) ];
-        font_descriptor_vec.push(("FontFile2".into(), Reference(doc.add_object(font_stream))));
+        // The horizontal widths are still needed in vertical writing, because
+        // the default position vector is derived from them
+        if self.vertical_writing {
+            let (heights_list, default_heights) = vertical_metrics(face, &glyph_ids);
+            desc_fonts.set("W2", Array(heights_list));
+            desc_fonts.set("DW2", Array(default_heights));
+        }
 
-        // although the following entry is technically not needed, Adobe Reader needs it
-        font_descriptor_vec.push(("FontBBox".into(), Array(font_bbox)));
+        let font_file = if is_cff { "FontFile3" } else { "FontFile2" };
+        font_descriptor_vec.push((font_file.into(), Reference(doc.add_object(font_stream))));
 
         let font_descriptor_vec_id = doc.add_object(LoDictionary::from_iter(font_descriptor_vec));
 
@@ -349,21 +1221,147 @@ impl ExternalFont {
 
         LoDictionary::from_iter(font_vec)
     }
+
+    /// Adds a color font to the document as a Type3 font. Each code that was assigned
+    /// by `encode_glyph_ids` gets a glyph procedure that draws the color glyph (see
+    /// `glyph_procedure`), the font program itself is not embedded.
+    fn into_type3_with_document(self, doc: &mut lopdf::Document, used_glyphs: &BTreeMap<u16, Option<String>>)
+    -> LoDictionary
+    {
+        use lopdf::Object::*;
+
+        let face = self.face();
+        let glyph_ids = self.color_glyphs.as_deref().unwrap_or(&[0]);
+
+        let mut resources = GlyphResources::default();
+        let mut char_procs = LoDictionary::new();
+        let mut differences = vec![Integer(0)];
+        let mut widths = Vec::with_capacity(glyph_ids.len());
+
+        for &glyph_id in glyph_ids {
+            let name = format!("g{}", glyph_id);
+            let procedure = glyph_procedure(face, glyph_id, &mut resources, doc);
+            char_procs.set(name.clone(), Reference(doc.add_object(LoStream::new(LoDictionary::new(), procedure))));
+            differences.push(Name(name.into_bytes()));
+            widths.push(Integer(i64::from(face.glyph_hor_advance(ttf_parser::GlyphId(glyph_id)).unwrap_or(0))));
+        }
+
+        // the ToUnicode map uses the single-byte codes instead of the glyph IDs
+        let reverse_cmap = lookup_unicode_for_glyphs(face, used_glyphs);
+        let code_texts: CmapBlock = glyph_ids.iter().enumerate().skip(1).filter_map(|(code, glyph_id)| {
+            let text = match used_glyphs.get(glyph_id) {
+                Some(Some(text)) => text.clone(),
+                _ => reverse_cmap.get(glyph_id)?.to_string(),
+            };
+            if text.is_empty() { None } else { Some((code as u32, text)) }
+        }).collect();
+        let postscript_name = postscript_name(face).unwrap_or_else(|| self.face_name.clone());
+        let to_unicode = generate_cid_to_unicode_map(postscript_name, code_texts.chunks(100).map(|block| block.to_vec()).collect(), 1);
+        let to_unicode_id = doc.add_object(LoStream::new(LoDictionary::new(), to_unicode.into_bytes()));
+
+        // the bitmaps may extend beyond the outlines, an empty box doesn't make any assumptions
+        let tables = face.tables();
+        let bbox = if tables.sbix.is_some() || tables.cbdt.is_some() {
+            vec![Integer(0); 4]
+        } else {
+            let bbox = face.global_bounding_box();
+            [bbox.x_min, bbox.y_min, bbox.x_max, bbox.y_max].iter().map(|&value| Integer(i64::from(value))).collect()
+        };
+
+        // the glyph procedures are in font units
+        let scale = 1.0 / f64::from(face.units_per_em());
+
+        LoDictionary::from_iter(vec![
+            ("Type", Name("Font".into())),
+            ("Subtype", Name("Type3".into())),
+            ("FontBBox", Array(bbox)),
+            ("FontMatrix", Array(vec![Real(scale), Integer(0), Integer(0), Real(scale), Integer(0), Integer(0)])),
+            ("CharProcs", Dictionary(char_procs)),
+            ("Encoding", Dictionary(LoDictionary::from_iter(vec![
+                ("Type", Name("Encoding".into())),
+                ("Differences", Array(differences)),
+            ]))),
+            ("FirstChar", Integer(0)),
+            ("LastChar", Integer(glyph_ids.len() as i64 - 1)),
+            ("Widths", Array(widths)),
+            ("Resources", Dictionary(resources.into_dictionary())),
+            ("ToUnicode", Reference(to_unicode_id)),
+        ])
+    }
+}
+
+/// Builds the `W2` array and the `DW2` entry of a vertically written font from the
+/// `vmtx`, `vhea` and `VORG` tables, see page 442 in the PDF 1.7 reference.
+///
+/// Each glyph gets a vertical displacement (negative, since vertical text runs downwards)
+/// and a position vector from the horizontal origin of the glyph to its vertical origin.
+/// Fonts without vertical metrics advance by the line height and use the ascender as origin.
+fn vertical_metrics(face: &ttf_parser::Face, glyph_ids: &[u16]) -> (Vec<lopdf::Object>, Vec<lopdf::Object>) {
+    use lopdf::Object::*;
+
+    let scale = 1000.0 / f64::from(face.units_per_em());
+    let scaled = |value: f64| Integer((value * scale) as i64);
+
+    let default_advance = f64::from(i32::from(face.ascender()) - i32::from(face.descender()));
+    let default_origin = f64::from(face.vertical_ascender().unwrap_or_else(|| face.ascender()));
+
+    let glyph_metrics = |glyph_id: u16| {
+        let glyph = ttf_parser::GlyphId(glyph_id);
+        let advance = f64::from(vertical_advance(face, glyph_id));
+        let origin_x = face.glyph_hor_advance(glyph).map(f64::from).unwrap_or(0.0) / 2.0;
+        // VORG is only present in CFF fonts, TrueType fonts have to use the top side bearing
+        let origin_y = face.glyph_y_origin(glyph).map(f64::from)
+            .or_else(|| {
+                let bearing = face.glyph_ver_side_bearing(glyph)?;
+                let bbox = face.glyph_bounding_box(glyph)?;
+                Some(f64::from(bearing) + f64::from(bbox.y_max))
+            })
+            .unwrap_or(default_origin);
+        vec![scaled(-advance), scaled(origin_x), scaled(origin_y)]
+    };
+
+    // same structure as the widths: c [w1y v1x v1y w1y v1x v1y ...] for consecutive glyph ids
+    let mut heights_list = Vec::new();
+    let mut current_low_gid = 0;
+    let mut current_high_gid = 0;
+    let mut current_metrics = Vec::new();
+
+    for &glyph_id in glyph_ids {
+        if glyph_id != current_high_gid && !current_metrics.is_empty() {
+            heights_list.push(Integer(i64::from(current_low_gid)));
+            heights_list.push(Array(::std::mem::take(&mut current_metrics)));
+        }
+        if current_metrics.is_empty() {
+            current_low_gid = glyph_id;
+        }
+        current_metrics.extend(glyph_metrics(glyph_id));
+        current_high_gid = glyph_id.wrapping_add(1);
+    }
+
+    if !current_metrics.is_empty() {
+        heights_list.push(Integer(i64::from(current_low_gid)));
+        heights_list.push(Array(current_metrics));
+    }
+
+    (heights_list, vec![scaled(default_origin), scaled(-default_advance)])
 }
 
 type GlyphId = u32;
-type UnicodeCodePoint = u32;
-type CmapBlock = Vec<(GlyphId, UnicodeCodePoint)>;
+type CmapBlock = Vec<(GlyphId, String)>;
 
-/// Generates a CMAP (character map) from valid cmap blocks
-fn generate_cid_to_unicode_map(face_name: String, all_cmap_blocks: Vec<CmapBlock>) -> String {
+/// Generates a CMAP (character map) from valid cmap blocks, for codes with `code_bytes`
+/// bytes (two for CIDs, one for the codes of Type3 fonts)
+fn generate_cid_to_unicode_map(face_name: String, all_cmap_blocks: Vec<CmapBlock>, code_bytes: usize) -> String {
 
     let mut cid_to_unicode_map = format!(include_str!("../../../../templates/gid_to_unicode_beg.txt"), face_name);
+    cid_to_unicode_map.push_str(&format!("1 begincodespacerange\n<{}> <{}>\nendcodespacerange\n", "00".repeat(code_bytes), "FF".repeat(code_bytes)));
 
-    for cmap_block in all_cmap_blocks.into_iter().filter(|block| !block.is_empty() || block.len() < 100) {
+    for cmap_block in all_cmap_blocks.into_iter().filter(|block| !block.is_empty()) {
         cid_to_unicode_map.push_str(format!("{} beginbfchar\r\n", cmap_block.len()).as_str());
-        for (glyph_id, unicode) in cmap_block {
-            cid_to_unicode_map.push_str(format!("<{:04x}> <{:04x}>\n", glyph_id, unicode).as_str());
+        for (glyph_id, text) in cmap_block {
+            // characters outside of the BMP are written as UTF-16 surrogate pairs
+            let unicode: String = text.encode_utf16().map(|unit| format!("{:04x}", unit)).collect();
+            cid_to_unicode_map.push_str(format!("<{:0width$x}> <{}>\n", glyph_id, unicode, width = 2 * code_bytes).as_str());
         }
         cid_to_unicode_map.push_str("endbfchar\r\n");
     }
@@ -372,6 +1370,180 @@ fn generate_cid_to_unicode_map(face_name: String, all_cmap_blocks: Vec<CmapBlock
     cid_to_unicode_map
 }
 
+/// Appends the axis coordinates to the PostScript name of a variable font instance, for
+/// example "RobotoFlex_700wght_75wdth" (see Adobe Technical Note #5902). Names that
+/// get longer than 63 characters use a hash of the coordinates instead.
+fn instance_postscript_name(postscript_name: String, variations: &[(ttf_parser::Tag, f32)]) -> String {
+    use std::hash::{Hash, Hasher};
+
+    let mut coordinates = String::new();
+    for &(axis, value) in variations {
+        let axis = String::from_utf8_lossy(&axis.to_bytes()).trim_end().to_string();
+        coordinates.push_str(&format!("_{}{}", value, axis));
+    }
+
+    if postscript_name.len() + coordinates.len() <= 63 {
+        postscript_name + &coordinates
+    } else {
+        let mut hasher = ::std::collections::hash_map::DefaultHasher::new();
+        coordinates.hash(&mut hasher);
+        format!("{}-{:016X}", postscript_name, hasher.finish())
+    }
+}
+
+/// Returns the PostScript name of the font from the `name` table, without any
+/// characters that are not allowed in a PostScript name
+fn postscript_name(face: &ttf_parser::Face) -> Option<String> {
+    use ttf_parser::PlatformId;
+    use ttf_parser::name_id::POST_SCRIPT_NAME;
+
+    let name = face.names().into_iter()
+        .filter(|name| name.name_id == POST_SCRIPT_NAME)
+        .filter_map(|name| match name.platform_id {
+            // Macintosh names are stored as (nearly) ASCII
+            PlatformId::Macintosh => Some(name.name.iter().map(|&b| b as char).collect()),
+            _ => name.to_string(),
+        })
+        .next()?;
+
+    let name: String = name.chars()
+        .filter(|c| ('!'..='~').contains(c) && !"[](){}<>/%".contains(*c))
+        .collect();
+
+    if name.is_empty() { None } else { Some(name) }
+}
+
+/// Reads the embedding permission and whether subsetting is allowed from the `fsType`
+/// field of the `OS/2` table. Fonts without an `OS/2` table can be embedded freely.
+fn embedding_permission(face: &ttf_parser::Face) -> (EmbeddingPermission, bool) {
+    use ttf_parser::Tag;
+
+    const RESTRICTED: u16 = 0x0002;
+    const PREVIEW_AND_PRINT: u16 = 0x0004;
+    const EDITABLE: u16 = 0x0008;
+    const NO_SUBSETTING: u16 = 0x0100;
+    const BITMAP_ONLY: u16 = 0x0200;
+
+    let fs_type = match face.raw_face().table(Tag::from_bytes(b"OS/2")).and_then(|os2| os2.get(8..10)) {
+        Some(fs_type) => u16::from_be_bytes([fs_type[0], fs_type[1]]),
+        None => return (EmbeddingPermission::Installable, true),
+    };
+
+    // only one of the usage bits should be set, but older fonts may set several
+    // of them, in which case the least restrictive one applies
+    let permission = if fs_type & BITMAP_ONLY != 0 {
+        EmbeddingPermission::Restricted
+    } else if fs_type & 0x000F == 0 {
+        EmbeddingPermission::Installable
+    } else if fs_type & EDITABLE != 0 {
+        EmbeddingPermission::Editable
+    } else if fs_type & PREVIEW_AND_PRINT != 0 {
+        EmbeddingPermission::PreviewAndPrint
+    } else if fs_type & RESTRICTED != 0 {
+        EmbeddingPermission::Restricted
+    } else {
+        // bit 0 is reserved, a font that only sets it isn't restricted
+        EmbeddingPermission::Installable
+    };
+
+    (permission, fs_type & NO_SUBSETTING == 0)
+}
+
+/// Builds the `Flags` entry of the font descriptor (see page 459 in the PDF 1.7 reference)
+/// from the `post`, `OS/2` and `cmap` tables
+fn font_descriptor_flags(face: &ttf_parser::Face) -> i64 {
+    use ttf_parser::{PlatformId, Tag};
+
+    const FIXED_PITCH: i64 = 1 << 0;
+    const SERIF: i64 = 1 << 1;
+    const SYMBOLIC: i64 = 1 << 2;
+    const SCRIPT: i64 = 1 << 3;
+    const NONSYMBOLIC: i64 = 1 << 5;
+    const ITALIC: i64 = 1 << 6;
+
+    let mut flags = 0;
+
+    if face.is_monospaced() {
+        flags |= FIXED_PITCH;
+    }
+
+    // the high byte of sFamilyClass in the OS/2 table is the IBM font class
+    let family_class = face.raw_face().table(Tag::from_bytes(b"OS/2")).and_then(|os2| os2.get(30)).cloned();
+    match family_class {
+        Some(1..=5) | Some(7) => flags |= SERIF,
+        Some(10) => flags |= SCRIPT,
+        _ => { },
+    }
+
+    // fonts with a symbol cmap (3, 0) use their own character set
+    let is_symbolic = face.tables().cmap.map(|cmap| {
+        cmap.subtables.into_iter().any(|subtable| subtable.platform_id == PlatformId::Windows && subtable.encoding_id == 0)
+    }).unwrap_or(true);
+    flags |= if is_symbolic { SYMBOLIC } else { NONSYMBOLIC };
+
+    if face.is_italic() || face.italic_angle() != 0.0 {
+        flags |= ITALIC;
+    }
+
+    flags
+}
+
+/// Generates the six uppercase letters that a subsetted font name has to be
+/// prefixed with. The tag is derived from the glyph set, so the same subset
+/// always gets the same tag.
+fn subset_tag(glyph_ids: &[u16]) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    glyph_ids.hash(&mut hasher);
+    let mut hash = hasher.finish();
+
+    let mut tag = String::with_capacity(6);
+    for _ in 0..6 {
+        tag.push((b'A' + (hash % 26) as u8) as char);
+        hash /= 26;
+    }
+    tag
+}
+
+/// Searches the cmap of the font for the characters of all glyphs that were
+/// written without any text attached (for example via `write_codepoints`).
+/// If several characters map to the same glyph, the lowest one is used.
+fn lookup_unicode_for_glyphs(face: &ttf_parser::Face, used_glyphs: &BTreeMap<u16, Option<String>>)
+-> HashMap<u16, char>
+{
+    let mut found = HashMap::<u16, char>::new();
+
+    if !used_glyphs.iter().any(|(gid, text)| *gid != 0 && text.is_none()) {
+        return found;
+    }
+
+    let cmap = match face.tables().cmap {
+        Some(cmap) => cmap,
+        None => return found,
+    };
+
+    // the subtables cover the full Unicode range (format 12 for characters outside of the BMP)
+    for subtable in cmap.subtables.into_iter().filter(|subtable| subtable.is_unicode()) {
+        subtable.codepoints(|codepoint| {
+            let glyph_id = match subtable.glyph_index(codepoint) {
+                Some(glyph_id) if glyph_id.0 != 0 => glyph_id.0,
+                _ => return,
+            };
+            let needs_lookup = used_glyphs.get(&glyph_id).map(|text| text.is_none()).unwrap_or(false);
+            if let (true, Some(c)) = (needs_lookup, ::std::char::from_u32(codepoint)) {
+                let entry = found.entry(glyph_id).or_insert(c);
+                if c < *entry {
+                    *entry = c;
+                }
+            }
+        });
+    }
+
+    found
+}
+
 impl PartialEq for ExternalFont {
     /// Two fonts are equal if their names are equal, the contents aren't checked
     fn eq(&self, other: &ExternalFont) -> bool {
@@ -395,6 +1567,11 @@ pub struct DirectFontRef {
     pub(crate) inner_obj: lopdf::ObjectId,
     /// Actual font data
     pub(crate) data: Font,
+    /// Glyph IDs that were written with this font, mapped to the text they
+    /// represent. The text is `None` if the glyph was written without any text
+    /// (see `write_codepoints`) and empty if the glyph doesn't represent any
+    /// text on its own (for example the second glyph of a decomposed character)
+    pub(crate) used_glyphs: BTreeMap<u16, Option<String>>,
 }
 
 impl IndirectFontRef {
@@ -408,10 +1585,61 @@ impl IndirectFontRef {
     }
 }
 
+impl AsRef<[IndirectFontRef]> for IndirectFontRef {
+    fn as_ref(&self) -> &[IndirectFontRef] {
+        ::std::slice::from_ref(self)
+    }
+}
+
+/// A font with fallback fonts for characters that are missing in it (for example
+/// emoji or CJK characters in a Latin font). A `FontFamily` can be used instead of
+/// an `IndirectFontRef` to write or measure text: the text is split into runs, each
+/// run is written with the first font that has its characters and the font (`Tf`)
+/// is switched automatically.
+#[derive(Debug, Hash, Eq, Clone, PartialEq)]
+pub struct FontFamily {
+    /// The primary font, followed by the fallback fonts in order of preference
+    fonts: Vec<IndirectFontRef>,
+}
+
+impl FontFamily {
+    /// Creates a new family with the primary font
+    pub fn new(font: &IndirectFontRef)
+    -> Self
+    {
+        Self {
+            fonts: vec![font.clone()],
+        }
+    }
+
+    /// Adds a fallback font, which is used for characters that none of the
+    /// previous fonts have
+    pub fn with_fallback(mut self, font: &IndirectFontRef)
+    -> Self
+    {
+        self.fonts.push(font.clone());
+        self
+    }
+
+    /// Returns the fonts of the family, starting with the primary font
+    pub fn fonts(&self) -> &[IndirectFontRef] {
+        &self.fonts
+    }
+}
+
+impl AsRef<[IndirectFontRef]> for FontFamily {
+    fn as_ref(&self) -> &[IndirectFontRef] {
+        &self.fonts
+    }
+}
+
 /// Font list for tracking fonts within a single PDF document
 #[derive(Default, Debug, Clone)]
 pub struct FontList {
     fonts: HashMap<IndirectFontRef, DirectFontRef>,
+    /// Problems that occurred while writing text (for example characters that
+    /// couldn't be encoded in a builtin font), reported by `check_for_errors`
+    errors: Vec<PdfError>,
 }
 
 impl FontList {
@@ -437,12 +1665,137 @@ impl FontList {
     pub fn get_font(&self, font: &IndirectFontRef)
     -> Option<DirectFontRef>
     {
-        let font_ref = self.fonts.get(font);
-        if let Some(r) = font_ref {
-            Some(r.clone())
-        } else {
-            None
+        self.fonts.get(font).cloned()
+    }
+
+    /// Returns the font data of the font, without cloning it
+    #[inline]
+    pub(crate) fn font_data(&self, font: &IndirectFontRef)
+    -> Option<&Font>
+    {
+        self.fonts.get(font).map(|direct_ref| &direct_ref.data)
+    }
+
+    /// Returns the font data of the font, so that its caches can be filled
+    #[inline]
+    pub(crate) fn font_data_mut(&mut self, font: &IndirectFontRef)
+    -> Option<&mut Font>
+    {
+        self.fonts.get_mut(font).map(|direct_ref| &mut direct_ref.data)
+    }
+
+    /// Records that the glyphs were written with the given font, so that they
+    /// are included when the font is subsetted. The first text that is recorded
+    /// for a glyph is used for the ToUnicode map.
+    pub(crate) fn record_used_glyphs<I>(&mut self, font: &IndirectFontRef, glyphs: I)
+    where I: IntoIterator<Item = (u16, Option<String>)>
+    {
+        if let Some(direct_ref) = self.fonts.get_mut(font) {
+            for (glyph_id, text) in glyphs {
+                let entry = direct_ref.used_glyphs.entry(glyph_id).or_insert(None);
+                if entry.is_none() {
+                    *entry = text;
+                }
+            }
+        }
+    }
+
+    /// Encodes the glyph IDs as character codes of the font (see `ExternalFont::encode_glyph_ids`)
+    /// and records an error if a color font ran out of codes. Returns the codes and the
+    /// number of bytes per glyph.
+    pub(crate) fn encode_glyph_ids(&mut self, font: &IndirectFontRef, glyph_ids: &[u16])
+    -> (Vec<u8>, usize)
+    {
+        let (bytes, bytes_per_glyph, all_encoded) = match self.font_data_mut(font) {
+            Some(&mut Font::ExternalFont(ref mut external_font)) => external_font.encode_glyph_ids(glyph_ids),
+            _ => (glyph_ids.iter().flat_map(|glyph_id| glyph_id.to_be_bytes()).collect(), 2, true),
+        };
+        if !all_encoded {
+            self.record_error(PdfError::TooManyColorGlyphs);
+        }
+        (bytes, bytes_per_glyph)
+    }
+
+    /// Records characters that couldn't be encoded in a builtin font or a Type1 font
+    /// and were replaced, so that they can be reported by `check_for_errors`
+    pub(crate) fn record_unencodable_chars(&mut self, chars: Vec<char>) {
+        #[cfg(feature = "logging")] {
+            for c in &chars {
+                warn!("Character {:?} can't be encoded in the font, it was replaced", c);
+            }
         }
+        self.errors.extend(chars.into_iter().map(PdfError::UnencodableCharacter));
+    }
+
+    /// Records an error that occurred while writing text, so that it can be
+    /// reported by `check_for_errors`
+    pub(crate) fn record_error(&mut self, error: PdfError) {
+        #[cfg(feature = "logging")] {
+            warn!("{}", error);
+        }
+        self.errors.push(error);
+    }
+
+    /// Returns the errors that occurred while writing text
+    pub(crate) fn errors(&self) -> &[PdfError] {
+        &self.errors
+    }
+
+    /// Returns the text that a PDF reader will extract for the glyphs, according
+    /// to the ToUnicode map of the font. Returns `None` if the text of a glyph
+    /// is not known yet (because it was written via `write_codepoints`).
+    pub(crate) fn extracted_text(&self, font: &IndirectFontRef, glyph_ids: &[u16])
+    -> Option<String>
+    {
+        let direct_ref = self.fonts.get(font)?;
+        let mut text = String::new();
+        for glyph_id in glyph_ids {
+            text.push_str(direct_ref.used_glyphs.get(glyph_id)?.as_ref()?);
+        }
+        Some(text)
+    }
+
+    /// Splits the text into runs that are written with the same font: each character
+    /// gets the first of the `fonts` that has a glyph for it. Whitespace and characters
+    /// that none of the fonts have stay in the current run, so that they don't cause
+    /// needless font switches. Returns the byte ranges of the runs in `text`.
+    pub(crate) fn font_runs(&self, text: &str, fonts: &[IndirectFontRef])
+    -> Vec<(Range<usize>, IndirectFontRef)>
+    {
+        let coverage: Vec<Box<dyn Fn(char) -> bool + '_>> = fonts.iter().map(|font| -> Box<dyn Fn(char) -> bool + '_> {
+            match self.font_data(font) {
+                Some(Font::ExternalFont(external_font)) => {
+                    let face = external_font.face();
+                    Box::new(move |c| face.glyph_index(c).is_some())
+                },
+                Some(Font::BuiltinFont(builtin_font)) => Box::new(move |c| builtin_font.encode_char(c).is_some()),
+                Some(Font::Type1Font(type1_font)) => Box::new(move |c| type1_font.has_char(c)),
+                None => Box::new(|_| false),
+            }
+        }).collect();
+
+        let mut runs: Vec<(Range<usize>, usize)> = Vec::new();
+
+        for (position, c) in text.char_indices() {
+            let current = runs.last().map(|run| run.1);
+            let font_index = match current {
+                Some(current) if c.is_whitespace() && coverage[current](c) => Some(current),
+                _ => coverage.iter().position(|has_glyph| has_glyph(c)),
+            };
+            let font_index = font_index.or(current).unwrap_or(0);
+
+            let end = position + c.len_utf8();
+            match runs.last_mut() {
+                Some(run) if run.1 == font_index => run.0.end = end,
+                _ => runs.push((position..end, font_index)),
+            }
+        }
+
+        // text of fonts that were not added to the document is not written
+        runs.into_iter()
+            .filter(|&(_, font_index)| font_index < fonts.len() && self.font_data(&fonts[font_index]).is_some())
+            .map(|(range, font_index)| (range, fonts[font_index].clone()))
+            .collect()
     }
 
     /// Returns the number of fonts currenly in use
@@ -469,8 +1822,9 @@ impl FontList {
 
         for (indirect_ref, direct_font_ref) in self.fonts {
             let font_dict_collected = match direct_font_ref.data {
-                Font::ExternalFont(font) => font.into_with_document(doc),
+                Font::ExternalFont(font) => (*font).into_with_document(doc, &direct_font_ref.used_glyphs),
                 Font::BuiltinFont(font)  => font.into(),
+                Font::Type1Font(font)    => (*font).into_with_document(doc),
             };
 
             doc.objects.insert(direct_font_ref.inner_obj, lopdf::Object::Dictionary(font_dict_collected));
@@ -480,3 +1834,275 @@ impl FontList {
         font_dict
     }
 }
+
+#[test]
+fn test_subset_tag() {
+    // the tag must be six uppercase letters and stable for the same glyph set
+    let tag = subset_tag(&[0, 36, 37, 38]);
+    assert_eq!(tag.len(), 6);
+    assert!(tag.chars().all(|c| c.is_ascii_uppercase()));
+    assert_eq!(tag, subset_tag(&[0, 36, 37, 38]));
+}
+
+#[test]
+fn test_font_runs() {
+    // Roboto doesn't have the spade suit, so it has to be written with Symbol
+    let roboto = IndirectFontRef::new("F0");
+    let symbol = IndirectFontRef::new("Symbol");
+    let mut fonts = FontList::new();
+    let external_font = ExternalFont::new(&include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf")[..], 0).unwrap();
+    for (font_ref, data) in vec![(&roboto, Font::ExternalFont(Box::new(external_font))), (&symbol, Font::BuiltinFont(BuiltinFont::Symbol))] {
+        fonts.add_font(font_ref.clone(), DirectFontRef { inner_obj: (1, 0), data, used_glyphs: BTreeMap::new() });
+    }
+
+    let family = FontFamily::new(&roboto).with_fallback(&symbol);
+    let runs = fonts.font_runs("a \u{2660} b", family.fonts());
+    assert_eq!(runs, vec![(0..2, roboto.clone()), (2..6, symbol), (6..7, roboto)]);
+}
+
+#[test]
+fn test_font_descriptor_values() {
+    let face = ttf_parser::Face::parse(include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf"), 0).unwrap();
+    assert_eq!(postscript_name(&face), Some("Roboto-Medium".to_string()));
+    // sans serif, not italic, uses the Unicode character set
+    assert_eq!(font_descriptor_flags(&face), 32);
+}
+
+#[test]
+fn test_to_unicode_surrogate_pairs() {
+    // U+1F600 is outside of the BMP and has to be written as a surrogate pair
+    let cmap = generate_cid_to_unicode_map("F0".into(), vec![vec![(3, "\u{1F600}".into()), (4, "a".into())]], 2);
+    assert!(cmap.contains("<0003> <d83dde00>"));
+    assert!(cmap.contains("<0004> <0061>"));
+}
+
+#[test]
+fn test_synthetic_bold_advance() {
+    // the stroke of a synthetic bold font makes every glyph wider
+    let roboto = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let mut regular = Font::ExternalFont(Box::new(ExternalFont::new(&roboto[..], 0).unwrap()));
+    let mut bold = Font::ExternalFont(Box::new(ExternalFont::new(&roboto[..], 1).unwrap().with_synthesis(FontSynthesis::bold())));
+
+    let regular_width = regular.measure_text("abc", 10.0, None).width.0;
+    let bold_width = bold.measure_text("abc", 10.0, None).width.0;
+    assert!((bold_width - regular_width - 3.0 * SYNTHETIC_BOLD_STROKE * 10.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_embedding_permission() {
+    // sets the fsType field in the OS/2 table of Roboto
+    let with_fs_type = |fs_type: u16| super::sfnt::patched_roboto(|_, tables| {
+        let os2 = tables.iter_mut().find(|table| &table.0 == b"OS/2").unwrap();
+        os2.1[8..10].copy_from_slice(&fs_type.to_be_bytes());
+    });
+
+    let font = ExternalFont::new(&with_fs_type(0x0000)[..], 0).unwrap();
+    assert_eq!(font.embedding_permission(), EmbeddingPermission::Installable);
+    assert!(font.is_subsetting_allowed());
+
+    let font = ExternalFont::new(&with_fs_type(0x0104)[..], 0).unwrap();
+    assert_eq!(font.embedding_permission(), EmbeddingPermission::PreviewAndPrint);
+    assert!(!font.is_subsetting_allowed());
+
+    match ExternalFont::new(&with_fs_type(0x0002)[..], 0) {
+        Err(Error::Pdf(PdfError::FontEmbeddingRestricted)) => { },
+        other => panic!("restricted font was loaded: {:?}", other.map(|font| font.face_name)),
+    }
+}
+
+#[test]
+fn test_type1_font_encoding() {
+    use lopdf::Object::{Integer, Name};
+
+    let pfa = [
+        &b"%!PS-AdobeFont-1.0: Helvetica\ncurrentfile eexec\nD9D66F63\n"[..],
+        &[&[b'0'; 64][..], b"\n"].concat().repeat(8),
+        b"cleartomark\n",
+    ].concat();
+    let afm = include_bytes!("../../../../../assets/afm/Helvetica.afm");
+    let mut font = Type1Font::new(&pfa[..], &afm[..], 0).unwrap();
+    assert_eq!(font.font_name(), "Helvetica");
+
+    // glyphs keep their code in the standard encoding, eacute isn't encoded there
+    let (codes, unencodable) = font.encode_text_lossy("A \u{e9}A\u{4e00}");
+    assert_eq!(codes, vec![65, 32, 1, 65, 63]);
+    assert_eq!(unencodable, vec!['\u{4e00}']);
+
+    // same metrics as the builtin Helvetica
+    let builtin = Font::BuiltinFont(BuiltinFont::Helvetica).measure_text("AV \u{e9}", 10.0, None);
+    assert_eq!(Font::Type1Font(Box::new(font.clone())).measure_text("AV \u{e9}", 10.0, None), builtin);
+
+    let mut doc = lopdf::Document::with_version("1.3");
+    let dictionary = font.into_with_document(&mut doc);
+    let differences = dictionary.get(b"Encoding").and_then(|encoding| encoding.as_dict()?.get(b"Differences")?.as_array()).unwrap();
+    let differences: Vec<String> = differences.iter().map(|object| match *object {
+        Integer(code) => code.to_string(),
+        Name(ref name) => format!("/{}", ::std::string::String::from_utf8_lossy(name)),
+        _ => panic!("unexpected object in Differences: {:?}", object),
+    }).collect();
+    assert_eq!(differences, vec!["1", "/eacute", "32", "/space", "63", "/question", "65", "/A"]);
+}
+
+#[test]
+fn test_vertical_writing() {
+    use lopdf::Object;
+
+    // replace the GSUB table of Roboto with one that has a `vert` feature, which substitutes "a" with "b"
+    let roboto = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let face = ttf_parser::Face::parse(roboto, 0).unwrap();
+    let (a, b) = (face.glyph_index('a').unwrap().0, face.glyph_index('b').unwrap().0);
+    let mut gsub = Vec::new();
+    for value in &[
+        1, 0, 10, 12, 26,           // header: version, script list, feature list, lookup list
+        0,                          // script list: no scripts
+        1, 0x7665, 0x7274, 8,       // feature list: "vert" feature
+        0, 1, 0,                    // feature: lookup 0
+        1, 4,                       // lookup list: one lookup
+        1, 0, 1, 8,                 // lookup: single substitution with one subtable
+        1, 6, b.wrapping_sub(a),    // subtable: coverage, delta
+        1, 1, a,                    // coverage: glyph "a"
+    ] {
+        gsub.extend_from_slice(&u16::to_be_bytes(*value));
+    }
+    let font_bytes = super::sfnt::patched_roboto(|_, tables| {
+        tables.retain(|table| &table.0 != b"GSUB");
+        tables.push((*b"GSUB", gsub));
+    });
+
+    // the builder can be combined with the other options
+    let font = ExternalFont::new(&font_bytes[..], 0).unwrap().with_synthesis(FontSynthesis::oblique(10.0)).with_vertical_writing();
+    assert!(font.vertical_writing);
+    assert_eq!(font.synthesis, FontSynthesis::oblique(10.0));
+
+    let (doc, page, layer) = ::PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let font_ref = doc.add_loaded_external_font(font).unwrap();
+    let layer = doc.get_page(page).get_layer(layer);
+    layer.begin_text_section();
+    layer.set_font(&font_ref, 10.0);
+    layer.write_text("a", &font_ref);
+    layer.end_text_section();
+
+    // the vertical form of "a" is written
+    let mut document = doc.document.borrow_mut();
+    let used_glyphs = document.fonts.get_font(&font_ref).unwrap().used_glyphs;
+    assert_eq!(used_glyphs.keys().cloned().collect::<Vec<_>>(), vec![b]);
+
+    let font = match document.fonts.get_font(&font_ref).unwrap().data {
+        Font::ExternalFont(font) => *font,
+        _ => panic!("not an external font"),
+    };
+    let dictionary = font.into_with_document(&mut document.inner_doc, &used_glyphs);
+    assert_eq!(dictionary.get(b"Encoding").and_then(Object::as_name_str).ok(), Some("Identity-V"));
+    let descendant = dictionary.get(b"DescendantFonts").and_then(Object::as_array).unwrap()[0].as_dict().unwrap();
+    assert!(descendant.get(b"W2").is_ok());
+    assert!(descendant.get(b"DW2").is_ok());
+}
+
+#[test]
+fn test_pair_value_records() {
+    // replace the GPOS table of Roboto with one that kerns "AV" with both value records:
+    // the advance of "A" and the placement and advance of "V" are adjusted
+    let roboto = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let face = ttf_parser::Face::parse(roboto, 0).unwrap();
+    let (a, v) = (face.glyph_index('A').unwrap().0, face.glyph_index('V').unwrap().0);
+    let mut gpos = Vec::new();
+    for value in &[
+        1, 0, 10, 12, 26,                   // header: version, script list, feature list, lookup list
+        0,                                  // script list: no scripts
+        1, 0x6B65, 0x726E, 8,               // feature list: "kern" feature
+        0, 1, 0,                            // feature: lookup 0
+        1, 4,                               // lookup list: one lookup
+        2, 0, 1, 8,                         // lookup: pair adjustment with one subtable
+        1, 12, 0x0004, 0x0005, 1, 18,       // subtable: coverage, value formats, one pair set
+        1, 1, a,                            // coverage: glyph "A"
+        1, v, (-1024i16) as u16, 512, 1024, // pair set: "V", x advance / x placement, x advance
+    ] {
+        gpos.extend_from_slice(&u16::to_be_bytes(*value));
+    }
+    let font_bytes = super::sfnt::patched_roboto(|_, tables| {
+        tables.retain(|table| &table.0 != b"GPOS");
+        tables.push((*b"GPOS", gpos));
+    });
+
+    // 2048 units per em: the "V" is moved left by 1024 - 512 units and the next
+    // glyph right by the 1024 units of the advance minus the 512 of the placement
+    let font = ExternalFont::new(&font_bytes[..], 0).unwrap();
+    assert_eq!(kerning_for_glyphs(font.face(), &[a, v, a, v]), vec![0, -250, 250, -250]);
+}
+
+#[test]
+fn test_cff_font_embedding() {
+    use lopdf::Object;
+
+    // replace the TrueType outlines of Roboto with a CFF table, in which every glyph is empty
+    let roboto = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let number_of_glyphs = ttf_parser::Face::parse(roboto, 0).unwrap().number_of_glyphs();
+    let offset = |value: usize| { let mut bytes = vec![29]; bytes.extend_from_slice(&(value as u32).to_be_bytes()); bytes };
+    let char_strings_offset = 39;
+    let private_offset = char_strings_offset + 3 + 2 * (usize::from(number_of_glyphs) + 1) + usize::from(number_of_glyphs);
+
+    let mut cff = vec![1, 0, 4, 1];                                 // header
+    cff.extend_from_slice(&[0, 1, 1, 1, 5]);                        // name index
+    cff.extend_from_slice(b"Test");
+    cff.extend_from_slice(&[0, 1, 1, 1, 18]);                       // top dict index
+    cff.extend(offset(char_strings_offset));
+    cff.push(17);
+    cff.extend(offset(2));
+    cff.extend(offset(private_offset));
+    cff.push(18);
+    cff.extend_from_slice(&[0, 0, 0, 0]);                           // string index, global subroutines
+    assert_eq!(cff.len(), char_strings_offset);
+    cff.extend_from_slice(&number_of_glyphs.to_be_bytes());         // char strings: "endchar"
+    cff.push(2);
+    for i in 0..=number_of_glyphs {
+        cff.extend_from_slice(&(i + 1).to_be_bytes());
+    }
+    cff.extend(vec![14; usize::from(number_of_glyphs)]);
+    cff.extend_from_slice(&[139, 20]);                              // private dict: defaultWidthX 0
+
+    let font_bytes = super::sfnt::patched_roboto(|sfnt_version, tables| {
+        *sfnt_version = u32::from_be_bytes(*b"OTTO");
+        tables.retain(|table| &table.0 != b"glyf" && &table.0 != b"loca");
+        tables.push((*b"CFF ", cff));
+    });
+
+    let font = ExternalFont::new(&font_bytes[..], 0).unwrap();
+    assert!(font.face().tables().cff.is_some());
+    let used_glyphs: BTreeMap<u16, Option<String>> = vec![(1, Some("a".to_string()))].into_iter().collect();
+
+    // CFF fonts are embedded as CIDFontType0 fonts with the OpenType font in a FontFile3 stream
+    let mut doc = lopdf::Document::with_version("1.3");
+    let dictionary = font.into_with_document(&mut doc, &used_glyphs);
+    assert_eq!(doc.version, "1.6");
+    let descendant = dictionary.get(b"DescendantFonts").and_then(Object::as_array).unwrap()[0].as_dict().unwrap();
+    assert_eq!(descendant.get(b"Subtype").and_then(Object::as_name_str).ok(), Some("CIDFontType0"));
+    assert!(descendant.get(b"CIDToGIDMap").is_err());
+
+    let descriptor = doc.get_dictionary(descendant.get(b"FontDescriptor").and_then(Object::as_reference).unwrap()).unwrap();
+    assert!(descriptor.get(b"FontFile2").is_err());
+    let font_file = doc.get_object(descriptor.get(b"FontFile3").and_then(Object::as_reference).unwrap()).and_then(Object::as_stream).unwrap();
+    assert_eq!(font_file.dict.get(b"Subtype").and_then(Object::as_name_str).ok(), Some("OpenType"));
+    assert_eq!(&font_file.content[..4], b"OTTO");
+}
+
+#[test]
+fn test_glyph_caches() {
+    // the cached glyph IDs and advances are the same as the ones of the face, also
+    // when they are looked up again and after the font was switched to vertical writing
+    let roboto = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let face = ttf_parser::Face::parse(roboto, 0).unwrap();
+    let mut font = ExternalFont::new(&roboto[..], 0).unwrap();
+
+    for _ in 0..2 {
+        for c in "aW\u{e9}\u{20ac}\u{5d0}\u{1F600}".chars() {
+            let glyph_id = face.glyph_index(c).map(|glyph| glyph.0).unwrap_or(0);
+            assert_eq!(font.glyph_id(c), glyph_id);
+            assert_eq!(font.glyph_advance(glyph_id), face.glyph_hor_advance(ttf_parser::GlyphId(glyph_id)).unwrap_or(0));
+        }
+    }
+
+    let glyph_id = font.glyph_id('W');
+    let mut font = font.with_vertical_writing();
+    assert_eq!(font.glyph_id('W'), glyph_id);
+    assert_eq!(font.glyph_advance(glyph_id), vertical_advance(&face, glyph_id));
+}
@@ -0,0 +1,235 @@
+//! Hyphenation with Frank Liang's algorithm (the one TeX uses), with the patterns
+//! of a language loaded from a TeX pattern file
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use {Error, PdfError};
+
+/// The soft hyphen (U+00AD): marks where a word may be hyphenated, but is not drawn
+/// unless the word is broken there
+pub const SOFT_HYPHEN: char = '\u{AD}';
+
+/// Finds the hyphenation points of words with the Liang patterns of a language, for
+/// example the `hyph-de-1996.tex` file of the `hyph-utf8` package for German.
+///
+/// Cloning a `Hyphenator` is cheap, the patterns are shared (also between threads).
+///
+/// ```rust,ignore
+/// let hyphenator = Hyphenator::new(File::open("hyph-de-1996.tex")?)?.with_min_lengths(2, 2);
+/// assert_eq!(hyphenator.hyphenate("Silbentrennung"), vec![3, 6, 10]);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hyphenator {
+    patterns: Arc<Patterns>,
+    /// Minimum number of characters before the first hyphen (TeX: `\lefthyphenmin`)
+    left_min: usize,
+    /// Minimum number of characters after the last hyphen (TeX: `\righthyphenmin`)
+    right_min: usize,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Patterns {
+    /// The letters of each pattern and the values before, between and after them
+    values: HashMap<String, Vec<u8>>,
+    /// Words with explicit hyphenation points (TeX: `\hyphenation`), as the number
+    /// of characters before each hyphen
+    exceptions: HashMap<String, Vec<usize>>,
+    /// Number of letters of the longest pattern
+    max_length: usize,
+}
+
+impl Hyphenator {
+
+    /// Reads a UTF-8 TeX pattern file: the patterns in `\patterns{...}` and the
+    /// exceptions in `\hyphenation{...}`. A file without `\patterns` is read as a plain
+    /// list of patterns (like the `.pat.txt` files of `hyph-utf8`). Fails with
+    /// `PdfError::InvalidHyphenationPatterns` if the file contains no patterns.
+    pub fn new<R>(mut pattern_file: R)
+    -> Result<Self, Error> where R: Read
+    {
+        let mut buf = String::new();
+        pattern_file.read_to_string(&mut buf)?;
+        Ok(Self::from_patterns(&buf)?)
+    }
+
+    /// Same as `new`, for a pattern file that is already in memory
+    pub fn from_patterns(pattern_file: &str)
+    -> Result<Self, PdfError>
+    {
+        // comments run from % to the end of the line
+        let content: String = pattern_file.lines()
+            .map(|line| line.split('%').next().unwrap_or(""))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let (patterns, exceptions) = match group(&content, "\\patterns") {
+            Some(patterns) => (patterns, group(&content, "\\hyphenation").unwrap_or("")),
+            None => (&content[..], ""),
+        };
+
+        let mut parsed = Patterns::default();
+
+        for pattern in patterns.split_whitespace() {
+            let mut letters = String::new();
+            let mut values = vec![0];
+            for c in pattern.chars() {
+                match c.to_digit(10) {
+                    Some(value) => *values.last_mut().unwrap() = value as u8,
+                    None => { letters.push(c); values.push(0); },
+                }
+            }
+            if letters.is_empty() || !pattern.chars().any(|c| c.is_ascii_digit()) {
+                return Err(PdfError::InvalidHyphenationPatterns);
+            }
+            parsed.max_length = parsed.max_length.max(values.len() - 1);
+            parsed.values.insert(letters, values);
+        }
+
+        if parsed.values.is_empty() {
+            return Err(PdfError::InvalidHyphenationPatterns);
+        }
+
+        for exception in exceptions.split_whitespace() {
+            let mut word = String::new();
+            let mut hyphens = Vec::new();
+            for c in exception.chars() {
+                match c {
+                    '-' => hyphens.push(word.chars().count()),
+                    c => word.push(c),
+                }
+            }
+            parsed.exceptions.insert(lowercase(&word), hyphens);
+        }
+
+        Ok(Self {
+            patterns: Arc::new(parsed),
+            left_min: 2,
+            right_min: 3,
+        })
+    }
+
+    /// Sets the minimum number of characters before the first and after the last
+    /// hyphen of a word, the default is 2 and 3 (like in TeX)
+    pub fn with_min_lengths(mut self, left_min: usize, right_min: usize)
+    -> Self
+    {
+        self.left_min = left_min.max(1);
+        self.right_min = right_min.max(1);
+        self
+    }
+
+    /// Returns the positions (byte offsets) in the word where it may be hyphenated.
+    ///
+    /// If the word contains soft hyphens, only the positions after them are returned:
+    /// like in TeX, the patterns aren't used for words with explicit hyphenation points.
+    pub fn hyphenate(&self, word: &str)
+    -> Vec<usize>
+    {
+        if word.contains(SOFT_HYPHEN) {
+            return soft_hyphens(word);
+        }
+
+        let offsets: Vec<usize> = word.char_indices().map(|(offset, _)| offset).collect();
+        let length = offsets.len();
+        if length < self.left_min + self.right_min {
+            return Vec::new();
+        }
+
+        // the number of characters before each possible hyphen
+        let lowercase = lowercase(word);
+        let hyphens = match self.patterns.exceptions.get(&lowercase) {
+            Some(hyphens) => hyphens.clone(),
+            None => self.pattern_hyphens(&lowercase),
+        };
+
+        hyphens.into_iter()
+            .filter(|&before| before >= self.left_min && before + self.right_min <= length)
+            .map(|before| offsets[before])
+            .collect()
+    }
+
+    /// Applies all patterns that match the word (with a dot at its start and end):
+    /// a hyphen is allowed where the highest value between two letters is odd
+    fn pattern_hyphens(&self, lowercase: &str)
+    -> Vec<usize>
+    {
+        let dotted: Vec<char> = Some('.').into_iter().chain(lowercase.chars()).chain(Some('.')).collect();
+        let mut values = vec![0; dotted.len() + 1];
+
+        for start in 0..dotted.len() {
+            let max_end = dotted.len().min(start + self.patterns.max_length);
+            for end in start + 1..=max_end {
+                let letters: String = dotted[start..end].iter().collect();
+                if let Some(pattern) = self.patterns.values.get(&letters) {
+                    for (value, pattern_value) in values[start..].iter_mut().zip(pattern) {
+                        *value = (*value).max(*pattern_value);
+                    }
+                }
+            }
+        }
+
+        // values[1 + n] is the value between the n-th and the (n + 1)-th character of the word
+        (1..dotted.len() - 2).filter(|&before| values[before + 1] % 2 == 1).collect()
+    }
+}
+
+/// Returns the positions after the soft hyphens in the word (except at its end)
+pub(crate) fn soft_hyphens(word: &str)
+-> Vec<usize>
+{
+    word.char_indices()
+        .filter(|&(_, c)| c == SOFT_HYPHEN)
+        .map(|(offset, c)| offset + c.len_utf8())
+        .filter(|&offset| offset < word.len())
+        .collect()
+}
+
+/// Converts the word to lowercase character by character, so that the number of
+/// characters doesn't change (unlike `str::to_lowercase`, e.g. for "İ")
+fn lowercase(word: &str)
+-> String
+{
+    word.chars().map(|c| c.to_lowercase().next().unwrap_or(c)).collect()
+}
+
+/// Returns the content of a TeX group like `\patterns{...}`
+fn group<'a>(content: &'a str, command: &str)
+-> Option<&'a str>
+{
+    let start = content.find(command)? + command.len();
+    let start = start + content[start..].find('{')? + 1;
+    let end = start + content[start..].find('}')?;
+    Some(&content[start..end])
+}
+
+#[test]
+fn test_hyphenate() {
+    // the patterns from Liang's thesis for "hyphenation"
+    let hyphenator = Hyphenator::from_patterns("
+        % comment
+        \\patterns{
+        hy3ph he2n hena4 hen5at 1na n2at 1tio 2io o2n
+        }
+        \\hyphenation{
+        ta-ble
+        İn-ta-ble
+        }
+    ").unwrap();
+
+    assert_eq!(hyphenator.hyphenate("hyphenation"), vec![2, 6]);
+    assert_eq!(hyphenator.hyphenate("Hyphenation"), vec![2, 6]);
+    assert_eq!(hyphenator.hyphenate("Table"), vec![2]);
+    assert_eq!(hyphenator.clone().with_min_lengths(3, 2).hyphenate("table"), Vec::<usize>::new());
+    // exceptions are found with the same lowercase conversion as the words ("İ" is 2 bytes)
+    assert_eq!(hyphenator.hyphenate("İntable"), vec![3, 5]);
+
+    // soft hyphens take precedence over the patterns
+    assert_eq!(hyphenator.hyphenate("hyphen\u{AD}ation"), vec![8]);
+
+    // a plain list of patterns
+    let hyphenator = Hyphenator::from_patterns("1ß a1b").unwrap().with_min_lengths(1, 1);
+    assert_eq!(hyphenator.hyphenate("aßab"), vec![1, 4]);
+
+    assert_eq!(Hyphenator::from_patterns("% nothing").unwrap_err(), PdfError::InvalidHyphenationPatterns);
+}
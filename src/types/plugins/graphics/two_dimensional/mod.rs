@@ -3,11 +3,28 @@
 pub mod point;
 pub mod line;
 pub mod font;
+pub mod shaping;
+pub mod paragraph;
+pub mod hyphenation;
+pub mod rich_text;
+pub mod flow;
+mod afm;
+mod color_font;
+mod encoding;
+mod glyph_names;
+mod sfnt;
+mod type1;
+mod woff;
 // pub mod svg;
 pub mod image;
 
 pub use self::point::Point;
 pub use self::line::Line;
 pub use self::font::*;
+pub use self::shaping::{ShapingOptions, TextDirection};
+pub use self::paragraph::{Paragraph, ParagraphLayout, TextAlign};
+pub use self::hyphenation::Hyphenator;
+pub use self::rich_text::{RichText, TextSpan};
+pub use self::flow::{Frame, TextFlow};
 // pub use self::svg::Svg;
 pub use self::image::Image;
\ No newline at end of file
@@ -0,0 +1,481 @@
+//! Paragraphs: text that is broken into lines to fit into a box of a given width
+//! and aligned inside of it
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use unicode_linebreak::{linebreaks, BreakOpportunity};
+use super::hyphenation::SOFT_HYPHEN;
+use {Hyphenator, IndirectFontRef, Mm, PdfDocument, PdfLayerReference, Pt, TextDirection, TextMatrix};
+
+/// Horizontal alignment of the lines of a `Paragraph`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum TextAlign {
+    #[default]
+    Left,
+    Right,
+    Center,
+    /// The lines are stretched to the width of the box by widening the spaces (and
+    /// the gaps between the letters, see `Paragraph::with_letter_spacing`). The last
+    /// line (and lines that end with a line break) are aligned to the left.
+    Justify,
+}
+
+/// A text that is broken into lines at the line break opportunities of the Unicode
+/// Line Breaking Algorithm (UAX #14) to fit into a box of the given width. Words that
+/// are wider than the box are broken between their characters. Line breaks in the
+/// text (`\n`) always start a new line.
+///
+/// Words may also be broken at soft hyphens (U+00AD) and, with a `Hyphenator`, at
+/// their hyphenation points: a hyphen (`-`) is drawn at the end of the line then.
+/// Soft hyphens are not drawn if the word isn't broken at them.
+///
+/// ```rust,ignore
+/// let paragraph = Paragraph::new(text, &font, 10.0, 12.0, Mm(80.0)).with_align(TextAlign::Justify);
+/// let layout = paragraph.render(&layer, Mm(20.0), Mm(270.0));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paragraph {
+    text: String,
+    /// The font, or the fonts of a `FontFamily`
+    fonts: Vec<IndirectFontRef>,
+    /// Font size in points
+    font_size: f64,
+    /// Distance between the baselines of two lines in points
+    line_height: f64,
+    /// Width of the box
+    width: Mm,
+    align: TextAlign,
+    /// Maximum spacing between the letters of justified lines in points
+    max_letter_spacing: f64,
+    /// Height of the box, lines that don't fit into it are returned as overflow
+    max_height: Option<Mm>,
+    hyphenator: Option<Hyphenator>,
+    /// Base direction for reordering right-to-left text, see `PdfLayerReference::set_base_direction`
+    base_direction: Option<TextDirection>,
+}
+
+/// Result of rendering a `Paragraph`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParagraphLayout {
+    /// Height of the lines that were written (the number of lines times the line height)
+    pub height: Mm,
+    /// Number of lines that were written
+    pub lines: usize,
+    /// The text that didn't fit into the maximum height, starting with the first
+    /// line that wasn't written
+    pub overflow: Option<String>,
+}
+
+/// A line of a paragraph, as byte range in the text of the paragraph
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ParagraphLine {
+    /// The text of the line, without the whitespace at the end
+    pub(crate) range: Range<usize>,
+    /// Width of the text in points
+    pub(crate) width: f64,
+    /// Whether the line may be stretched for justified text (false for the last line
+    /// and lines that end with a line break)
+    pub(crate) justify: bool,
+    /// Whether the line ends with a hyphenated word (and a hyphen is drawn)
+    pub(crate) hyphen: bool,
+}
+
+/// Kind of a position where a line may be broken
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Break {
+    Allowed,
+    Mandatory,
+    /// Inside a word, at a soft hyphen or a hyphenation point
+    Hyphen,
+}
+
+impl Paragraph {
+
+    /// Creates a paragraph with the font (or `FontFamily`) at the font size and
+    /// the line height (in points), which is broken into lines of the `width`
+    pub fn new<S, F>(text: S, font: &F, font_size: f64, line_height: f64, width: Mm)
+    -> Self where S: Into<String>, F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        Self {
+            text: text.into(),
+            fonts: font.as_ref().to_vec(),
+            font_size,
+            line_height,
+            width,
+            align: TextAlign::default(),
+            max_letter_spacing: 0.0,
+            max_height: None,
+            hyphenator: None,
+            base_direction: None,
+        }
+    }
+
+    /// Sets the alignment of the lines, the default is `TextAlign::Left`
+    pub fn with_align(mut self, align: TextAlign)
+    -> Self
+    {
+        self.align = align;
+        self
+    }
+
+    /// Sets the maximum spacing (in points) that may be put between the letters of
+    /// justified lines before the rest is put into the spaces, the default is 0
+    pub fn with_letter_spacing(mut self, max_letter_spacing: f64)
+    -> Self
+    {
+        self.max_letter_spacing = max_letter_spacing;
+        self
+    }
+
+    /// Sets the height of the box: the lines that don't fit into it are not written,
+    /// their text is returned as `ParagraphLayout::overflow`
+    pub fn with_max_height(mut self, max_height: Mm)
+    -> Self
+    {
+        self.max_height = Some(max_height);
+        self
+    }
+
+    /// Hyphenates the words of the paragraph with the hyphenator (the patterns of the
+    /// language of the text) if they don't fit on a line
+    pub fn with_hyphenator(mut self, hyphenator: &Hyphenator)
+    -> Self
+    {
+        self.hyphenator = Some(hyphenator.clone());
+        self
+    }
+
+    /// Sets the base direction of the text for the Unicode Bidirectional Algorithm. By
+    /// default, the direction of each line is taken from its first character with a
+    /// strong direction, so a line of a Hebrew paragraph that starts with a Latin word
+    /// would be reordered as left-to-right text.
+    pub fn with_base_direction(mut self, direction: TextDirection)
+    -> Self
+    {
+        self.base_direction = Some(direction);
+        self
+    }
+
+    /// Replaces the text, for continuing the overflow of the paragraph in another box
+    pub(crate) fn with_text(mut self, text: String)
+    -> Self
+    {
+        self.text = text;
+        self
+    }
+
+    /// Sets the width of the box
+    pub(crate) fn with_width(mut self, width: Mm)
+    -> Self
+    {
+        self.width = width;
+        self
+    }
+
+    /// Returns the text of the paragraph
+    #[inline]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Writes the paragraph into a box whose top left corner is at `x` / `y`, in its
+    /// own text section. The first baseline is placed so that the ascent and descent of
+    /// the font are centered in the line height. Returns the height that was used and
+    /// the text that didn't fit.
+    pub fn render(&self, layer: &PdfLayerReference, x: Mm, y: Mm)
+    -> ParagraphLayout
+    {
+        let (lines, ascent, descent) = {
+            let doc = layer.document.upgrade().unwrap();
+            let mut doc = doc.borrow_mut();
+            let metrics = doc.measure_text(&self.fonts, "", self.font_size, None);
+            (self.layout_lines(&mut doc), metrics.ascent.0, metrics.descent.0)
+        };
+        let previous_direction = {
+            let doc = layer.document.upgrade().unwrap();
+            let doc = doc.borrow();
+            doc.pages[layer.page.0].layers[layer.layer.0].current_base_direction
+        };
+
+        let max_lines = match self.max_height {
+            Some(max_height) if self.line_height > 0.0 => (Pt::from(max_height).0 / self.line_height + 1e-9).floor() as usize,
+            _ => lines.len(),
+        };
+        let written = lines.len().min(max_lines);
+
+        if written > 0 {
+            let (x, top) = (Pt::from(x).0, Pt::from(y).0);
+            let width = Pt::from(self.width).0;
+            let half_leading = (self.line_height - (ascent - descent)) / 2.0;
+
+            layer.begin_text_section();
+            layer.set_font(&self.fonts, self.font_size);
+            if self.base_direction.is_some() {
+                layer.set_base_direction(self.base_direction);
+            }
+
+            for (i, line) in lines[..written].iter().enumerate() {
+                let text = line_text(&self.text[line.range.clone()], line.hyphen);
+                let offset = match self.align {
+                    TextAlign::Left | TextAlign::Justify => 0.0,
+                    TextAlign::Right => width - line.width,
+                    TextAlign::Center => (width - line.width) / 2.0,
+                };
+                let baseline = top - i as f64 * self.line_height - half_leading - ascent;
+                layer.set_text_matrix(TextMatrix::Translate(Pt(x + offset).into(), Pt(baseline).into()));
+
+                if self.align == TextAlign::Justify && line.justify {
+                    layer.write_justified_text(&text, &self.fonts, self.width, self.max_letter_spacing);
+                } else {
+                    layer.write_text(&text, &self.fonts);
+                }
+            }
+
+            if self.base_direction.is_some() {
+                layer.set_base_direction(previous_direction);
+            }
+            layer.end_text_section();
+        }
+
+        ParagraphLayout {
+            height: Pt(written as f64 * self.line_height).into(),
+            lines: written,
+            overflow: lines.get(written).map(|line| self.text[line.range.start..].to_string()),
+        }
+    }
+
+    /// Breaks the text into lines: as many segments between two break opportunities as
+    /// fit are put on a line (whitespace at the end of a line doesn't count, the hyphen
+    /// at the end of a hyphenated line does)
+    pub(crate) fn layout_lines(&self, doc: &mut PdfDocument)
+    -> Vec<ParagraphLine>
+    {
+        let text = &self.text;
+        let max_width = Pt::from(self.width).0;
+        let mut measure = |text: &str| doc.measure_text(&self.fonts, text, self.font_size, self.base_direction).width.0;
+
+        let mut lines = Vec::new();
+        if text.is_empty() {
+            return lines;
+        }
+
+        // each segment (the text between two breaks) is measured once, the width of a
+        // line is the sum of the widths of its segments (which ignores the kerning between
+        // them). Only the lines themselves are measured again, for their exact width.
+        let hyphen_width = measure("-");
+
+        // start of the current line and end of the segments that fit on it so far, with
+        // and without the whitespace at the end
+        let mut line_start = 0;
+        let mut line_end = 0;
+        let mut line_end_hyphen = false;
+        let mut line_width = 0.0;
+        let mut line_trimmed_width = 0.0;
+
+        for (position, kind) in self.breaks() {
+            let hyphen = kind == Break::Hyphen;
+            let segment = &text[line_end..position];
+            let segment_trimmed_width = measure(&line_text(segment, false));
+            let segment_width = segment_trimmed_width + measure(&segment[segment.trim_end().len()..]);
+
+            // the width of the line if it would end after the segment
+            let width_with = |line_width: f64, line_trimmed_width: f64| {
+                let trimmed = if segment.trim_end().is_empty() { line_trimmed_width } else { line_width + segment_trimmed_width };
+                trimmed + if hyphen { hyphen_width } else { 0.0 }
+            };
+
+            if line_end > line_start && width_with(line_width, line_trimmed_width) > max_width {
+                lines.push(ParagraphLine::new(text, line_start..line_end, &mut measure, true, line_end_hyphen));
+                line_start = line_end;
+                line_width = 0.0;
+                line_trimmed_width = 0.0;
+            }
+
+            if width_with(line_width, line_trimmed_width) > max_width {
+                // a segment that is wider than the whole line is broken between its characters
+                let visible_end = line_end + segment.trim_end().len();
+                line_width = 0.0;
+                line_trimmed_width = 0.0;
+                for (offset, c) in segment.char_indices() {
+                    let offset = line_end + offset;
+                    let width = if c == SOFT_HYPHEN { 0.0 } else { measure(c.encode_utf8(&mut [0; 4])) };
+                    let end_width = if hyphen && offset + c.len_utf8() == visible_end { hyphen_width } else { 0.0 };
+                    if offset > line_start && !c.is_whitespace() && line_width + width + end_width > max_width {
+                        lines.push(ParagraphLine::new(text, line_start..offset, &mut measure, false, false));
+                        line_start = offset;
+                        line_width = 0.0;
+                    }
+                    line_width += width;
+                    if !c.is_whitespace() {
+                        line_trimmed_width = line_width;
+                    }
+                }
+            } else {
+                if !segment.trim_end().is_empty() {
+                    line_trimmed_width = line_width + segment_trimmed_width;
+                }
+                line_width += segment_width;
+            }
+
+            line_end = position;
+            line_end_hyphen = hyphen;
+
+            if kind == Break::Mandatory {
+                lines.push(ParagraphLine::new(text, line_start..position, &mut measure, false, false));
+                line_start = position;
+                line_width = 0.0;
+                line_trimmed_width = 0.0;
+            }
+        }
+
+        lines
+    }
+
+    /// Returns the positions where the text may be broken: the line break opportunities
+    /// and the hyphenation points of the words
+    fn breaks(&self)
+    -> Vec<(usize, Break)>
+    {
+        let text = &self.text;
+        let mut breaks = BTreeMap::new();
+
+        if let Some(ref hyphenator) = self.hyphenator {
+            let mut word_start = None;
+            for (offset, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+                match word_start {
+                    _ if c.is_alphabetic() || c == SOFT_HYPHEN => { word_start.get_or_insert(offset); },
+                    Some(start) => {
+                        breaks.extend(hyphenator.hyphenate(&text[start..offset]).into_iter().map(|hyphen| (start + hyphen, Break::Hyphen)));
+                        word_start = None;
+                    },
+                    None => { },
+                }
+            }
+        }
+
+        for (position, opportunity) in linebreaks(text) {
+            let kind = match opportunity {
+                BreakOpportunity::Mandatory => Break::Mandatory,
+                _ if text[..position].ends_with(SOFT_HYPHEN) => Break::Hyphen,
+                _ => Break::Allowed,
+            };
+            breaks.insert(position, kind);
+        }
+
+        breaks.into_iter().collect()
+    }
+}
+
+impl ParagraphLine {
+
+    fn new<M>(text: &str, range: Range<usize>, measure: &mut M, justify: bool, hyphen: bool)
+    -> Self where M: FnMut(&str) -> f64
+    {
+        let end = range.start + text[range.clone()].trim_end().len();
+        Self {
+            width: measure(&line_text(&text[range.clone()], hyphen)),
+            range: range.start..end,
+            justify,
+            hyphen,
+        }
+    }
+}
+
+/// Returns the text that is written for a line: without soft hyphens and whitespace
+/// at the end, but with a hyphen if the last word is hyphenated
+fn line_text(text: &str, hyphen: bool)
+-> String
+{
+    let mut line: String = text.trim_end().chars().filter(|&c| c != SOFT_HYPHEN).collect();
+    if hyphen {
+        line.push('-');
+    }
+    line
+}
+
+#[test]
+fn test_paragraph_lines() {
+    use {BuiltinFont, PdfDocument};
+
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+    let layer = doc.get_page(page).get_layer(layer);
+
+    // "aaa bbb" is 35.94pt wide in Helvetica at 10pt (with the kerning of "bb"), the box is 40pt wide
+    let paragraph = Paragraph::new("aaa bbb ccc\nddd", &font, 10.0, 12.0, Pt(40.0).into());
+    let lines = paragraph.layout_lines(&mut doc.document.borrow_mut());
+    let texts: Vec<&str> = lines.iter().map(|line| &paragraph.text()[line.range.clone()]).collect();
+    assert_eq!(texts, vec!["aaa bbb", "ccc", "ddd"]);
+    assert!((lines[0].width - 35.94).abs() < 1e-9);
+    assert_eq!(lines.iter().map(|line| line.justify).collect::<Vec<_>>(), vec![true, false, false]);
+
+    // words that are wider than the box are broken between their characters
+    let paragraph = Paragraph::new("aaaaaaaaaa", &font, 10.0, 12.0, Pt(40.0).into());
+    let lines = paragraph.layout_lines(&mut doc.document.borrow_mut());
+    assert_eq!(lines.iter().map(|line| line.range.clone()).collect::<Vec<_>>(), vec![0..7, 7..10]);
+
+    let layout = Paragraph::new("aaa bbb ccc\nddd", &font, 10.0, 12.0, Pt(40.0).into())
+        .with_max_height(Pt(20.0).into())
+        .render(&layer, Mm(10.0), Mm(200.0));
+    assert_eq!(layout.lines, 1);
+    assert!((Pt::from(layout.height).0 - 12.0).abs() < 1e-3);
+    assert_eq!(layout.overflow.as_deref(), Some("ccc\nddd"));
+}
+
+#[test]
+fn test_paragraph_justify() {
+    use lopdf::Object;
+    use {BuiltinFont, PdfDocument};
+
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+
+    // the first line ("aaa bbb", 35.94pt wide) is stretched to 40pt with a TJ operation
+    Paragraph::new("aaa bbb ccc", &font, 10.0, 12.0, Pt(40.0).into())
+        .with_align(TextAlign::Justify)
+        .with_letter_spacing(0.2)
+        .render(&doc.get_page(page).get_layer(layer), Mm(10.0), Mm(200.0));
+
+    let document = doc.document.borrow();
+    let operations = &document.pages[page.0].layers[layer.0].operations;
+    assert!(operations.iter().all(|operation| operation.operator != "Tw"));
+
+    let justified: Vec<&Vec<Object>> = operations.iter()
+        .filter(|operation| operation.operator == "TJ")
+        .filter_map(|operation| operation.operands[0].as_array().ok())
+        .collect();
+    assert_eq!(justified.len(), 1);
+
+    // the offsets are in thousandths of the font size, negative values widen the text:
+    // without the kerning of the two "bb" pairs (2 × 10), they widen the line by 40 - 35.94 points
+    let offsets: f64 = justified[0].iter().map(|object| match *object {
+        Object::Real(offset) => offset,
+        Object::Integer(offset) => offset as f64,
+        _ => 0.0,
+    }).sum();
+    assert!(((20.0 - offsets) * 10.0 / 1000.0 - (40.0 - 35.94)).abs() < 1e-3);
+}
+
+#[test]
+fn test_paragraph_hyphenation() {
+    use {BuiltinFont, PdfDocument};
+
+    let (doc, _, _) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let font = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+    let texts = |paragraph: &Paragraph| paragraph.layout_lines(&mut doc.document.borrow_mut()).iter()
+        .map(|line| line_text(&paragraph.text()[line.range.clone()], line.hyphen))
+        .collect::<Vec<_>>();
+
+    // soft hyphens are only drawn (as a hyphen) where the word is broken
+    let paragraph = Paragraph::new("aa bb\u{AD}cc\u{AD}dd", &font, 10.0, 12.0, Pt(35.0).into());
+    assert_eq!(texts(&paragraph), vec!["aa bb-", "ccdd"]);
+
+    // the patterns from Liang's thesis for "hyphenation", "hy-phen-ation"
+    let hyphenator = Hyphenator::from_patterns("hy3ph he2n hena4 hen5at 1na n2at 1tio 2io o2n").unwrap();
+    let paragraph = Paragraph::new("a hyphenation", &font, 10.0, 12.0, Pt(35.0).into()).with_hyphenator(&hyphenator);
+    assert_eq!(texts(&paragraph), vec!["a hy-", "phen-", "ation"]);
+
+    // paragraphs with a hyphenator can be laid out on other threads
+    fn assert_send<T: Send>(_: &T) { }
+    assert_send(&paragraph);
+}
@@ -0,0 +1,284 @@
+//! Rich text: a line of text made of spans with different fonts, sizes, colors and
+//! baseline offsets, written as a single text object
+
+use std::iter::FromIterator;
+use {Color, Greyscale, IndirectFontRef, Line, Mm, PdfDocument, PdfLayerReference, Point, Pt, TextMatrix, TextMetrics};
+
+/// Position of the underline below the baseline and its thickness, in thousandths
+/// of the font size (the values of the standard 14 fonts)
+const UNDERLINE_POSITION: f64 = -100.0;
+const UNDERLINE_THICKNESS: f64 = 50.0;
+
+/// A piece of text with its own font (or `FontFamily`), size and style
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextSpan {
+    text: String,
+    fonts: Vec<IndirectFontRef>,
+    /// Font size in points
+    font_size: f64,
+    color: Color,
+    /// Character spacing in points (see `PdfLayerReference::set_character_spacing`)
+    character_spacing: f64,
+    /// Baseline offset in points (see `PdfLayerReference::set_line_offset`)
+    rise: i64,
+    underline: bool,
+}
+
+/// A line of text made of `TextSpan`s, for example a bold word, a colored link or
+/// a superscript in regular text. The spans are written one after another on the
+/// same baseline (except for their rise).
+///
+/// ```rust,ignore
+/// let text: RichText = vec![
+///     TextSpan::new("E = mc", &regular, 12.0),
+///     TextSpan::new("2", &regular, 8.0).with_rise(5),
+///     TextSpan::new(" (see ", &regular, 12.0),
+///     TextSpan::new("here", &regular, 12.0).with_color(blue).with_underline(true),
+///     TextSpan::new(")", &regular, 12.0),
+/// ].into_iter().collect();
+/// text.render(&layer, Mm(20.0), Mm(250.0));
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RichText {
+    spans: Vec<TextSpan>,
+}
+
+impl TextSpan {
+
+    /// Creates a span with the font (or `FontFamily`) at the font size, in black,
+    /// without character spacing, rise or underline
+    pub fn new<S, F>(text: S, font: &F, font_size: f64)
+    -> Self where S: Into<String>, F: AsRef<[IndirectFontRef]> + ?Sized
+    {
+        Self {
+            text: text.into(),
+            fonts: font.as_ref().to_vec(),
+            font_size,
+            color: Color::Greyscale(Greyscale::new(0.0, None)),
+            character_spacing: 0.0,
+            rise: 0,
+            underline: false,
+        }
+    }
+
+    /// Sets the fill color of the text (and of the underline)
+    pub fn with_color(mut self, color: Color)
+    -> Self
+    {
+        self.color = color;
+        self
+    }
+
+    /// Sets the extra spacing after each character, in points
+    pub fn with_character_spacing(mut self, character_spacing: f64)
+    -> Self
+    {
+        self.character_spacing = character_spacing;
+        self
+    }
+
+    /// Moves the text up (positive values, for superscripts) or down (negative
+    /// values, for subscripts) from the baseline, in points
+    pub fn with_rise(mut self, rise: i64)
+    -> Self
+    {
+        self.rise = rise;
+        self
+    }
+
+    /// Sets if the text is underlined
+    pub fn with_underline(mut self, underline: bool)
+    -> Self
+    {
+        self.underline = underline;
+        self
+    }
+
+    /// Returns the text of the span
+    #[inline]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the metrics of the span, with the character spacing added to the
+    /// advances and the rise added to the ascent and descent
+    fn measure(&self, doc: &mut PdfDocument)
+    -> TextMetrics
+    {
+        let metrics = doc.measure_text(&self.fonts, &self.text, self.font_size, None);
+        let glyph_advances: Vec<Pt> = metrics.glyph_advances.iter().map(|advance| Pt(advance.0 + self.character_spacing)).collect();
+        TextMetrics {
+            width: Pt(glyph_advances.iter().map(|advance| advance.0).sum()),
+            ascent: Pt(metrics.ascent.0 + self.rise as f64),
+            descent: Pt(metrics.descent.0 + self.rise as f64),
+            glyph_advances,
+        }
+    }
+}
+
+impl RichText {
+
+    /// Creates an empty text
+    pub fn new()
+    -> Self
+    {
+        Self::default()
+    }
+
+    /// Appends a span to the text
+    pub fn with_span(mut self, span: TextSpan)
+    -> Self
+    {
+        self.spans.push(span);
+        self
+    }
+
+    /// Returns the spans of the text
+    #[inline]
+    pub fn spans(&self) -> &[TextSpan] {
+        &self.spans
+    }
+
+    /// Returns the width of the whole text and the highest ascent and lowest descent
+    /// of the spans (including their rise)
+    pub fn measure(&self, layer: &PdfLayerReference)
+    -> TextMetrics
+    {
+        let doc = layer.document.upgrade().unwrap();
+        let mut doc = doc.borrow_mut();
+        self.measure_spans(&mut doc).1
+    }
+
+    fn measure_spans(&self, doc: &mut PdfDocument)
+    -> (Vec<TextMetrics>, TextMetrics)
+    {
+        let spans: Vec<TextMetrics> = self.spans.iter().map(|span| span.measure(doc)).collect();
+        let total = TextMetrics {
+            width: Pt(spans.iter().map(|metrics| metrics.width.0).sum()),
+            ascent: Pt(spans.iter().map(|metrics| metrics.ascent.0).fold(0.0, f64::max)),
+            descent: Pt(spans.iter().map(|metrics| metrics.descent.0).fold(0.0, f64::min)),
+            glyph_advances: spans.iter().flat_map(|metrics| metrics.glyph_advances.iter().cloned()).collect(),
+        };
+        (spans, total)
+    }
+
+    /// Writes the text in its own text section, starting at `x` on the baseline `y`,
+    /// and draws the underlines. Returns the metrics of the text (see `measure`).
+    ///
+    /// The font and fill color of the last span stay set afterwards, the character
+    /// spacing and the rise are reset to 0.
+    pub fn render(&self, layer: &PdfLayerReference, x: Mm, y: Mm)
+    -> TextMetrics
+    {
+        let (spans, total) = {
+            let doc = layer.document.upgrade().unwrap();
+            let mut doc = doc.borrow_mut();
+            self.measure_spans(&mut doc)
+        };
+
+        if self.spans.is_empty() {
+            return total;
+        }
+
+        layer.begin_text_section();
+        layer.set_text_matrix(TextMatrix::Translate(x, y));
+
+        let mut previous: Option<&TextSpan> = None;
+        for span in &self.spans {
+            if previous.map(|p| p.fonts != span.fonts || p.font_size != span.font_size).unwrap_or(true) {
+                layer.set_font(&span.fonts, span.font_size);
+            }
+            if previous.map(|p| p.color != span.color).unwrap_or(true) {
+                layer.set_fill_color(span.color.clone());
+            }
+            if previous.map(|p| p.character_spacing).unwrap_or(0.0) != span.character_spacing {
+                layer.set_character_spacing(span.character_spacing);
+            }
+            if previous.map(|p| p.rise).unwrap_or(0) != span.rise {
+                layer.set_line_offset(span.rise);
+            }
+            layer.write_text(&span.text, &span.fonts);
+            previous = Some(span);
+        }
+
+        if let Some(last) = previous {
+            if last.character_spacing != 0.0 {
+                layer.set_character_spacing(0.0);
+            }
+            if last.rise != 0 {
+                layer.set_line_offset(0);
+            }
+        }
+
+        layer.end_text_section();
+
+        // the underlines are drawn as filled rectangles below the text
+        let mut start = Pt::from(x).0;
+        let mut underlines = Vec::new();
+        for (span, metrics) in self.spans.iter().zip(&spans) {
+            if span.underline && metrics.width.0 > 0.0 {
+                let top = Pt::from(y).0 + span.rise as f64 + span.font_size * UNDERLINE_POSITION / 1000.0;
+                let bottom = top - span.font_size * UNDERLINE_THICKNESS / 1000.0;
+                underlines.push((span, [(start, top), (start + metrics.width.0, top), (start + metrics.width.0, bottom), (start, bottom)]));
+            }
+            start += metrics.width.0;
+        }
+
+        if !underlines.is_empty() {
+            layer.save_graphics_state();
+            for (span, corners) in underlines {
+                layer.set_fill_color(span.color.clone());
+                let mut rectangle: Line = corners.iter().map(|&(x, y)| (Point::new(Pt(x).into(), Pt(y).into()), false)).collect();
+                rectangle.set_closed(true);
+                rectangle.set_fill(true);
+                layer.add_shape(rectangle);
+            }
+            layer.restore_graphics_state();
+        }
+
+        total
+    }
+}
+
+impl FromIterator<TextSpan> for RichText {
+    fn from_iter<I: IntoIterator<Item = TextSpan>>(iter: I) -> Self {
+        RichText {
+            spans: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[test]
+fn test_rich_text() {
+    use {BuiltinFont, PdfDocument, Rgb};
+
+    let (doc, page, layer) = PdfDocument::new("test", Mm(210.0), Mm(297.0), "Layer 1");
+    let regular = doc.add_builtin_font(BuiltinFont::Helvetica).unwrap();
+    let bold = doc.add_builtin_font(BuiltinFont::HelveticaBold).unwrap();
+    let blue = Color::Rgb(Rgb::new(0.0, 0.0, 1.0, None));
+
+    let text: RichText = vec![
+        TextSpan::new("a", &regular, 10.0),
+        TextSpan::new("b", &bold, 10.0).with_character_spacing(1.0),
+        TextSpan::new("2", &regular, 5.0).with_rise(4),
+        TextSpan::new("c", &regular, 10.0).with_color(blue).with_underline(true),
+    ].into_iter().collect();
+
+    // a: 5.56, b: 6.11 + 1 (character spacing), 2: 2.78, c: 5, the superscript is
+    // higher than the rest of the text (ascent 3.59 + 4 > 7.18)
+    let metrics = text.render(&doc.get_page(page).get_layer(layer), Mm(10.0), Mm(200.0));
+    assert!((metrics.width.0 - 20.45).abs() < 1e-9);
+    assert!((metrics.ascent.0 - 7.59).abs() < 1e-9);
+    assert_eq!(metrics.glyph_advances.len(), 4);
+
+    let document = doc.document.borrow();
+    let operators: Vec<&str> = document.pages[page.0].layers[layer.0].operations.iter().map(|operation| &operation.operator[..]).collect();
+    assert_eq!(operators.iter().filter(|&&operator| operator == "BT").count(), 1);
+    assert_eq!(operators.iter().filter(|&&operator| operator == "Tf").count(), 4);
+    assert_eq!(operators.iter().filter(|&&operator| operator == "Ts").count(), 2);
+    assert_eq!(operators.iter().filter(|&&operator| operator == "Tc").count(), 2);
+
+    // the underline is drawn after the text object
+    let end = operators.iter().position(|&operator| operator == "ET").unwrap();
+    assert!(operators[end..].contains(&"f"));
+}
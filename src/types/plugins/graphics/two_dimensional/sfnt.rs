@@ -0,0 +1,456 @@
+//! Writing OpenType / TrueType font files: standalone fonts from the faces of
+//! font collections and static instances of variable fonts
+
+use ttf_parser::{Face, GlyphId, OutlineBuilder, Tag};
+use PdfError;
+
+/// The tables of a font file, by tag
+pub(super) type Tables = Vec<([u8; 4], Vec<u8>)>;
+
+pub(super) fn read_u16(data: &[u8], offset: usize) -> Result<u16, PdfError> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(PdfError::FontFaceError)
+}
+
+pub(super) fn read_u32(data: &[u8], offset: usize) -> Result<u32, PdfError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(PdfError::FontFaceError)
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    if let Some(bytes) = data.get_mut(offset..offset + 2) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Sum of the table as big-endian 32-bit words (the last word is padded with zeros)
+fn checksum(table: &[u8]) -> u32 {
+    table.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+/// Writes a font file with the tables. The table directory is sorted by tag and
+/// the checksums (including `checkSumAdjustment` in the `head` table) are recomputed.
+pub(super) fn write_sfnt(sfnt_version: u32, mut tables: Tables) -> Vec<u8> {
+
+    tables.sort_by_key(|table| table.0);
+
+    let num_tables = tables.len() as u16;
+    let entry_selector = 15 - num_tables.max(1).leading_zeros() as u16;
+    let search_range: u16 = 16 << entry_selector;
+
+    let mut font = Vec::new();
+    font.extend_from_slice(&sfnt_version.to_be_bytes());
+    font.extend_from_slice(&num_tables.to_be_bytes());
+    font.extend_from_slice(&search_range.to_be_bytes());
+    font.extend_from_slice(&entry_selector.to_be_bytes());
+    font.extend_from_slice(&(num_tables * 16 - search_range).to_be_bytes());
+
+    let mut head_offset = None;
+    let mut offset = 12 + 16 * tables.len();
+    for &mut (tag, ref mut table) in tables.iter_mut() {
+        if &tag == b"head" && table.len() >= 12 {
+            // checkSumAdjustment is set after the whole font is written
+            table[8..12].copy_from_slice(&[0; 4]);
+            head_offset = Some(offset);
+        }
+
+        font.extend_from_slice(&tag);
+        font.extend_from_slice(&checksum(table).to_be_bytes());
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+
+        // tables start on four byte boundaries
+        offset += (table.len() + 3) & !3;
+    }
+
+    for (_, table) in &tables {
+        font.extend_from_slice(table);
+        while font.len() % 4 != 0 {
+            font.push(0);
+        }
+    }
+
+    if let Some(head_offset) = head_offset {
+        let adjustment = 0xB1B0_AFBA_u32.wrapping_sub(checksum(&font));
+        font[head_offset + 8..head_offset + 12].copy_from_slice(&adjustment.to_be_bytes());
+    }
+
+    font
+}
+
+/// Reads the sfnt version and the tables of the font that starts at `face_offset`
+/// (0 for a single font file, the offset of the face for a collection)
+pub(super) fn read_tables(data: &[u8], face_offset: usize) -> Result<(u32, Tables), PdfError> {
+
+    // offset table: sfnt version, number of tables, search range, entry selector, range shift
+    let sfnt_version = read_u32(data, face_offset)?;
+    let num_tables = usize::from(read_u16(data, face_offset + 4)?);
+
+    let mut tables = Vec::with_capacity(num_tables);
+    for i in 0..num_tables {
+        // table record: tag, checksum, offset, length
+        let record_offset = face_offset + 12 + 16 * i;
+        let tag = read_u32(data, record_offset)?.to_be_bytes();
+        let table_offset = read_u32(data, record_offset + 8)? as usize;
+        let table_length = read_u32(data, record_offset + 12)? as usize;
+        let table = data.get(table_offset..table_offset + table_length).ok_or(PdfError::FontFaceError)?;
+        tables.push((tag, table.to_vec()));
+    }
+
+    Ok((sfnt_version, tables))
+}
+
+/// Rebuilds Roboto after `patch` has changed its sfnt version and tables, for tests
+/// that need a font with other tables
+#[cfg(test)]
+pub(crate) fn patched_roboto<F>(patch: F) -> Vec<u8> where F: FnOnce(&mut u32, &mut Tables) {
+    let roboto = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let (mut sfnt_version, mut tables) = read_tables(roboto, 0).unwrap();
+    patch(&mut sfnt_version, &mut tables);
+    write_sfnt(sfnt_version, tables)
+}
+
+/// Copies the tables of one face of a font collection into a standalone font file
+/// (the table directory is rewritten, the tables themselves are not changed).
+/// Data that isn't a collection is returned unchanged (without copying) for face 0.
+pub(crate) fn extract_collection_face(data: Vec<u8>, face_index: u32) -> Result<Vec<u8>, PdfError> {
+
+    if data.get(0..4) != Some(b"ttcf") {
+        return if face_index == 0 { Ok(data) } else { Err(PdfError::FaceNotInCollection) };
+    }
+
+    if face_index >= read_u32(&data, 8)? {
+        return Err(PdfError::FaceNotInCollection);
+    }
+
+    let face_offset = read_u32(&data, 12 + 4 * face_index as usize)? as usize;
+    let (sfnt_version, tables) = read_tables(&data, face_offset)?;
+
+    Ok(write_sfnt(sfnt_version, tables))
+}
+
+/// Collects the outline of a glyph as TrueType contours (on- and off-curve points)
+#[derive(Debug, Default)]
+struct GlyfOutline {
+    contours: Vec<Vec<(i16, i16, bool)>>,
+    current: Vec<(i16, i16, bool)>,
+    last: (f32, f32),
+    /// Horizontal offset that is added to all points
+    x_offset: f32,
+}
+
+impl GlyfOutline {
+
+    fn push(&mut self, x: f32, y: f32, on_curve: bool) {
+        self.current.push(((x + self.x_offset).round() as i16, y.round() as i16, on_curve));
+        if on_curve {
+            self.last = (x, y);
+        }
+    }
+
+    fn finish_contour(&mut self) {
+        // contours are closed implicitly, the point that closes it is not needed
+        if self.current.len() > 1 && self.current.first() == self.current.last() {
+            self.current.pop();
+        }
+        if !self.current.is_empty() {
+            self.contours.push(::std::mem::take(&mut self.current));
+        }
+    }
+
+    /// Encodes the outline as a simple glyph without instructions. Returns the glyph data,
+    /// the minimum x (the left side bearing), the number of points and the number of contours
+    fn encode(mut self) -> Option<(Vec<u8>, i16, usize, usize)> {
+        self.finish_contour();
+
+        let points: Vec<(i16, i16, bool)> = self.contours.iter().flat_map(|contour| contour.iter().cloned()).collect();
+        let x_min = points.iter().map(|p| p.0).min()?;
+        let y_min = points.iter().map(|p| p.1).min()?;
+        let x_max = points.iter().map(|p| p.0).max()?;
+        let y_max = points.iter().map(|p| p.1).max()?;
+
+        let mut glyph = Vec::new();
+        for value in &[self.contours.len() as i16, x_min, y_min, x_max, y_max] {
+            glyph.extend_from_slice(&value.to_be_bytes());
+        }
+
+        let mut end_point = 0;
+        for contour in &self.contours {
+            end_point += contour.len();
+            glyph.extend_from_slice(&((end_point - 1) as u16).to_be_bytes());
+        }
+
+        // no instructions, the hinting of the variable font doesn't match the instance
+        glyph.extend_from_slice(&0u16.to_be_bytes());
+
+        // all coordinates are written as two-byte deltas, so only the on-curve bit is set
+        glyph.extend(points.iter().map(|&(_, _, on_curve)| on_curve as u8));
+        let mut previous = 0i16;
+        for &(x, _, _) in &points {
+            glyph.extend_from_slice(&x.wrapping_sub(previous).to_be_bytes());
+            previous = x;
+        }
+        let mut previous = 0i16;
+        for &(_, y, _) in &points {
+            glyph.extend_from_slice(&y.wrapping_sub(previous).to_be_bytes());
+            previous = y;
+        }
+
+        while glyph.len() % 4 != 0 {
+            glyph.push(0);
+        }
+
+        Some((glyph, x_min, points.len(), self.contours.len()))
+    }
+}
+
+impl OutlineBuilder for GlyfOutline {
+
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.finish_contour();
+        self.push(x, y, true);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.push(x, y, true);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        self.push(x1, y1, false);
+        self.push(x, y, true);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        // glyf outlines don't contain cubic curves, approximate them with one quadratic curve
+        let (x0, y0) = self.last;
+        self.push((3.0 * (x1 + x2) - x0 - x) / 4.0, (3.0 * (y1 + y2) - y0 - y) / 4.0, false);
+        self.push(x, y, true);
+    }
+
+    fn close(&mut self) {
+        self.finish_contour();
+    }
+}
+
+/// Returns the advance width of the glyph at the variation coordinates of the face.
+/// Without an `HVAR` table, the advance is the distance between the left and the right
+/// phantom point of the glyph in `gvar` (ttf-parser only applies the delta of the right one).
+pub(crate) fn glyph_hor_advance(face: &Face, glyph_id: GlyphId) -> Option<u16> {
+    if face.tables().hvar.is_none() {
+        if let Some(points) = face.glyph_phantom_points(glyph_id) {
+            let advance = face.tables().hmtx?.advance(glyph_id)?;
+            return Some((f32::from(advance) + points.right.x - points.left.x).round().max(0.0) as u16);
+        }
+    }
+    face.glyph_hor_advance(glyph_id)
+}
+
+/// Creates a static instance of a variable font with TrueType outlines at the variation
+/// coordinates of the `face`: the outlines (`gvar`) and advance widths (`HVAR`, or the
+/// phantom points in `gvar` if there is no `HVAR` table) of the glyphs in `glyph_ids`
+/// (sorted) are applied, all other glyphs are left empty.
+/// The variation tables and the hinting are not copied into the instance.
+///
+/// Only the tables that are needed for embedding the font in a PDF are written.
+pub(crate) fn instance_glyf_font(face: &Face, variations: &[(Tag, f32)], glyph_ids: &[u16]) -> Result<Vec<u8>, PdfError> {
+
+    let raw_table = |tag: &[u8; 4]| face.raw_face().table(Tag::from_bytes(tag)).map(|table| table.to_vec());
+    let required_table = |tag: &[u8; 4]| raw_table(tag).ok_or(PdfError::FontFaceError);
+
+    if raw_table(b"glyf").is_none() {
+        return Err(PdfError::FontFaceError);
+    }
+
+    let mut glyf = Vec::new();
+    let mut loca = Vec::new();
+    let mut hmtx = Vec::new();
+    let (mut max_points, mut max_contours, mut max_advance) = (0, 0, 0);
+
+    for glyph_id in 0..face.number_of_glyphs() {
+        loca.extend_from_slice(&(glyf.len() as u32).to_be_bytes());
+
+        // without HVAR, the advance is the distance between the left and the right phantom
+        // point, and the outline is moved so that the left phantom point stays at the origin
+        let phantom_points = match face.tables().hvar {
+            Some(_) => None,
+            None => face.glyph_phantom_points(GlyphId(glyph_id)),
+        };
+
+        let mut left_side_bearing = 0;
+        if glyph_ids.binary_search(&glyph_id).is_ok() {
+            let mut outline = GlyfOutline {
+                x_offset: phantom_points.map(|points| -points.left.x).unwrap_or(0.0),
+                ..GlyfOutline::default()
+            };
+            face.outline_glyph(GlyphId(glyph_id), &mut outline);
+            if let Some((glyph, x_min, points, contours)) = outline.encode() {
+                glyf.extend_from_slice(&glyph);
+                left_side_bearing = x_min;
+                max_points = max_points.max(points);
+                max_contours = max_contours.max(contours);
+            }
+        }
+
+        let advance = glyph_hor_advance(face, GlyphId(glyph_id)).unwrap_or(0);
+        max_advance = max_advance.max(advance);
+        hmtx.extend_from_slice(&advance.to_be_bytes());
+        hmtx.extend_from_slice(&left_side_bearing.to_be_bytes());
+    }
+    loca.extend_from_slice(&(glyf.len() as u32).to_be_bytes());
+
+    // long offsets in the loca table
+    let mut head = required_table(b"head")?;
+    write_u16(&mut head, 50, 1);
+
+    // every glyph has its own advance in the hmtx table
+    let mut hhea = required_table(b"hhea")?;
+    write_u16(&mut hhea, 10, max_advance);
+    write_u16(&mut hhea, 34, face.number_of_glyphs());
+
+    // maximum profile: no composite glyphs and no instructions
+    let mut maxp = required_table(b"maxp")?;
+    if maxp.len() >= 32 {
+        write_u16(&mut maxp, 6, max_points as u16);
+        write_u16(&mut maxp, 8, max_contours as u16);
+        for &offset in &[10, 12, 26, 28, 30] {
+            write_u16(&mut maxp, offset, 0);
+        }
+    }
+
+    let mut os2 = required_table(b"OS/2")?;
+    if let Some(&(_, weight)) = variations.iter().find(|&&(tag, _)| tag == Tag::from_bytes(b"wght")) {
+        write_u16(&mut os2, 4, weight.round().clamp(1.0, 1000.0) as u16);
+    }
+
+    let mut tables = vec![
+        (*b"glyf", glyf),
+        (*b"loca", loca),
+        (*b"hmtx", hmtx),
+        (*b"head", head),
+        (*b"hhea", hhea),
+        (*b"maxp", maxp),
+        (*b"OS/2", os2),
+    ];
+
+    for tag in &[b"cmap", b"name", b"post"] {
+        if let Some(table) = raw_table(tag) {
+            tables.push((**tag, table));
+        }
+    }
+
+    Ok(write_sfnt(0x0001_0000, tables))
+}
+
+#[test]
+fn test_extract_collection_face() {
+    // wrap Roboto in a collection with one face: the table offsets move by the 16 byte collection header
+    let font_bytes = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let mut collection = b"ttcf\x00\x01\x00\x00\x00\x00\x00\x01\x00\x00\x00\x10".to_vec();
+    collection.extend_from_slice(font_bytes);
+    let num_tables = usize::from(read_u16(font_bytes, 4).unwrap());
+    for i in 0..num_tables {
+        let offset = 16 + 12 + 16 * i + 8;
+        let table_offset = read_u32(&collection, offset).unwrap();
+        collection[offset..offset + 4].copy_from_slice(&(table_offset + 16).to_be_bytes());
+    }
+
+    let face_bytes = extract_collection_face(collection.clone(), 0).unwrap();
+    let face = Face::parse(&face_bytes, 0).unwrap();
+    assert_eq!(face.number_of_glyphs(), Face::parse(font_bytes, 0).unwrap().number_of_glyphs());
+    assert_eq!(ttf_parser::fonts_in_collection(&face_bytes), None);
+    assert_eq!(extract_collection_face(collection, 1), Err(PdfError::FaceNotInCollection));
+
+    // a single font is returned as it is
+    let single = font_bytes.to_vec();
+    let ptr = single.as_ptr();
+    assert_eq!(extract_collection_face(single, 0).map(|bytes| bytes.as_ptr()), Ok(ptr));
+}
+
+#[test]
+fn test_instance_glyf_font() {
+    // Roboto isn't variable, so the instance has to have the same outlines and advances
+    let face = Face::parse(include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf"), 0).unwrap();
+    let glyph_id = face.glyph_index('g').unwrap();
+    let instance_bytes = instance_glyf_font(&face, &[], &[0, glyph_id.0]).unwrap();
+    let instance = Face::parse(&instance_bytes, 0).unwrap();
+
+    assert_eq!(instance.glyph_bounding_box(glyph_id), face.glyph_bounding_box(glyph_id));
+    assert_eq!(instance.glyph_hor_advance(glyph_id), face.glyph_hor_advance(glyph_id));
+    assert_eq!(instance.glyph_index('a').and_then(|a| instance.glyph_bounding_box(a)), None);
+}
+
+#[test]
+fn test_instance_phantom_points() {
+    // make Roboto variable: a width axis that moves the left phantom point of "a" by 20 and
+    // the right one by 100 units (in gvar, without an HVAR table), so "a" gets 80 units wider
+    let roboto = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let face = Face::parse(roboto, 0).unwrap();
+    let glyph_id = face.glyph_index('a').unwrap();
+
+    // the number of points of "a": the last end point of its contours + 1
+    let table = |tag: &[u8; 4]| face.raw_face().table(Tag::from_bytes(tag)).unwrap();
+    let glyph_offset = match read_u16(table(b"head"), 50).unwrap() {
+        0 => 2 * usize::from(read_u16(table(b"loca"), 2 * usize::from(glyph_id.0)).unwrap()),
+        _ => read_u32(table(b"loca"), 4 * usize::from(glyph_id.0)).unwrap() as usize,
+    };
+    let contours = usize::from(read_u16(table(b"glyf"), glyph_offset).unwrap());
+    let points = read_u16(table(b"glyf"), glyph_offset + 10 + 2 * (contours - 1)).unwrap() + 1;
+
+    let mut fvar = Vec::new();
+    for value in &[1u16, 0, 16, 2, 1, 20, 0, 8] {
+        fvar.extend_from_slice(&value.to_be_bytes());
+    }
+    fvar.extend_from_slice(b"wdth");
+    for value in &[75u32 << 16, 100 << 16, 125 << 16, 256] {
+        fvar.extend_from_slice(&value.to_be_bytes());
+    }
+
+    let glyph_count = face.number_of_glyphs();
+    let data_offset = 20 + 2 * (u32::from(glyph_count) + 1);
+    let mut gvar = Vec::new();
+    for value in &[1u16, 0, 1, 0] {
+        gvar.extend_from_slice(&value.to_be_bytes());
+    }
+    gvar.extend_from_slice(&data_offset.to_be_bytes());
+    gvar.extend_from_slice(&glyph_count.to_be_bytes());
+    gvar.extend_from_slice(&0u16.to_be_bytes());
+    gvar.extend_from_slice(&data_offset.to_be_bytes());
+    // the variation data of "a" is 20 bytes long (offsets are divided by 2)
+    for glyph in 0..=glyph_count {
+        gvar.extend_from_slice(&(if glyph > glyph_id.0 { 10u16 } else { 0 }).to_be_bytes());
+    }
+    // one tuple with its own point numbers (the two phantom points) at the maximum width
+    for value in &[1u16, 10, 10, 0xA000, 0x4000] {
+        gvar.extend_from_slice(&value.to_be_bytes());
+    }
+    gvar.extend_from_slice(&[2, 0x81]);
+    gvar.extend_from_slice(&points.to_be_bytes());
+    gvar.extend_from_slice(&1u16.to_be_bytes());
+    gvar.extend_from_slice(&[0x01, 20, 100, 0x81]);
+
+    let variable_bytes = patched_roboto(|_, tables| {
+        tables.push((*b"fvar", fvar));
+        tables.push((*b"gvar", gvar));
+    });
+
+    let wdth = Tag::from_bytes(b"wdth");
+    let mut variable = Face::parse(&variable_bytes, 0).unwrap();
+    assert!(variable.tables().hvar.is_none());
+    variable.set_variation(wdth, 125.0).unwrap();
+
+    let advance = face.glyph_hor_advance(glyph_id).unwrap();
+    assert_eq!(variable.glyph_hor_advance(glyph_id), Some(advance + 100));
+    assert_eq!(glyph_hor_advance(&variable, glyph_id), Some(advance + 80));
+
+    // the outline is moved to the left, so that the left phantom point is at the origin
+    let instance_bytes = instance_glyf_font(&variable, &[(wdth, 125.0)], &[0, glyph_id.0]).unwrap();
+    let instance = Face::parse(&instance_bytes, 0).unwrap();
+    assert_eq!(instance.glyph_hor_advance(glyph_id), Some(advance + 80));
+    assert_eq!(instance.glyph_bounding_box(glyph_id).map(|bbox| bbox.x_min), face.glyph_bounding_box(glyph_id).map(|bbox| bbox.x_min - 20));
+}
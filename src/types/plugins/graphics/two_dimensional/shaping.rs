@@ -0,0 +1,464 @@
+//! OpenType text shaping (ligatures, contextual forms, mark positioning),
+//! needed for complex scripts such as Arabic, Hebrew, Devanagari or Thai
+
+use lopdf;
+use lopdf::content::Operation;
+use rustybuzz;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ops::Range;
+use unicode_bidi::{BidiInfo, Level};
+use unicode_bidi_mirroring;
+use utils::vertical_advance;
+use PdfError;
+
+/// Direction in which a run of text is written
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextDirection {
+    /// Text is written from left to right (Latin, Cyrillic, Devanagari, ...)
+    LeftToRight,
+    /// Text is written from right to left (Arabic, Hebrew, ...)
+    RightToLeft,
+}
+
+/// Options for shaping text with `PdfLayerReference::write_shaped_text`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapingOptions {
+    /// Base direction of the paragraph for the Unicode Bidirectional Algorithm.
+    /// If not set, the direction set with `PdfLayerReference::set_base_direction` is
+    /// used, or it is taken from the first character with a strong direction
+    pub base_direction: Option<TextDirection>,
+    /// Script of the text, as an ISO 15924 tag (for example `"Arab"` or `"Deva"`).
+    /// If not set (or not a valid tag), it is guessed from the text
+    pub script: Option<String>,
+    /// Language of the text, as a BCP 47 tag (for example `"ar"` or `"hi"`).
+    /// Some fonts use different glyphs depending on the language
+    pub language: Option<String>,
+    /// Whether to apply the kerning of the font, on by default
+    pub kerning: bool,
+    /// OpenType features to turn on or off, in addition to the default features
+    /// of the shaper (such as standard ligatures and contextual alternates)
+    pub features: FontFeatures,
+}
+
+impl Default for ShapingOptions {
+    fn default() -> Self {
+        Self {
+            base_direction: None,
+            script: None,
+            language: None,
+            kerning: true,
+            features: FontFeatures::new(),
+        }
+    }
+}
+
+impl ShapingOptions {
+    /// Creates the default shaping options (everything guessed from the text, with kerning)
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// A set of OpenType features, for example small caps (`smcp`), tabular figures (`tnum`),
+/// oldstyle figures (`onum`), discretionary ligatures (`dlig`) or stylistic sets (`ss01`).
+/// The features are applied with the GSUB / GPOS lookups of the font, features that the
+/// font doesn't have are ignored.
+///
+/// ```rust,ignore
+/// let features = FontFeatures::new().with_feature("smcp").without_feature("liga");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FontFeatures {
+    /// Four letter feature tags and their values (0 = off, 1 = on, higher values select alternates)
+    features: Vec<(String, u32)>,
+}
+
+impl FontFeatures {
+
+    /// Creates an empty set of features (only the default features of the shaper are applied)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turns the feature on
+    pub fn with_feature(self, tag: &str) -> Self {
+        self.with_feature_value(tag, 1)
+    }
+
+    /// Turns the feature off, for example `liga` to write the text without standard ligatures
+    pub fn without_feature(self, tag: &str) -> Self {
+        self.with_feature_value(tag, 0)
+    }
+
+    /// Sets the value of the feature. For features such as `salt` or `cv01`, the value
+    /// selects one of the alternate glyphs (starting at 1)
+    pub fn with_feature_value(mut self, tag: &str, value: u32) -> Self {
+        self.features.retain(|(t, _)| t != tag);
+        self.features.push((tag.to_string(), value));
+        self
+    }
+
+    /// Returns if no features are set
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// Returns the features for the shaper, applied to the whole text
+    fn to_rustybuzz(&self) -> Vec<rustybuzz::Feature> {
+        use rustybuzz::ttf_parser::Tag;
+        self.features.iter()
+            .map(|(tag, value)| rustybuzz::Feature::new(Tag::from_bytes_lossy(tag.as_bytes()), *value, ..))
+            .collect()
+    }
+}
+
+/// A glyph as it was placed by the shaper. All values are in font units. In vertical
+/// writing, the "x" values are measured downwards along the line.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct ShapedGlyph {
+    /// Glyph ID in the font
+    pub(crate) glyph_id: u16,
+    /// Byte offset of the first character in the text that this glyph belongs to
+    pub(crate) cluster: usize,
+    /// The advance of the glyph in the `hmtx` table (`vmtx` in vertical writing),
+    /// which is also the width of the glyph in the PDF font
+    pub(crate) advance: i32,
+    /// How far the pen has to move after this glyph
+    pub(crate) x_advance: i32,
+    /// How far the glyph is moved horizontally, without affecting the pen
+    pub(crate) x_offset: i32,
+    /// How far the glyph is moved vertically, without affecting the pen
+    pub(crate) y_offset: i32,
+    /// Whether the glyph is part of a right-to-left run
+    pub(crate) right_to_left: bool,
+}
+
+/// Glyphs of a shaped text, in visual order
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ShapedText {
+    pub(crate) glyphs: Vec<ShapedGlyph>,
+    pub(crate) units_per_em: u16,
+    /// Whether the text is written from top to bottom
+    pub(crate) vertical: bool,
+    /// How much wider each glyph is in the PDF font than in the font itself, in
+    /// thousandths of an em (the stroke of synthetic bold fonts)
+    pub(crate) extra_advance: i64,
+}
+
+impl ShapedText {
+
+    /// Shapes the text with the GSUB / GPOS tables of the font. The text is split
+    /// into runs of one direction first (see `bidi_runs`), each run is shaped on
+    /// its own. Fails if the font can't be parsed or if the shaper returns a glyph
+    /// ID that doesn't fit into a two-byte CID.
+    ///
+    /// If `vertical` is set, the whole text is shaped top to bottom as one run
+    /// (which applies the `vert` feature) and the glyphs advance by their vertical
+    /// metrics. Glyph offsets are not supported in vertical writing.
+    pub(crate) fn shape(face: &rustybuzz::Face, text: &str, options: &ShapingOptions, vertical: bool)
+    -> Result<Self, PdfError>
+    {
+        use rustybuzz::ttf_parser::{GlyphId, Tag};
+        use rustybuzz::{Direction, Feature, Language, Script, UnicodeBuffer};
+
+        let script = options.script.as_ref().and_then(|s| Script::from_iso15924_tag(Tag::from_bytes_lossy(s.as_bytes())));
+        let language = options.language.as_ref().and_then(|l| l.parse::<Language>().ok());
+
+        let mut features = Vec::new();
+        if !options.kerning {
+            features.push(Feature::new(Tag::from_bytes(b"kern"), 0, ..));
+        }
+        features.extend(options.features.to_rustybuzz());
+
+        let mut glyphs = Vec::new();
+
+        let runs = if vertical {
+            vec![(0..text.len(), TextDirection::LeftToRight)]
+        } else {
+            bidi_runs(text, options.base_direction)
+        };
+
+        for (range, direction) in runs {
+
+            let mut buffer = UnicodeBuffer::new();
+            buffer.push_str(&text[range.clone()]);
+            buffer.set_direction(match direction {
+                _ if vertical => Direction::TopToBottom,
+                TextDirection::LeftToRight => Direction::LeftToRight,
+                TextDirection::RightToLeft => Direction::RightToLeft,
+            });
+            if let Some(script) = script {
+                buffer.set_script(script);
+            }
+            if let Some(ref language) = language {
+                buffer.set_language(language.clone());
+            }
+
+            let output = rustybuzz::shape(face, &features, buffer);
+
+            for (info, pos) in output.glyph_infos().iter().zip(output.glyph_positions()) {
+                let glyph_id = u16::try_from(info.glyph_id).map_err(|_| PdfError::GlyphIdOutOfRange(info.glyph_id))?;
+                let glyph = if vertical {
+                    ShapedGlyph {
+                        glyph_id,
+                        cluster: range.start + info.cluster as usize,
+                        // same advance as in the W2 array of the font
+                        advance: i32::from(vertical_advance(face, glyph_id)),
+                        // the pen moves downwards, so the y advance is negative
+                        x_advance: -pos.y_advance,
+                        x_offset: 0,
+                        y_offset: 0,
+                        right_to_left: false,
+                    }
+                } else {
+                    ShapedGlyph {
+                        glyph_id,
+                        // clusters are relative to the run
+                        cluster: range.start + info.cluster as usize,
+                        advance: i32::from(face.glyph_hor_advance(GlyphId(glyph_id)).unwrap_or(0)),
+                        x_advance: pos.x_advance,
+                        x_offset: pos.x_offset,
+                        y_offset: pos.y_offset,
+                        right_to_left: direction == TextDirection::RightToLeft,
+                    }
+                };
+                glyphs.push(glyph);
+            }
+        }
+
+        Ok(Self {
+            glyphs,
+            units_per_em: face.units_per_em() as u16,
+            vertical,
+            extra_advance: 0,
+        })
+    }
+
+    /// Returns the glyph IDs, in the order they are written to the PDF
+    pub(crate) fn glyph_ids(&self) -> Vec<u16> {
+        self.glyphs.iter().map(|g| g.glyph_id).collect()
+    }
+
+    /// Returns the text that each glyph represents, for the ToUnicode map.
+    ///
+    /// All characters of a cluster are assigned to the first glyph of the cluster
+    /// (in logical order), the other glyphs of the cluster don't represent any text.
+    pub(crate) fn glyph_texts(&self, text: &str) -> Vec<(u16, Option<String>)> {
+
+        let mut cluster_starts: Vec<usize> = self.glyphs.iter().map(|g| g.cluster).collect();
+        cluster_starts.sort();
+        cluster_starts.dedup();
+
+        let cluster_text = |cluster: usize| -> String {
+            let end = cluster_starts.iter().cloned().find(|&start| start > cluster).unwrap_or(text.len());
+            text.get(cluster..end).unwrap_or_default().to_string()
+        };
+
+        // glyphs of right-to-left runs are in visual order, so the logically
+        // first glyph of a cluster is the last one of the cluster in the run
+        let mut first_glyph_of_cluster = HashMap::new();
+        for (i, glyph) in self.glyphs.iter().enumerate() {
+            if glyph.right_to_left || !first_glyph_of_cluster.contains_key(&glyph.cluster) {
+                first_glyph_of_cluster.insert(glyph.cluster, i);
+            }
+        }
+
+        self.glyphs.iter().enumerate().map(|(i, glyph)| {
+            let glyph_text = if first_glyph_of_cluster.get(&glyph.cluster) == Some(&i) {
+                cluster_text(glyph.cluster)
+            } else {
+                String::new()
+            };
+            (glyph.glyph_id, Some(glyph_text))
+        }).collect()
+    }
+
+    /// Converts the shaped glyphs into text showing operations (`TJ`, or `Tj` if
+    /// the glyphs don't need any positioning). Vertical offsets are written as a
+    /// text rise (`Ts`) relative to `text_rise`, which needs the font size.
+    ///
+    /// `codes` are the glyphs encoded for the font, `bytes_per_glyph` bytes per glyph
+    /// (see `FontList::encode_glyph_ids`).
+    pub(crate) fn to_operations(&self, codes: &[u8], bytes_per_glyph: usize, font_size: f64, text_rise: f64) -> Vec<Operation> {
+        use lopdf::Object::*;
+        use lopdf::StringFormat::Hexadecimal;
+
+        fn push_text_operation(operations: &mut Vec<Operation>, list: &mut Vec<lopdf::Object>) {
+            match list.len() {
+                0 => { },
+                1 if list[0].as_str().is_ok() => operations.push(Operation::new("Tj", vec![list.remove(0)])),
+                _ => operations.push(Operation::new("TJ", vec![Array(::std::mem::take(list))])),
+            }
+        }
+
+        // PDF positions are in thousandths of an em
+        let scale = 1000.0 / f64::from(self.units_per_em);
+
+        let mut operations = Vec::new();
+        let mut list = Vec::new();
+        let mut current_bytes = Vec::<u8>::new();
+
+        // where the pen of the PDF reader is after the last glyph
+        let mut pdf_position: i64 = 0;
+        // where the shaper placed the pen
+        let mut position = 0.0;
+        let mut current_y_offset = 0;
+
+        for (glyph, code) in self.glyphs.iter().zip(codes.chunks(bytes_per_glyph)) {
+
+            if glyph.y_offset != current_y_offset {
+                if !current_bytes.is_empty() {
+                    list.push(String(::std::mem::take(&mut current_bytes), Hexadecimal));
+                }
+                push_text_operation(&mut operations, &mut list);
+                let rise = text_rise + f64::from(glyph.y_offset) * font_size / f64::from(self.units_per_em);
+                operations.push(Operation::new("Ts", vec![Real(rise)]));
+                current_y_offset = glyph.y_offset;
+            }
+
+            let target = (position + f64::from(glyph.x_offset) * scale).round() as i64;
+            let adjustment = target - pdf_position;
+            if adjustment != 0 {
+                if !current_bytes.is_empty() {
+                    list.push(String(::std::mem::take(&mut current_bytes), Hexadecimal));
+                }
+                // positive numbers in a TJ array move the next glyph to the left
+                // (or downwards, in vertical writing)
+                list.push(Integer(if self.vertical { adjustment } else { -adjustment }));
+            }
+
+            current_bytes.extend_from_slice(code);
+
+            // the glyph widths in the PDF font are truncated, only the difference
+            // between the shaped advance and the font advance needs an adjustment.
+            // Glyphs that don't advance (marks) stay on their base glyph, even if
+            // the PDF font makes them wider
+            let pdf_width = (f64::from(glyph.advance) * scale) as i64 + self.extra_advance;
+            pdf_position = target + pdf_width;
+            position += pdf_width as f64 + f64::from(glyph.x_advance - glyph.advance) * scale;
+            if glyph.x_advance == 0 {
+                position -= self.extra_advance as f64;
+            }
+        }
+
+        if !current_bytes.is_empty() {
+            list.push(String(current_bytes, Hexadecimal));
+        }
+
+        // move the pen to the end of the shaped text, so that text written
+        // afterwards continues at the right position
+        let adjustment = position.round() as i64 - pdf_position;
+        if adjustment != 0 && !list.is_empty() {
+            list.push(Integer(if self.vertical { adjustment } else { -adjustment }));
+        }
+
+        push_text_operation(&mut operations, &mut list);
+
+        if current_y_offset != 0 {
+            operations.push(Operation::new("Ts", vec![Real(text_rise)]));
+        }
+
+        operations
+    }
+}
+
+/// Splits the text into runs of one direction with the Unicode Bidirectional
+/// Algorithm. The runs are returned in visual order (from left to right).
+///
+/// If `base_direction` is not set, the direction of each paragraph is taken from
+/// its first character with a strong direction.
+pub(crate) fn bidi_runs(text: &str, base_direction: Option<TextDirection>)
+-> Vec<(Range<usize>, TextDirection)>
+{
+    let base_level = base_direction.map(|direction| match direction {
+        TextDirection::LeftToRight => Level::ltr(),
+        TextDirection::RightToLeft => Level::rtl(),
+    });
+
+    let bidi_info = BidiInfo::new(text, base_level);
+
+    if !bidi_info.has_rtl() {
+        return vec![(0..text.len(), TextDirection::LeftToRight)];
+    }
+
+    let mut runs = Vec::new();
+    for paragraph in &bidi_info.paragraphs {
+        let (levels, level_runs) = bidi_info.visual_runs(paragraph, paragraph.range.clone());
+        for run in level_runs {
+            let direction = if levels[run.start].is_rtl() {
+                TextDirection::RightToLeft
+            } else {
+                TextDirection::LeftToRight
+            };
+            runs.push((run, direction));
+        }
+    }
+    runs
+}
+
+/// Returns the characters of the text in visual order, with the characters of
+/// right-to-left runs reversed and mirrored (so that `(` becomes `)`). Returns
+/// `None` if the text doesn't contain any right-to-left runs.
+pub(crate) fn visual_text(text: &str, base_direction: Option<TextDirection>) -> Option<String> {
+
+    let runs = bidi_runs(text, base_direction);
+
+    if runs.iter().all(|&(_, direction)| direction == TextDirection::LeftToRight) {
+        return None;
+    }
+
+    let mut visual = String::with_capacity(text.len());
+    for (range, direction) in runs {
+        match direction {
+            TextDirection::LeftToRight => visual.push_str(&text[range]),
+            TextDirection::RightToLeft => visual.extend(text[range].chars().rev().map(|c| {
+                unicode_bidi_mirroring::get_mirrored(c).unwrap_or(c)
+            })),
+        }
+    }
+    Some(visual)
+}
+
+/// Encodes the text as a PDF text string (UTF-16BE with byte order mark),
+/// as used for `/ActualText`
+pub(crate) fn encode_text_string(text: &str) -> Vec<u8> {
+    let mut bytes = vec![0xFE, 0xFF];
+    for unit in text.encode_utf16() {
+        bytes.extend_from_slice(&unit.to_be_bytes());
+    }
+    bytes
+}
+
+#[test]
+fn test_shaped_text_ligature() {
+    // Roboto has an "fi" ligature, which has to map back to both characters
+    let face = rustybuzz::Face::from_slice(include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf"), 0).unwrap();
+    let shaped = ShapedText::shape(&face, "fit", &ShapingOptions::new(), false).unwrap();
+    assert_eq!(shaped.glyphs.len(), 2);
+    let texts = shaped.glyph_texts("fit");
+    assert_eq!(texts[0].1, Some("fi".to_string()));
+    assert_eq!(texts[1].1, Some("t".to_string()));
+}
+
+#[test]
+fn test_visual_text() {
+    // the Hebrew word is reversed, the surrounding German text is not
+    assert_eq!(visual_text("Herr \u{5E9}\u{5DC}\u{5D5}\u{5DD} kommt", None),
+               Some("Herr \u{5DD}\u{5D5}\u{5DC}\u{5E9} kommt".to_string()));
+    assert_eq!(visual_text("Herr Meier (kommt)", None), None);
+
+    // the first strong character decides the direction, unless it is set
+    assert_eq!(visual_text("(ab) \u{5D0}\u{5D1}", None), Some("(ab) \u{5D1}\u{5D0}".to_string()));
+    assert_eq!(visual_text("(ab) \u{5D0}\u{5D1}", Some(TextDirection::RightToLeft)),
+               Some("\u{5D1}\u{5D0} (ab)".to_string()));
+}
+
+#[test]
+fn test_font_features() {
+    // turning off "liga" writes "fi" as two glyphs
+    let face = rustybuzz::Face::from_slice(include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf"), 0).unwrap();
+    let options = ShapingOptions { features: FontFeatures::new().without_feature("liga"), ..ShapingOptions::new() };
+    let shaped = ShapedText::shape(&face, "fit", &options, false).unwrap();
+    assert_eq!(shaped.glyphs.len(), 3);
+}
@@ -0,0 +1,154 @@
+//! Reading PostScript Type1 font programs from PFB (binary) and PFA (ASCII) files.
+//! The program is embedded as `FontFile`, which needs the lengths of its three parts.
+
+use PdfError;
+
+/// A Type1 font program: the clear text part, the encrypted part (in binary form)
+/// and the fixed part with the zeros and `cleartomark`
+#[derive(Debug, Clone)]
+pub(crate) struct Type1Program {
+    /// The three parts of the program, one after another
+    pub(crate) data: Vec<u8>,
+    pub(crate) length1: usize,
+    pub(crate) length2: usize,
+    pub(crate) length3: usize,
+}
+
+impl Type1Program {
+
+    /// Parses a PFB or PFA file, fails with `PdfError::FontFaceError` if it is
+    /// neither of them
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, PdfError> {
+        match data.first() {
+            Some(0x80) => Self::parse_pfb(data),
+            Some(b'%') => Self::parse_pfa(data),
+            _ => Err(PdfError::FontFaceError),
+        }
+    }
+
+    /// A PFB file consists of segments with a six byte header: 0x80, the type of the
+    /// segment (1: ASCII, 2: binary, 3: end of file) and the length (little endian)
+    fn parse_pfb(data: &[u8]) -> Result<Self, PdfError> {
+
+        const ASCII: u8 = 1;
+        const BINARY: u8 = 2;
+        const END_OF_FILE: u8 = 3;
+
+        let mut parts = [Vec::new(), Vec::new(), Vec::new()];
+        let mut offset = 0;
+
+        // some files end without the end of file segment
+        while offset < data.len() {
+            let segment_type = match data.get(offset..offset + 2) {
+                Some(&[0x80, END_OF_FILE]) => break,
+                Some(&[0x80, segment_type]) => segment_type,
+                _ => return Err(PdfError::FontFaceError),
+            };
+            let length = data.get(offset + 2..offset + 6).ok_or(PdfError::FontFaceError)?;
+            let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+            let segment = data.get(offset + 6..offset + 6 + length).ok_or(PdfError::FontFaceError)?;
+
+            // the clear text comes before the binary segments, the fixed part after them
+            match segment_type {
+                ASCII if parts[1].is_empty() => parts[0].extend_from_slice(segment),
+                ASCII => parts[2].extend_from_slice(segment),
+                BINARY if parts[2].is_empty() => parts[1].extend_from_slice(segment),
+                _ => return Err(PdfError::FontFaceError),
+            }
+            offset += 6 + length;
+        }
+
+        Self::from_parts(parts)
+    }
+
+    /// A PFA file is plain text: the encrypted part follows `eexec` in hexadecimal
+    /// form and is followed by 512 zeros and `cleartomark`
+    fn parse_pfa(data: &[u8]) -> Result<Self, PdfError> {
+
+        let is_whitespace = |b: &u8| b" \t\r\n".contains(b);
+
+        let eexec = find(data, b"eexec").ok_or(PdfError::FontFaceError)?;
+        let mut encrypted_start = eexec + b"eexec".len();
+        while data.get(encrypted_start).map(is_whitespace).unwrap_or(false) {
+            encrypted_start += 1;
+        }
+
+        // the fixed part starts with the last 512 zeros before `cleartomark`
+        let cleartomark = rfind(data, b"cleartomark").filter(|&position| position > encrypted_start).ok_or(PdfError::FontFaceError)?;
+        let mut fixed_start = cleartomark;
+        let mut zeros = 0;
+        while zeros < 512 && fixed_start > encrypted_start {
+            match data[fixed_start - 1] {
+                b'0' => zeros += 1,
+                ref b if is_whitespace(b) => { },
+                _ => break,
+            }
+            fixed_start -= 1;
+        }
+
+        let encrypted = &data[encrypted_start..fixed_start];
+        let is_hex = encrypted.iter().take(4).all(|b| b.is_ascii_hexdigit());
+        let encrypted = if is_hex { decode_hex(encrypted)? } else { encrypted.to_vec() };
+
+        Self::from_parts([data[..encrypted_start].to_vec(), encrypted, data[fixed_start..].to_vec()])
+    }
+
+    fn from_parts(parts: [Vec<u8>; 3]) -> Result<Self, PdfError> {
+        if parts[0].is_empty() || parts[1].is_empty() {
+            return Err(PdfError::FontFaceError);
+        }
+        let [part1, part2, part3] = parts;
+        Ok(Self {
+            length1: part1.len(),
+            length2: part2.len(),
+            length3: part3.len(),
+            data: [part1, part2, part3].concat(),
+        })
+    }
+
+    /// Returns the name of the font from the `/FontName` entry in the clear text part
+    pub(crate) fn font_name(&self) -> Option<String> {
+        let clear_text = String::from_utf8_lossy(&self.data[..self.length1]);
+        let after_key = &clear_text[clear_text.find("/FontName")? + "/FontName".len()..];
+        let name = after_key.trim_start().strip_prefix('/')?;
+        let name: String = name.chars().take_while(|c| !c.is_whitespace() && !"[](){}<>/%".contains(*c)).collect();
+        if name.is_empty() { None } else { Some(name) }
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|window| window == needle)
+}
+
+fn rfind(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).rposition(|window| window == needle)
+}
+
+/// Decodes hexadecimal data, whitespace is ignored
+fn decode_hex(data: &[u8]) -> Result<Vec<u8>, PdfError> {
+    let digits: Vec<u8> = data.iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .map(|&b| (b as char).to_digit(16).map(|digit| digit as u8).ok_or(PdfError::FontFaceError))
+        .collect::<Result<_, _>>()?;
+    Ok(digits.chunks(2).map(|pair| (pair[0] << 4) | pair.get(1).cloned().unwrap_or(0)).collect())
+}
+
+#[test]
+fn test_parse_type1_program() {
+    let clear_text = b"%!PS-AdobeFont-1.0: Test 001.000\n/FontName /Test-Regular def\ncurrentfile eexec\n".to_vec();
+    let fixed = [&[b'0'; 64][..], b"\n"].concat().repeat(8);
+    let fixed = [&fixed[..], b"cleartomark\n"].concat();
+
+    let pfa = [&clear_text[..], b"D9D66F633B846A\n", &fixed].concat();
+    let program = Type1Program::parse(&pfa).unwrap();
+    assert_eq!(program.length1, clear_text.len());
+    assert_eq!(&program.data[program.length1..program.length1 + program.length2], &[0xD9, 0xD6, 0x6F, 0x63, 0x3B, 0x84, 0x6A]);
+    assert_eq!(program.length3, fixed.len());
+    assert_eq!(program.font_name(), Some("Test-Regular".to_string()));
+
+    let segment = |segment_type: u8, data: &[u8]| [&[0x80, segment_type][..], &(data.len() as u32).to_le_bytes(), data].concat();
+    let binary = [0xD9, 0xD6, 0x6F, 0x63];
+    let pfb = [segment(1, &clear_text), segment(2, &binary), segment(1, &fixed), vec![0x80, 3]].concat();
+    let program = Type1Program::parse(&pfb).unwrap();
+    assert_eq!((program.length1, program.length2, program.length3), (clear_text.len(), 4, fixed.len()));
+}
@@ -0,0 +1,624 @@
+//! Decoding WOFF (zlib) and WOFF2 (Brotli) web fonts into the OpenType / TrueType
+//! font files that they contain, see https://www.w3.org/TR/WOFF/ and https://www.w3.org/TR/WOFF2/
+
+use std::io::Read;
+use brotli_decompressor::Decompressor;
+use flate2::read::ZlibDecoder;
+use super::sfnt::{read_u16, read_u32, write_sfnt};
+use PdfError;
+
+/// Tags of the tables that WOFF2 refers to by their index in this list
+const KNOWN_TAGS: [&[u8; 4]; 63] = [
+    b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post",
+    b"cvt ", b"fpgm", b"glyf", b"loca", b"prep", b"CFF ", b"VORG", b"EBDT",
+    b"EBLC", b"gasp", b"hdmx", b"kern", b"LTSH", b"PCLT", b"VDMX", b"vhea",
+    b"vmtx", b"BASE", b"GDEF", b"GPOS", b"GSUB", b"EBSC", b"JSTF", b"MATH",
+    b"CBDT", b"CBLC", b"COLR", b"CPAL", b"SVG ", b"sbix", b"acnt", b"avar",
+    b"bdat", b"bloc", b"bsln", b"cvar", b"fdsc", b"feat", b"fmtx", b"fvar",
+    b"gvar", b"hsty", b"just", b"lcar", b"mort", b"morx", b"opbd", b"prop",
+    b"trak", b"Zapf", b"Silf", b"Glat", b"Gloc", b"Feat", b"Sill",
+];
+
+/// Reads the data types of WOFF2 one after another
+struct Stream<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Stream<'a> {
+
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], PdfError> {
+        let bytes = self.data.get(self.offset..self.offset + length).ok_or(PdfError::FontFaceError)?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PdfError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PdfError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn i16(&mut self) -> Result<i16, PdfError> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn u32(&mut self) -> Result<u32, PdfError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// `UIntBase128`: up to five bytes with seven bits each, most significant first
+    fn base128(&mut self) -> Result<u32, PdfError> {
+        let mut value: u32 = 0;
+        for i in 0..5 {
+            let byte = self.u8()?;
+            // no leading zeros and no overflow
+            if (i == 0 && byte == 0x80) || value & 0xFE00_0000 != 0 {
+                return Err(PdfError::FontFaceError);
+            }
+            value = (value << 7) | u32::from(byte & 0x7F);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(PdfError::FontFaceError)
+    }
+
+    /// `255UInt16`: one byte for small values, with three prefixes for larger ones
+    fn u255_16(&mut self) -> Result<u16, PdfError> {
+        const ONE_MORE_BYTE_CODE_2: u8 = 254;
+        const ONE_MORE_BYTE_CODE_1: u8 = 255;
+        const WORD_CODE: u8 = 253;
+        const LOWEST_U_CODE: u16 = 253;
+
+        match self.u8()? {
+            WORD_CODE => self.u16(),
+            ONE_MORE_BYTE_CODE_1 => Ok(u16::from(self.u8()?) + LOWEST_U_CODE),
+            ONE_MORE_BYTE_CODE_2 => Ok(u16::from(self.u8()?) + LOWEST_U_CODE * 2),
+            code => Ok(u16::from(code)),
+        }
+    }
+}
+
+/// Decodes WOFF and WOFF2 data into a font file. Other data (which should already
+/// be a font file) is returned unchanged.
+pub(crate) fn decode_web_font(data: Vec<u8>) -> Result<Vec<u8>, PdfError> {
+    match data.get(0..4) {
+        Some(b"wOFF") => decode_woff(&data),
+        Some(b"wOF2") => decode_woff2(&data),
+        _ => Ok(data),
+    }
+}
+
+/// WOFF stores the tables of the font one by one, each compressed with zlib
+/// unless compression didn't make it smaller
+fn decode_woff(data: &[u8]) -> Result<Vec<u8>, PdfError> {
+
+    let flavor = read_u32(data, 4)?;
+    let num_tables = usize::from(read_u16(data, 12)?);
+
+    let mut tables = Vec::with_capacity(num_tables);
+    for i in 0..num_tables {
+        // table directory entry: tag, offset, compressed length, original length, original checksum
+        let entry = 44 + 20 * i;
+        let tag = read_u32(data, entry)?.to_be_bytes();
+        let offset = read_u32(data, entry + 4)? as usize;
+        let compressed_length = read_u32(data, entry + 8)? as usize;
+        let original_length = read_u32(data, entry + 12)? as usize;
+        let table = data.get(offset..offset + compressed_length).ok_or(PdfError::FontFaceError)?;
+
+        let table = if compressed_length < original_length {
+            // the length is only checked after decompression, `take` limits the output
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(table).take(original_length as u64).read_to_end(&mut decompressed).map_err(|_| PdfError::FontFaceError)?;
+            decompressed
+        } else {
+            table.to_vec()
+        };
+
+        if table.len() != original_length {
+            return Err(PdfError::FontFaceError);
+        }
+        tables.push((tag, table));
+    }
+
+    Ok(write_sfnt(flavor, tables))
+}
+
+/// WOFF2 compresses all tables together with Brotli. The `glyf` and `loca` tables
+/// (and optionally `hmtx`) are transformed into a more compact form before, which
+/// has to be reversed. Font collections are not supported.
+fn decode_woff2(data: &[u8]) -> Result<Vec<u8>, PdfError> {
+
+    let flavor = read_u32(data, 4)?;
+    if &flavor.to_be_bytes() == b"ttcf" {
+        return Err(PdfError::FontFaceError);
+    }
+
+    let num_tables = read_u16(data, 12)?;
+    let total_compressed_size = read_u32(data, 20)? as usize;
+
+    // table directory: flags (index of a known tag and the transformation), the tag
+    // itself if it isn't known, the original length and the length after the transformation
+    let mut directory = Stream::new(data);
+    directory.offset = 48;
+    let mut entries = Vec::with_capacity(usize::from(num_tables));
+    for _ in 0..num_tables {
+        let flags = directory.u8()?;
+        let tag = match flags & 0x3F {
+            63 => directory.u32()?.to_be_bytes(),
+            index => *KNOWN_TAGS[usize::from(index)],
+        };
+        let transform_version = flags >> 6;
+        let original_length = directory.base128()? as usize;
+        // version 0 is the glyf / loca transformation, version 3 means "not transformed"
+        let transformed = match &tag {
+            b"glyf" | b"loca" => transform_version == 0,
+            _ => transform_version != 0,
+        };
+        let length = if transformed { directory.base128()? as usize } else { original_length };
+        entries.push((tag, transformed, length));
+    }
+
+    // the decompressed data is exactly the tables one after another, anything more
+    // isn't read (so that a small file can't decompress into gigabytes)
+    let total_length = entries.iter().try_fold(0usize, |total, &(_, _, length)| total.checked_add(length)).ok_or(PdfError::FontFaceError)?;
+    let compressed = directory.bytes(total_compressed_size)?;
+    let mut decompressed = Vec::new();
+    Decompressor::new(compressed, 4096).take(total_length as u64 + 1).read_to_end(&mut decompressed).map_err(|_| PdfError::FontFaceError)?;
+    if decompressed.len() != total_length {
+        return Err(PdfError::FontFaceError);
+    }
+
+    let mut table_data = Stream::new(&decompressed);
+    let mut tables = Vec::with_capacity(entries.len());
+    for &(tag, transformed, length) in &entries {
+        tables.push((tag, transformed, table_data.bytes(length)?.to_vec()));
+    }
+
+    let find_table = |tables: &[([u8; 4], bool, Vec<u8>)], tag: &[u8; 4]| {
+        tables.iter().position(|&(table_tag, _, _)| &table_tag == tag)
+    };
+
+    // the loca table is rebuilt from the glyf table, its own data is empty
+    let mut x_mins = None;
+    if let Some(glyf) = find_table(&tables, b"glyf").filter(|&glyf| tables[glyf].1) {
+        let reconstructed = reconstruct_glyf(&tables[glyf].2)?;
+        tables[glyf] = (*b"glyf", false, reconstructed.glyf);
+        match find_table(&tables, b"loca") {
+            Some(loca) => tables[loca] = (*b"loca", false, reconstructed.loca),
+            None => return Err(PdfError::FontFaceError),
+        }
+        x_mins = Some(reconstructed.x_mins);
+    }
+
+    if let Some(hmtx) = find_table(&tables, b"hmtx").filter(|&hmtx| tables[hmtx].1) {
+        // the left side bearings that were left out are the x minimum of the glyphs
+        let x_mins = x_mins.as_ref().ok_or(PdfError::FontFaceError)?;
+        let hhea = find_table(&tables, b"hhea").ok_or(PdfError::FontFaceError)?;
+        let num_h_metrics = usize::from(read_u16(&tables[hhea].2, 34)?);
+        tables[hmtx] = (*b"hmtx", false, reconstruct_hmtx(&tables[hmtx].2, num_h_metrics, x_mins)?);
+    }
+
+    Ok(write_sfnt(flavor, tables.into_iter().map(|(tag, _, table)| (tag, table)).collect()))
+}
+
+/// The `glyf` and `loca` tables rebuilt from a transformed `glyf` table
+struct ReconstructedGlyf {
+    glyf: Vec<u8>,
+    loca: Vec<u8>,
+    /// x minimum of each glyph, for the `hmtx` transformation
+    x_mins: Vec<i16>,
+}
+
+/// Rebuilds the `glyf` and `loca` tables from the transformed `glyf` table, which
+/// splits the glyphs into separate streams (contour counts, point counts, flags,
+/// coordinates, composite glyphs, bounding boxes and instructions)
+fn reconstruct_glyf(table: &[u8]) -> Result<ReconstructedGlyf, PdfError> {
+
+    const ARG_1_AND_2_ARE_WORDS: u16 = 0x0001;
+    const WE_HAVE_A_SCALE: u16 = 0x0008;
+    const MORE_COMPONENTS: u16 = 0x0020;
+    const WE_HAVE_AN_X_AND_Y_SCALE: u16 = 0x0040;
+    const WE_HAVE_A_TWO_BY_TWO: u16 = 0x0080;
+    const WE_HAVE_INSTRUCTIONS: u16 = 0x0100;
+
+    let mut header = Stream::new(table);
+    let _reserved = header.u16()?;
+    let option_flags = header.u16()?;
+    let num_glyphs = usize::from(header.u16()?);
+    let index_format = header.u16()?;
+
+    let mut stream_sizes = [0; 7];
+    for size in stream_sizes.iter_mut() {
+        *size = header.u32()? as usize;
+    }
+    let mut streams = stream_sizes.iter().map(|&size| header.bytes(size).map(Stream::new)).collect::<Result<Vec<_>, _>>()?;
+    let mut instruction_stream = streams.pop().unwrap();
+    let mut bbox_stream = streams.pop().unwrap();
+    let mut composite_stream = streams.pop().unwrap();
+    let mut glyph_stream = streams.pop().unwrap();
+    let mut flag_stream = streams.pop().unwrap();
+    let mut n_points_stream = streams.pop().unwrap();
+    let mut n_contour_stream = streams.pop().unwrap();
+
+    // one bit per glyph, set if the bounding box is stored (and not computed from the points)
+    let bbox_bitmap = bbox_stream.bytes(4 * num_glyphs.div_ceil(32))?;
+    // one bit per glyph, set if the OVERLAP_SIMPLE flag has to be set on the glyph
+    let overlap_bitmap = if option_flags & 1 != 0 { Some(header.bytes(num_glyphs.div_ceil(8))?) } else { None };
+    let bit_is_set = |bitmap: &[u8], glyph: usize| bitmap[glyph >> 3] & (0x80 >> (glyph & 7)) != 0;
+
+    let mut glyf = Vec::new();
+    let mut loca = Vec::new();
+    let mut x_mins = Vec::with_capacity(num_glyphs);
+
+    let push_loca = |loca: &mut Vec<u8>, offset: usize| {
+        if index_format == 0 {
+            loca.extend_from_slice(&((offset / 2) as u16).to_be_bytes());
+        } else {
+            loca.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+    };
+
+    for glyph_id in 0..num_glyphs {
+        push_loca(&mut loca, glyf.len());
+
+        let num_contours = n_contour_stream.i16()?;
+        let has_bbox = bit_is_set(bbox_bitmap, glyph_id);
+
+        let mut x_min = 0;
+        if num_contours == 0 {
+            // empty glyph, can't have a bounding box
+            if has_bbox {
+                return Err(PdfError::FontFaceError);
+            }
+        } else if num_contours > 0 {
+            let mut end_points = Vec::with_capacity(num_contours as usize);
+            let mut num_points: u32 = 0;
+            for _ in 0..num_contours {
+                num_points += u32::from(n_points_stream.u255_16()?);
+                end_points.push(num_points.checked_sub(1).filter(|&end| end <= 0xFFFF).ok_or(PdfError::FontFaceError)? as u16);
+            }
+
+            let mut points = Vec::with_capacity(num_points as usize);
+            let (mut x, mut y) = (0, 0);
+            for _ in 0..num_points {
+                let (dx, dy, on_curve) = decode_triplet(flag_stream.u8()?, &mut glyph_stream)?;
+                x += dx;
+                y += dy;
+                points.push((x, y, on_curve));
+            }
+
+            let instruction_length = usize::from(glyph_stream.u255_16()?);
+            let instructions = instruction_stream.bytes(instruction_length)?;
+
+            let bbox = if has_bbox {
+                [bbox_stream.i16()?, bbox_stream.i16()?, bbox_stream.i16()?, bbox_stream.i16()?]
+            } else {
+                let bound = |coordinates: &mut dyn Iterator<Item = i32>, max: bool| {
+                    coordinates.fold(None, |bound: Option<i32>, c| Some(bound.map_or(c, |b| if max { b.max(c) } else { b.min(c) }))).unwrap_or(0) as i16
+                };
+                [
+                    bound(&mut points.iter().map(|p| p.0), false),
+                    bound(&mut points.iter().map(|p| p.1), false),
+                    bound(&mut points.iter().map(|p| p.0), true),
+                    bound(&mut points.iter().map(|p| p.1), true),
+                ]
+            };
+            x_min = bbox[0];
+
+            let overlap = overlap_bitmap.map(|bitmap| bit_is_set(bitmap, glyph_id)).unwrap_or(false);
+            encode_simple_glyph(&mut glyf, &end_points, &points, instructions, bbox, overlap);
+        } else {
+            // composite glyphs always have a bounding box
+            if !has_bbox {
+                return Err(PdfError::FontFaceError);
+            }
+            glyf.extend_from_slice(&num_contours.to_be_bytes());
+            let bbox = bbox_stream.bytes(8)?;
+            x_min = i16::from_be_bytes([bbox[0], bbox[1]]);
+            glyf.extend_from_slice(bbox);
+
+            // the components are stored unchanged, their length depends on the flags
+            let start = composite_stream.offset;
+            let mut have_instructions = false;
+            loop {
+                let flags = composite_stream.u16()?;
+                let _glyph_index = composite_stream.u16()?;
+                let mut length = if flags & ARG_1_AND_2_ARE_WORDS != 0 { 4 } else { 2 };
+                if flags & WE_HAVE_A_SCALE != 0 {
+                    length += 2;
+                } else if flags & WE_HAVE_AN_X_AND_Y_SCALE != 0 {
+                    length += 4;
+                } else if flags & WE_HAVE_A_TWO_BY_TWO != 0 {
+                    length += 8;
+                }
+                composite_stream.bytes(length)?;
+                have_instructions |= flags & WE_HAVE_INSTRUCTIONS != 0;
+                if flags & MORE_COMPONENTS == 0 {
+                    break;
+                }
+            }
+            glyf.extend_from_slice(&composite_stream.data[start..composite_stream.offset]);
+
+            if have_instructions {
+                let instruction_length = glyph_stream.u255_16()?;
+                glyf.extend_from_slice(&instruction_length.to_be_bytes());
+                glyf.extend_from_slice(instruction_stream.bytes(usize::from(instruction_length))?);
+            }
+        }
+
+        // glyphs start on four byte boundaries, which is required for short loca offsets
+        while glyf.len() % 4 != 0 {
+            glyf.push(0);
+        }
+        x_mins.push(x_min);
+    }
+
+    push_loca(&mut loca, glyf.len());
+
+    Ok(ReconstructedGlyf { glyf, loca, x_mins })
+}
+
+/// Decodes the flag of a point and its coordinates from the glyph stream. Returns the
+/// x and y deltas to the previous point and whether the point is on the curve.
+fn decode_triplet(flag: u8, glyph_stream: &mut Stream) -> Result<(i32, i32, bool), PdfError> {
+
+    // the lowest bit of the flag (and of the flag shifted by one for y) is the sign
+    let with_sign = |flag: u8, value: i32| if flag & 1 != 0 { value } else { -value };
+
+    let on_curve = flag & 0x80 == 0;
+    let flag = flag & 0x7F;
+
+    let data_length = match flag {
+        0..=83 => 1,
+        84..=119 => 2,
+        120..=123 => 3,
+        _ => 4,
+    };
+    let data: Vec<i32> = glyph_stream.bytes(data_length)?.iter().map(|&b| i32::from(b)).collect();
+
+    let (dx, dy) = match flag {
+        0..=9 => (0, with_sign(flag, (i32::from(flag & 14) << 7) + data[0])),
+        10..=19 => (with_sign(flag, (i32::from((flag - 10) & 14) << 7) + data[0]), 0),
+        20..=83 => {
+            let b0 = i32::from(flag - 20);
+            (with_sign(flag, 1 + (b0 & 0x30) + (data[0] >> 4)), with_sign(flag >> 1, 1 + ((b0 & 0x0C) << 2) + (data[0] & 0x0F)))
+        },
+        84..=119 => {
+            let b0 = i32::from(flag - 84);
+            (with_sign(flag, 1 + ((b0 / 12) << 8) + data[0]), with_sign(flag >> 1, 1 + (((b0 % 12) >> 2) << 8) + data[1]))
+        },
+        120..=123 => (with_sign(flag, (data[0] << 4) + (data[1] >> 4)), with_sign(flag >> 1, ((data[1] & 0x0F) << 8) + data[2])),
+        _ => (with_sign(flag, (data[0] << 8) + data[1]), with_sign(flag >> 1, (data[2] << 8) + data[3])),
+    };
+
+    Ok((dx, dy, on_curve))
+}
+
+/// Writes a simple glyph in the format of the `glyf` table. Coordinates that don't
+/// change are left out and small deltas are written as one byte.
+fn encode_simple_glyph(glyf: &mut Vec<u8>, end_points: &[u16], points: &[(i32, i32, bool)], instructions: &[u8], bbox: [i16; 4], overlap: bool) {
+
+    const ON_CURVE_POINT: u8 = 0x01;
+    const X_SHORT_VECTOR: u8 = 0x02;
+    const Y_SHORT_VECTOR: u8 = 0x04;
+    const X_IS_SAME_OR_POSITIVE: u8 = 0x10;
+    const Y_IS_SAME_OR_POSITIVE: u8 = 0x20;
+    const OVERLAP_SIMPLE: u8 = 0x40;
+
+    glyf.extend_from_slice(&(end_points.len() as i16).to_be_bytes());
+    for value in &bbox {
+        glyf.extend_from_slice(&value.to_be_bytes());
+    }
+    for end_point in end_points {
+        glyf.extend_from_slice(&end_point.to_be_bytes());
+    }
+    glyf.extend_from_slice(&(instructions.len() as u16).to_be_bytes());
+    glyf.extend_from_slice(instructions);
+
+    let mut flags = Vec::with_capacity(points.len());
+    let mut x_coordinates = Vec::new();
+    let mut y_coordinates = Vec::new();
+
+    let encode_delta = |delta: i32, coordinates: &mut Vec<u8>, short: u8, same_or_positive: u8| -> u8 {
+        if delta == 0 {
+            same_or_positive
+        } else if delta.abs() < 256 {
+            coordinates.push(delta.unsigned_abs() as u8);
+            short | if delta > 0 { same_or_positive } else { 0 }
+        } else {
+            coordinates.extend_from_slice(&(delta as i16).to_be_bytes());
+            0
+        }
+    };
+
+    let (mut previous_x, mut previous_y) = (0, 0);
+    for (i, &(x, y, on_curve)) in points.iter().enumerate() {
+        let mut flag = if on_curve { ON_CURVE_POINT } else { 0 };
+        if i == 0 && overlap {
+            flag |= OVERLAP_SIMPLE;
+        }
+        flag |= encode_delta(x - previous_x, &mut x_coordinates, X_SHORT_VECTOR, X_IS_SAME_OR_POSITIVE);
+        flag |= encode_delta(y - previous_y, &mut y_coordinates, Y_SHORT_VECTOR, Y_IS_SAME_OR_POSITIVE);
+        flags.push(flag);
+        previous_x = x;
+        previous_y = y;
+    }
+
+    glyf.extend_from_slice(&flags);
+    glyf.extend_from_slice(&x_coordinates);
+    glyf.extend_from_slice(&y_coordinates);
+}
+
+/// Rebuilds the `hmtx` table from the transformed table, which can leave out the
+/// left side bearings that are equal to the x minimum of the glyph
+fn reconstruct_hmtx(table: &[u8], num_h_metrics: usize, x_mins: &[i16]) -> Result<Vec<u8>, PdfError> {
+
+    let mut stream = Stream::new(table);
+    let flags = stream.u8()?;
+    if flags & 0xFC != 0 || num_h_metrics > x_mins.len() {
+        return Err(PdfError::FontFaceError);
+    }
+
+    let advances = (0..num_h_metrics).map(|_| stream.u16()).collect::<Result<Vec<_>, _>>()?;
+    let mut left_side_bearings = Vec::with_capacity(x_mins.len());
+    for (glyph_id, &x_min) in x_mins.iter().enumerate() {
+        // bit 0: no bearings for the glyphs with their own advance, bit 1: for the others
+        let left_out = if glyph_id < num_h_metrics { flags & 1 != 0 } else { flags & 2 != 0 };
+        left_side_bearings.push(if left_out { x_min } else { stream.i16()? });
+    }
+
+    let mut hmtx = Vec::with_capacity(2 * (num_h_metrics + x_mins.len()));
+    for (glyph_id, left_side_bearing) in left_side_bearings.into_iter().enumerate() {
+        if let Some(advance) = advances.get(glyph_id) {
+            hmtx.extend_from_slice(&advance.to_be_bytes());
+        }
+        hmtx.extend_from_slice(&left_side_bearing.to_be_bytes());
+    }
+    Ok(hmtx)
+}
+
+#[test]
+fn test_decode_woff() {
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    // compress the tables of Roboto with zlib, unless they get larger
+    let font_bytes = include_bytes!("../../../../../assets/fonts/RobotoMedium.ttf");
+    let num_tables = read_u16(font_bytes, 4).unwrap();
+    let mut woff = b"wOFF\x00\x01\x00\x00".to_vec();
+    woff.extend_from_slice(&[0; 4]);
+    woff.extend_from_slice(&num_tables.to_be_bytes());
+    woff.extend_from_slice(&[0; 30]);
+
+    let mut table_data = Vec::new();
+    let data_offset = 44 + 20 * usize::from(num_tables);
+    for i in 0..usize::from(num_tables) {
+        let record = 12 + 16 * i;
+        let offset = read_u32(font_bytes, record + 8).unwrap() as usize;
+        let length = read_u32(font_bytes, record + 12).unwrap() as usize;
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&font_bytes[offset..offset + length]).unwrap();
+        let mut compressed = encoder.finish().unwrap();
+        if compressed.len() >= length {
+            compressed = font_bytes[offset..offset + length].to_vec();
+        }
+
+        woff.extend_from_slice(&font_bytes[record..record + 4]);
+        woff.extend_from_slice(&((data_offset + table_data.len()) as u32).to_be_bytes());
+        woff.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+        woff.extend_from_slice(&(length as u32).to_be_bytes());
+        woff.extend_from_slice(&font_bytes[record + 4..record + 8]);
+        table_data.extend_from_slice(&compressed);
+        while table_data.len() % 4 != 0 {
+            table_data.push(0);
+        }
+    }
+    woff.extend_from_slice(&table_data);
+
+    let decoded = decode_web_font(woff).unwrap();
+    let face = ttf_parser::Face::parse(&decoded, 0).unwrap();
+    let original = ttf_parser::Face::parse(font_bytes, 0).unwrap();
+    assert_eq!(face.number_of_glyphs(), original.number_of_glyphs());
+    assert_eq!(face.glyph_index('g'), original.glyph_index('g'));
+}
+
+#[test]
+fn test_reconstruct_glyf() {
+    // one triangle with a stored bounding box and one composite glyph referencing it
+    let mut table = Vec::new();
+    for value in &[0u16, 0, 2, 1] {
+        table.extend_from_slice(&value.to_be_bytes());
+    }
+    let n_contours = [0x00, 0x01, 0xFF, 0xFF];
+    let n_points = [3];
+    // (0, 0) on-curve, (+10, 0) on-curve, (-10, +20) off-curve, no instructions
+    let flags = [0, 11, 0x80 | 26];
+    let glyphs = [0, 10, 0x93, 0];
+    let composite = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    let bboxes = [0x40, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 20];
+    for stream in &[&n_contours[..], &n_points, &flags, &glyphs, &composite, &bboxes, &[]] {
+        table.extend_from_slice(&(stream.len() as u32).to_be_bytes());
+    }
+    for stream in &[&n_contours[..], &n_points, &flags, &glyphs, &composite, &bboxes] {
+        table.extend_from_slice(stream);
+    }
+
+    let ReconstructedGlyf { glyf, loca, x_mins } = reconstruct_glyf(&table).unwrap();
+    assert_eq!(x_mins, vec![0, 0]);
+
+    let mut head = vec![0; 54];
+    head[0..4].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+    head[18..20].copy_from_slice(&1000u16.to_be_bytes());
+    head[50..52].copy_from_slice(&1u16.to_be_bytes());
+    let mut hhea = vec![0; 36];
+    hhea[0..4].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+    let mut maxp = vec![0; 6];
+    maxp[0..4].copy_from_slice(&0x0000_5000u32.to_be_bytes());
+    maxp[4..6].copy_from_slice(&2u16.to_be_bytes());
+    let tables = vec![(*b"glyf", glyf), (*b"head", head), (*b"hhea", hhea), (*b"loca", loca), (*b"maxp", maxp)];
+    let font = write_sfnt(0x0001_0000, tables);
+    let face = ttf_parser::Face::parse(&font, 0).unwrap();
+    let bbox = face.glyph_bounding_box(ttf_parser::GlyphId(0)).unwrap();
+    assert_eq!((bbox.x_min, bbox.y_min, bbox.x_max, bbox.y_max), (0, 0, 10, 20));
+    assert_eq!(face.glyph_bounding_box(ttf_parser::GlyphId(1)).map(|bbox| bbox.x_max), Some(10));
+}
+
+#[test]
+fn test_decode_woff2() {
+    // Source Code Pro Italic (TrueType outlines, transformed glyf / loca tables)
+    let woff2 = include_bytes!("../../../../../assets/fonts/SourceCodePro-It.ttf.woff2");
+    let decoded = decode_web_font(woff2.to_vec()).unwrap();
+    let face = ttf_parser::Face::parse(&decoded, 0).unwrap();
+    let a = face.glyph_index('a').unwrap();
+    assert_eq!(face.glyph_hor_advance(a), Some(600));
+    assert!(face.glyph_bounding_box(a).is_some());
+
+    // a "head" table with the given length and an empty Brotli stream: the decompressed
+    // data must have exactly the length of the tables
+    let woff2 = |length: u8| {
+        let mut woff2 = b"wOF2\x00\x01\x00\x00".to_vec();
+        woff2.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        woff2.extend_from_slice(&[0; 24]);
+        woff2.extend_from_slice(&[1, length, 0x06]);
+        woff2
+    };
+    assert!(decode_web_font(woff2(0)).is_ok());
+    assert_eq!(decode_web_font(woff2(4)).unwrap_err(), PdfError::FontFaceError);
+}
+
+#[test]
+fn test_decode_woff_malformed() {
+    // a zlib compressed table of 16 zero bytes, with the original length from the directory
+    let woff = |num_tables: u8, original_length: u32| {
+        let mut woff = b"wOFF\x00\x01\x00\x00".to_vec();
+        woff.extend_from_slice(&[0, 0, 0, 0, 0, num_tables]);
+        woff.extend_from_slice(&[0; 30]);
+        if num_tables > 0 {
+            woff.extend_from_slice(b"head");
+            woff.extend_from_slice(&64u32.to_be_bytes());
+            woff.extend_from_slice(&11u32.to_be_bytes());
+            woff.extend_from_slice(&original_length.to_be_bytes());
+            woff.extend_from_slice(&[0; 4]);
+            woff.extend_from_slice(&[0x78, 0x9C, 0x63, 0x60, 0x40, 0x05, 0x00, 0x00, 0x10, 0x00, 0x01]);
+        }
+        woff
+    };
+    assert!(decode_web_font(woff(1, 16)).is_ok());
+    // a length of 4 GiB isn't allocated up front, the decompressed table is just too short
+    assert_eq!(decode_web_font(woff(1, u32::MAX)).unwrap_err(), PdfError::FontFaceError);
+
+    // the header says there is a table, but the directory is missing
+    let mut truncated = woff(0, 0);
+    truncated[13] = 1;
+    assert_eq!(decode_web_font(truncated).unwrap_err(), PdfError::FontFaceError);
+}
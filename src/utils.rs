@@ -135,21 +135,172 @@ fn u8_to_char(input: u8) -> char {
 pub fn text_bytes_for_font(text: &str, font: &rusttype::Font) -> Vec<u8> {
     use rusttype::Codepoint as Cp;
 
-    let char_iter = text.chars();
-    let mut list_gid = Vec::<u16>::new();
+    let glyph_ids: Vec<(u16, char)> = text.chars().map(|ch| (font.glyph(Cp(ch as u32)).id().0 as u16, ch)).collect();
+    glyph_ids_to_bytes(&glyph_ids)
+}
+
+/// Encodes glyph IDs as two big-endian bytes each (for Identity-H fonts)
+pub(crate) fn glyph_ids_to_bytes(glyph_ids: &[(u16, char)]) -> Vec<u8> {
+    glyph_ids
+        .iter()
+        .flat_map(|&(x, _)| vec![(x >> 8) as u8, (x & 255) as u8])
+        .collect::<Vec<u8>>()
+}
 
-    for ch in char_iter {
-        // note: font.glyph will panic if the character is \0
-        // since that can't happen in Rust, I think we're safe here
-        let glyph = font.glyph(Cp(ch as u32));
-        list_gid.push(glyph.id().0 as u16);
+/// Returns the kerning that has to be applied before each glyph, in thousandths
+/// of an em (negative values move the glyph closer to the previous glyph).
+///
+/// Pair kerning is read from the GPOS `kern` feature. If the font doesn't have one,
+/// the legacy `kern` table is used instead. Both value records of a GPOS pair are
+/// applied: a placement moves only its glyph, an advance moves all glyphs after it.
+/// A pair that adjusts its second glyph consumes it, like a shaper does, so the next
+/// pair of the lookup starts after it. Adjustments after the last glyph are dropped,
+/// they don't move any glyph.
+pub(crate) fn kerning_for_glyphs(face: &ttf_parser::Face, glyph_ids: &[u16]) -> Vec<i64> {
+    use ttf_parser::{GlyphId, Tag};
+    use ttf_parser::gpos::{PairAdjustment, PositioningSubtable};
 
-        // todo - kerning !!
-        // font.pair_kerning(scale, id, base_glyph.id());
+    // offsets before each glyph and after the last one, in font units
+    let mut offsets = vec![0i32; glyph_ids.len() + 1];
+
+    let units_per_em = f64::from(face.units_per_em());
+
+    // lookups of all "kern" features, regardless of script and language
+    let gpos = face.tables().gpos;
+    let mut kern_lookups = Vec::new();
+    if let Some(gpos) = gpos {
+        for feature in gpos.features.into_iter().filter(|f| f.tag == Tag::from_bytes(b"kern")) {
+            for lookup_index in feature.lookup_indices {
+                if !kern_lookups.contains(&lookup_index) {
+                    kern_lookups.push(lookup_index);
+                }
+            }
+        }
     }
 
-    list_gid
-        .iter()
-        .flat_map(|x| vec![(x >> 8) as u8, (x & 255) as u8])
-        .collect::<Vec<u8>>()
+    // x placement and x advance of the first and the second glyph of a pair
+    let values = |first: &ttf_parser::gpos::ValueRecord, second: &ttf_parser::gpos::ValueRecord| {
+        [first.x_placement, first.x_advance, second.x_placement, second.x_advance]
+    };
+
+    // a placement moves the glyph at `index` only, an advance all glyphs after it
+    let adjust = |offsets: &mut Vec<i32>, index: usize, placement: i16, advance: i16| {
+        offsets[index] += i32::from(placement);
+        offsets[index + 1] += i32::from(advance) - i32::from(placement);
+    };
+
+    if let (Some(gpos), false) = (gpos, kern_lookups.is_empty()) {
+        for lookup in kern_lookups.iter().filter_map(|i| gpos.lookups.get(*i)) {
+            let mut i = 0;
+            while i + 1 < glyph_ids.len() {
+                let (left, right) = (GlyphId(glyph_ids[i]), GlyphId(glyph_ids[i + 1]));
+                // the first subtable that covers the pair is used
+                let pair = lookup.subtables.into_iter::<PositioningSubtable>().filter_map(|subtable| {
+                    match subtable {
+                        PositioningSubtable::Pair(PairAdjustment::Format1 { coverage, sets }) => {
+                            sets.get(coverage.get(left)?)?.get(right).map(|(first, second)| values(&first, &second))
+                        },
+                        PositioningSubtable::Pair(PairAdjustment::Format2 { coverage, classes, matrix }) => {
+                            if !coverage.contains(left) {
+                                return None;
+                            }
+                            matrix.get((classes.0.get(left), classes.1.get(right))).map(|(first, second)| values(&first, &second))
+                        },
+                        _ => None,
+                    }
+                }).next();
+
+                match pair {
+                    Some([first_placement, first_advance, second_placement, second_advance]) => {
+                        adjust(&mut offsets, i, first_placement, first_advance);
+                        adjust(&mut offsets, i + 1, second_placement, second_advance);
+                        i += if second_placement != 0 || second_advance != 0 { 2 } else { 1 };
+                    },
+                    None => i += 1,
+                }
+            }
+        }
+    } else if let Some(kern) = face.tables().kern {
+        for (i, pair) in glyph_ids.windows(2).enumerate() {
+            let (left, right) = (GlyphId(pair[0]), GlyphId(pair[1]));
+            offsets[i + 1] += kern.subtables.into_iter()
+                .filter(|subtable| subtable.horizontal && !subtable.variable && !subtable.has_cross_stream)
+                .filter_map(|subtable| subtable.glyphs_kerning(left, right))
+                .map(i32::from)
+                .sum::<i32>();
+        }
+    }
+
+    offsets.pop();
+    offsets.into_iter().map(|offset| (f64::from(offset) * 1000.0 / units_per_em).round() as i64).collect()
+}
+
+/// Returns the vertical advance of the glyph from the `vmtx` table, in font units. Fonts
+/// without vertical metrics advance by the line height (same fallback as the shaper).
+pub(crate) fn vertical_advance(face: &ttf_parser::Face, glyph_id: u16) -> u16 {
+    face.glyph_ver_advance(ttf_parser::GlyphId(glyph_id))
+        .unwrap_or_else(|| (i32::from(face.ascender()) - i32::from(face.descender())) as u16)
+}
+
+/// Replaces the glyphs with their vertical forms (GSUB `vrt2` feature, or `vert` if the
+/// font doesn't have `vrt2`), for example rotated brackets or repositioned punctuation.
+/// Glyphs without a vertical form are returned unchanged.
+pub(crate) fn vertical_glyph_ids(face: &ttf_parser::Face, glyph_ids: &[u16]) -> Vec<u16> {
+    use ttf_parser::{GlyphId, Tag};
+    use ttf_parser::gsub::{SingleSubstitution, SubstitutionSubtable};
+
+    let gsub = match face.tables().gsub {
+        Some(gsub) => gsub,
+        None => return glyph_ids.to_vec(),
+    };
+
+    // vrt2 already contains all substitutions of vert, so only one of them is applied
+    let lookups_for_feature = |tag: &[u8; 4]| {
+        let mut lookups = Vec::new();
+        for feature in gsub.features.into_iter().filter(|f| f.tag == Tag::from_bytes(tag)) {
+            for lookup_index in feature.lookup_indices {
+                if !lookups.contains(&lookup_index) {
+                    lookups.push(lookup_index);
+                }
+            }
+        }
+        lookups
+    };
+
+    let mut vertical_lookups = lookups_for_feature(b"vrt2");
+    if vertical_lookups.is_empty() {
+        vertical_lookups = lookups_for_feature(b"vert");
+    }
+
+    let substitute = |glyph: GlyphId| -> GlyphId {
+        let mut glyph = glyph;
+        for lookup in vertical_lookups.iter().filter_map(|i| gsub.lookups.get(*i)) {
+            // the first subtable that covers the glyph is used
+            let substitute = lookup.subtables.into_iter::<SubstitutionSubtable>().filter_map(|subtable| {
+                match subtable {
+                    SubstitutionSubtable::Single(SingleSubstitution::Format1 { coverage, delta }) => {
+                        coverage.get(glyph)?;
+                        Some(GlyphId((i32::from(glyph.0) + i32::from(delta)) as u16))
+                    },
+                    SubstitutionSubtable::Single(SingleSubstitution::Format2 { coverage, substitutes }) => {
+                        substitutes.get(coverage.get(glyph)?)
+                    },
+                    _ => None,
+                }
+            }).next();
+            glyph = substitute.unwrap_or(glyph);
+        }
+        glyph
+    };
+
+    glyph_ids.iter().map(|&glyph_id| substitute(GlyphId(glyph_id)).0).collect()
+}
+
+#[test]
+fn test_kerning_for_glyphs() {
+    // "AV" is kerned in Roboto, so the "V" has to be moved closer to the "A"
+    let face = ttf_parser::Face::parse(include_bytes!("../assets/fonts/RobotoMedium.ttf"), 0).unwrap();
+    let kerning = kerning_for_glyphs(&face, &[37, 58]);
+    assert_eq!(kerning[0], 0);
+    assert!(kerning[1] < 0);
 }